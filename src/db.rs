@@ -3,6 +3,13 @@ use crate::view::View;
 
 /// Tables and views are always sorted by name.
 pub struct DB {
+    // `main`, or the name of an attached database.
+    pub schema: String,
+
     pub tables: Vec<Table>,
     pub views: Vec<View>,
+
+    // `sqlite_sequence` is not in `tables` because it's created by sqlite, but
+    // its contents (counters of AUTOINCREMENT) have to be dumped.
+    pub has_sqlite_sequence: bool,
 }
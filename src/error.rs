@@ -7,8 +7,91 @@ pub enum Error {
     FileError(ragit_fs::FileError),
     RusqliteError(rusqlite::Error),
     Base64DecodeError(base64::DecodeError),
+
+    /// A record cannot be (de)serialized to/from the type (see `from_record` and `to_record`).
+    SerdeError(String),
+
+    /// A `Storage` cannot do what stfg needs (e.g. reading from `TarStorage`), or a tar archive is broken.
+    StorageError(String),
+
+    /// `git` has failed (see `GitStorage`). It's what git has printed to stderr.
+    GitError(String),
     EdgeCase(String),
     CorruptedDataFile(String),
+
+    /// `from_git` checks the foreign keys after everything is restored.
+    /// Each element describes a violation.
+    ForeignKeyViolation(Vec<String>),
+
+    /// `PRAGMA integrity_check` has failed on the restored database.
+    /// Each element is a line of its result.
+    IntegrityCheckFailed(Vec<String>),
+
+    /// The output directory was created with a different algorithm of `RecordId::hash`.
+    /// stfg refuses to mix ids from different algorithms.
+    HashAlgorithmMismatch {
+        expected: String,
+        found: String,
+    },
+
+    /// stfg refuses to remove what it didn't create, unless it's forced (see `ToGitOptions::force`).
+    /// `path` is the output path, and `files` are what would have been removed.
+    RefusedToOverwrite {
+        path: String,
+        files: Vec<String>,
+    },
+
+    /// `from_git_schema` takes the source (`data_dir`) first, but `data_dir` is a database or
+    /// `db_path` is an output of stfg. Old versions took `db_path` first.
+    SwappedArguments {
+        data_dir: String,
+        db_path: String,
+    },
+
+    /// Another connection is using the database that `from_git` would replace.
+    DatabaseInUse(String),
+
+    /// `from_git_into_connection` only restores into an empty database. It's the
+    /// number of objects (tables, indexes, ...) that the database already has.
+    DatabaseNotEmpty(usize),
+
+    /// The output directory doesn't have a dump of the schema (an attached database).
+    NoSuchSchema(String),
+
+    /// The output directory doesn't have a dump of the table (see `ExportDir::table`).
+    NoSuchTable(String),
+
+    /// `TableReader::get` takes a value of each column of the primary key (or of every
+    /// column, if the table doesn't have a primary key), in order.
+    PrimaryKeyMismatch {
+        table: String,
+        columns: Vec<String>,
+        found: usize,
+    },
+
+    /// The output directory was created by a newer version of stfg.
+    UnsupportedFormatVersion {
+        supported: i64,
+        found: i64,
+    },
+
+    /// `value_encoding` of the manifest is unknown.
+    UnsupportedValueEncoding(String),
+
+    /// The data files of a table don't match the manifest. They might have been
+    /// edited by hand, or a merge/checkout went wrong.
+    ChecksumMismatch {
+        table: String,
+        expected: String,
+        found: String,
+    },
+
+    /// The data files of a table have a different number of records than the manifest says.
+    RowCountMismatch {
+        table: String,
+        expected: usize,
+        found: usize,
+    },
 }
 
 impl From<ragit_cli::Error> for Error {
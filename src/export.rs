@@ -0,0 +1,221 @@
+use crate::error::Error;
+use crate::from_git::{TableDir, read_schema_dir};
+use crate::layout::BucketLayout;
+use crate::record::{Record, RecordId, RecordReader};
+use crate::storage::{FsStorage, Storage};
+use crate::value::Value;
+use ragit_fs::join;
+use std::sync::Arc;
+
+#[cfg(test)]
+mod tests;
+
+/// An output directory of `to_git`, read directly from the data files. Nothing is restored
+/// to a database (`table.sql` is parsed by an in-memory sqlite, but that's all).
+///
+/// It doesn't compare the data files with the checksums in the manifest, because it'd have
+/// to read every file. Run `fsck` if you're not sure whether the files are intact.
+pub struct ExportDir {
+    // It's empty if it's read from a `Storage` (see `ExportDir::from_storage`).
+    path: String,
+    schema: String,
+
+    // sorted by the name of the directory
+    tables: Vec<TableReader>,
+}
+
+impl ExportDir {
+    /// It opens the `main` database of the output directory.
+    pub fn open(path: &str) -> Result<Self, Error> {
+        ExportDir::open_schema(path, "main")
+    }
+
+    /// `schema` is `main` or the name of an attached database (see `to_git_from_connection`).
+    pub fn open_schema(path: &str, schema: &str) -> Result<Self, Error> {
+        ExportDir::new(Arc::new(FsStorage::default()), path, schema)
+    }
+
+    /// It reads `schema` from the root of `storage` (e.g. an output of `to_git_into_storage`).
+    pub fn from_storage(storage: Arc<dyn Storage>, schema: &str) -> Result<Self, Error> {
+        ExportDir::new(storage, "", schema)
+    }
+
+    fn new(storage: Arc<dyn Storage>, path: &str, schema: &str) -> Result<Self, Error> {
+        let (_, tables) = read_schema_dir(storage.as_ref(), path, schema)?;
+        let mut result = Vec::with_capacity(tables.len());
+
+        for table in tables.into_iter() {
+            result.push(TableReader {
+                table,
+                storage: storage.clone(),
+            });
+        }
+
+        Ok(ExportDir {
+            path: path.to_string(),
+            schema: schema.to_string(),
+            tables: result,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn schema(&self) -> &str {
+        &self.schema
+    }
+
+    pub fn tables(&self) -> &[TableReader] {
+        &self.tables
+    }
+
+    pub fn table(&self, name: &str) -> Result<&TableReader, Error> {
+        self.tables.iter().find(
+            |table| table.name() == name
+        ).ok_or_else(
+            || Error::NoSuchTable(name.to_string())
+        )
+    }
+}
+
+/// A table of an `ExportDir`. It doesn't keep any record in memory: every call
+/// reads the data files again.
+pub struct TableReader {
+    table: TableDir,
+    storage: Arc<dyn Storage>,
+}
+
+impl TableReader {
+    pub fn name(&self) -> &str {
+        &self.table.schema.name
+    }
+
+    /// The columns that are dumped, in the order they're in a record. Generated
+    /// columns and hidden columns (of virtual tables) are not dumped.
+    pub fn columns(&self) -> &[String] {
+        &self.table.schema.columns
+    }
+
+    /// In the order of `PRIMARY KEY (...)`. It's empty if the table doesn't have one.
+    pub fn primary_key(&self) -> &[String] {
+        &self.table.schema.primary_key
+    }
+
+    pub fn create_table_sql(&self) -> &str {
+        &self.table.create_table_sql
+    }
+
+    pub fn create_index_sql(&self) -> &str {
+        &self.table.create_index_sql
+    }
+
+    pub fn create_trigger_sql(&self) -> &str {
+        &self.table.create_trigger_sql
+    }
+
+    pub fn layout(&self) -> BucketLayout {
+        self.table.layout
+    }
+
+    // see `crate::record_serde::to_record`
+    #[cfg(feature = "serde")]
+    pub(crate) fn schema_table(&self) -> &crate::table::Table {
+        &self.table.schema
+    }
+
+    /// The number of records, according to the manifest.
+    pub fn rows(&self) -> Option<usize> {
+        self.table.rows
+    }
+
+    /// It reads the records one at a time, bucket by bucket. The records are sorted by
+    /// id (within a bucket, and the buckets are in order), not by the primary key.
+    pub fn records(&self) -> Result<Records, Error> {
+        Ok(Records {
+            buckets: self.table.layout.list_buckets(self.storage.as_ref(), &self.table.path)?.into_iter().map(|(_, path)| path).collect::<Vec<_>>().into_iter(),
+            storage: self.storage.clone(),
+            reader: None,
+        })
+    }
+
+    /// It finds a record by its primary key. `primary_key` has a value of each column of
+    /// the primary key, in the order of `primary_key()`. If the table doesn't have a primary
+    /// key, it needs a value of every column, and returns the first record that matches.
+    ///
+    /// It only reads the bucket that the record belongs to.
+    pub fn get(&self, primary_key: &[Value]) -> Result<Option<Record>, Error> {
+        let table = &self.table.schema;
+        let columns = if table.primary_key.is_empty() { &table.columns } else { &table.primary_key };
+
+        if primary_key.len() != columns.len() {
+            return Err(Error::PrimaryKeyMismatch {
+                table: table.name.to_string(),
+                columns: columns.to_vec(),
+                found: primary_key.len(),
+            });
+        }
+
+        let id = RecordId::hash(primary_key);
+        let bucket_path = join(&self.table.path, &self.table.layout.bucket_path(self.table.layout.bucket_of(id)))?;
+
+        if !self.storage.is_file(&bucket_path) {
+            return Ok(None);
+        }
+
+        let mut reader = RecordReader::new(&bucket_path, self.storage.open(&bucket_path)?);
+
+        while let Some(record) = reader.next_record()? {
+            // Different keys may have the same id.
+            if record.id == id && columns.iter().zip(primary_key.iter()).all(|(column, value)| record.get(column) == Some(value)) {
+                return Ok(Some(record));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// The records of a table, read lazily (see `TableReader::records`).
+pub struct Records {
+    buckets: std::vec::IntoIter<String>,
+    storage: Arc<dyn Storage>,
+
+    // the bucket that is being read
+    reader: Option<RecordReader>,
+}
+
+impl Iterator for Records {
+    type Item = Result<Record, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(reader) = &mut self.reader {
+                match reader.next_record() {
+                    Ok(Some(record)) => {
+                        return Some(Ok(record));
+                    },
+                    Ok(None) => {
+                        self.reader = None;
+                    },
+                    Err(e) => {
+                        // It doesn't read the rest of the bucket.
+                        self.reader = None;
+                        return Some(Err(e));
+                    },
+                }
+            }
+
+            let bucket = self.buckets.next()?;
+
+            match self.storage.open(&bucket) {
+                Ok(reader) => {
+                    self.reader = Some(RecordReader::new(&bucket, reader));
+                },
+                Err(e) => {
+                    return Some(Err(e));
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use super::ExportDir;
+use crate::error::Error;
+use crate::layout::BucketLayout;
+use crate::to_git::{ToGitOptions, to_git_from_connection};
+use crate::util::TempDir;
+use crate::value::Value;
+use rusqlite::Connection;
+
+#[test]
+fn read_export() {
+    let tmp = TempDir::new("read-export");
+    let output = tmp.join("output").to_str().unwrap().to_string();
+
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch("
+        CREATE TABLE t (a TEXT, b INTEGER, c BLOB, PRIMARY KEY (b, a));
+        INSERT INTO t VALUES ('x', 1, x'00ff'), ('y', 1, NULL), ('x', 2, NULL);
+        CREATE TABLE u (a INTEGER, b TEXT);
+        INSERT INTO u VALUES (1, 'one'), (1, 'one'), (2, NULL);
+    ").unwrap();
+
+    for layout in [BucketLayout::Single, BucketLayout::Flat(2), BucketLayout::Nested(4)] {
+        to_git_from_connection(&conn, &output, &ToGitOptions::new().default_bucket_layout(layout).force(true)).unwrap();
+        let export = ExportDir::open(&output).unwrap();
+
+        assert_eq!(export.tables().iter().map(|t| t.name()).collect::<Vec<_>>(), vec!["t", "u"]);
+        let t = export.table("t").unwrap();
+        assert_eq!(t.primary_key(), &[String::from("b"), String::from("a")]);
+        assert_eq!(t.layout(), layout);
+        assert_eq!(t.rows(), Some(3));
+        assert_eq!(t.records().unwrap().count(), 3);
+
+        let record = t.get(&[Value::Integer(1), Value::Text(String::from("x"))]).unwrap().unwrap();
+        assert_eq!(record.get("c"), Some(&Value::Blob(vec![0, 255])));
+        assert!(t.get(&[Value::Integer(3), Value::Text(String::from("x"))]).unwrap().is_none());
+        assert!(matches!(t.get(&[Value::Integer(1)]), Err(Error::PrimaryKeyMismatch { .. })));
+
+        // A table without a primary key is looked up by all the columns.
+        let u = export.table("u").unwrap();
+        assert!(u.get(&[Value::Integer(2), Value::Null]).unwrap().is_some());
+        assert!(u.get(&[Value::Integer(2), Value::Text(String::from("two"))]).unwrap().is_none());
+
+        assert!(matches!(export.table("v"), Err(Error::NoSuchTable(_))));
+    }
+}
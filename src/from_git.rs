@@ -1,79 +1,1104 @@
 use crate::error::Error;
-use crate::record::read_records;
-use crate::to_git::get_db_schema_from_raw_sql;
+use crate::hash::check_hash_algorithm;
+use crate::layout::BucketLayout;
+use crate::manifest::{MANIFEST_FILE, Manifest, read_manifest, table_checksum};
+use crate::record::{Record, parse_line, read_records};
+use crate::to_git::{
+    FTS_CONFIG_FILE,
+    FTS_SHADOW_FILE,
+    PRAGMA_FILE,
+    PRAGMAS,
+    SQLITE_SEQUENCE_FILE,
+    get_db_schema_from_raw_sql,
+    schema_path,
+};
+use crate::options::{ProgressCallback, TableFilter};
+use crate::report::{Report, TableReport};
+use crate::staging::Staging;
+use crate::storage::{FsStorage, Storage};
+use crate::table::Table;
+use crate::util::utc_timestamp;
+use crate::value::Value;
+use crate::view::sort_by_dependency;
+use crate::vtable::VirtualTable;
 use ragit_fs::{
+    FileError,
     basename,
     exists,
     is_dir,
     join,
-    read_dir,
-    read_string,
     remove_file,
+    rename,
 };
-use rusqlite::{Connection, params_from_iter};
+use rusqlite::{Connection, Statement, params, params_from_iter};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Condvar, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Clone, Debug, Default)]
+pub struct FromGitOptions {
+    /// If it's set, the database is restored with `journal_mode=OFF`, `synchronous=OFF`
+    /// and a larger cache. It's much faster, but if the process dies in the middle, the
+    /// database is corrupted. The safe settings are restored after everything is inserted.
+    /// `from_git_into_connection` uses `journal_mode=MEMORY` instead, so that it can roll back.
+    /// Indexes are always created after the data is inserted.
+    pub bulk_load: bool,
+
+    /// How many threads parse the data files. The records are still inserted by a single
+    /// connection, in the same order, so the result is the same. It's 1 if not set.
+    pub jobs: Option<usize>,
+
+    /// If it's set, it runs `PRAGMA integrity_check` after the database is restored.
+    pub integrity_check: bool,
+
+    /// If `db_path` exists, stfg makes sure that it's a sqlite database before it replaces it.
+    /// If it's set, stfg skips the check and overwrites `db_path` anyway.
+    pub force: bool,
+
+    /// If it's set and `db_path` exists, the old database is kept at `{db_path}.{timestamp}`
+    /// (e.g. `db.sqlite.20250131-235959`), instead of being removed.
+    pub backup: bool,
+
+    /// Which tables to restore. If a restored table has a foreign key to a table that
+    /// is filtered out, it fails with `Error::ForeignKeyViolation`.
+    pub tables: TableFilter,
+
+    /// It's called every time the records of a table are inserted.
+    pub on_progress: Option<ProgressCallback>,
+}
+
+impl FromGitOptions {
+    pub fn new() -> Self {
+        FromGitOptions::default()
+    }
+
+    pub fn bulk_load(mut self, bulk_load: bool) -> Self {
+        self.bulk_load = bulk_load;
+        self
+    }
+
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = Some(jobs);
+        self
+    }
+
+    pub fn integrity_check(mut self, integrity_check: bool) -> Self {
+        self.integrity_check = integrity_check;
+        self
+    }
+
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
 
+    pub fn backup(mut self, backup: bool) -> Self {
+        self.backup = backup;
+        self
+    }
+
+    pub fn tables(mut self, tables: TableFilter) -> Self {
+        self.tables = tables;
+        self
+    }
+
+    pub fn on_progress<F: Fn(&TableReport) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.on_progress = Some(ProgressCallback::new(f));
+        self
+    }
+}
+
+/// It restores the `main` database. Attached databases are restored with `from_git_schema`.
+///
+/// Unlike the other functions, it takes the destination first. It's kept for compatibility.
+#[deprecated(note = "use `from_git_with_options(data_dir, db_path, &FromGitOptions::default())`, which takes the source first")]
 pub fn from_git(
     db_path: &str,
     data_dir: &str,
-) -> Result<(), Error> {
-    if exists(db_path) {
-        remove_file(db_path)?;
+) -> Result<Report, Error> {
+    from_git_with_options(data_dir, db_path, &FromGitOptions::default())
+}
+
+/// Like `to_git_with_options`, it takes the source (`data_dir`) first, then the destination.
+pub fn from_git_with_options(
+    data_dir: &str,
+    db_path: &str,
+    options: &FromGitOptions,
+) -> Result<Report, Error> {
+    from_git_schema(data_dir, db_path, "main", options)
+}
+
+/// It restores a database that `to_git_from_connection` has dumped, to a separate file.
+/// `schema` is `main` or the name of the attached database. A database can only refer
+/// to its own tables (and `temp`), so it doesn't matter in which order they're restored.
+pub fn from_git_schema(
+    data_dir: &str,
+    db_path: &str,
+    schema: &str,
+    options: &FromGitOptions,
+) -> Result<Report, Error> {
+    let started_at = Instant::now();
+
+    // With the old order of the arguments, `force` would overwrite the output.
+    check_argument_order(data_dir, db_path)?;
+
+    // The manifest is validated before anything is written to `db_path`.
+    let storage = FsStorage::default();
+    let tables = read_tables_to_restore(&storage, data_dir, schema, options)?;
+
+    if !options.force {
+        check_overwrite(db_path)?;
     }
 
-    let mut conn = Connection::open(db_path)?;
+    // Nobody can use the old database while it's being replaced. If it's not a
+    // database (with `force`), nobody can use it anyway.
+    let old_db = if exists(db_path) && !is_dir(db_path) && is_sqlite_database(db_path)? {
+        Some(lock_database(db_path)?)
+    } else {
+        None
+    };
 
-    for table_dir in read_dir(data_dir, false)?.iter() {
-        if !is_dir(&table_dir) {
-            continue;
+    // It's restored to a temporary file first, so that `db_path` is untouched
+    // if anything goes wrong.
+    let staging = Staging::new_file(db_path)?;
+    let mut conn = Connection::open(&staging.path)?;
+    let (reports, violations) = restore(&mut conn, &storage, &schema_path(data_dir, schema)?, &tables, schema, options, false)?;
+    conn.close().map_err(|(_, e)| e)?;
+
+    if let Some(violations) = violations {
+        return Err(Error::ForeignKeyViolation(violations));
+    }
+
+    match old_db {
+        Some(old_db) => {
+            replace_database(old_db, staging, db_path, options.backup)?;
+        },
+        None => {
+            staging.commit()?;
+        },
+    }
+
+    Ok(Report {
+        tables: reports,
+        elapsed: started_at.elapsed(),
+    })
+}
+
+/// Like `from_git_schema`, but it restores the database into the `main` database of `conn`
+/// (e.g. an in-memory database, or a connection with custom functions), which has to be empty.
+/// `conn` must not be in a transaction. `force` and `backup` of `options` are ignored.
+///
+/// If it fails while restoring the tables, the data, the views or the triggers, they're rolled
+/// back and `conn` is left empty. The pragmas (e.g. `page_size`) are applied outside of that, and
+/// if the foreign keys or the integrity check fail, `conn` has the whole database.
+pub fn from_git_into_connection(
+    data_dir: &str,
+    conn: &mut Connection,
+    schema: &str,
+    options: &FromGitOptions,
+) -> Result<Report, Error> {
+    restore_into_connection(&FsStorage::default(), data_dir, conn, schema, options)
+}
+
+/// Like `from_git_into_connection`, but it reads the output from `storage` (e.g. a `MemoryStorage`,
+/// or an archive of `TarStorage` that is read by `MemoryStorage::from_tar`).
+pub fn from_storage_into_connection(
+    storage: &dyn Storage,
+    conn: &mut Connection,
+    schema: &str,
+    options: &FromGitOptions,
+) -> Result<Report, Error> {
+    restore_into_connection(storage, "", conn, schema, options)
+}
+
+fn restore_into_connection(
+    storage: &dyn Storage,
+    data_dir: &str,
+    conn: &mut Connection,
+    schema: &str,
+    options: &FromGitOptions,
+) -> Result<Report, Error> {
+    let started_at = Instant::now();
+    let tables = read_tables_to_restore(storage, data_dir, schema, options)?;
+    let objects = conn.query_row("SELECT COUNT(*) FROM main.sqlite_master;", [], |row| row.get::<_, i64>(0))?;
+
+    if objects != 0 {
+        return Err(Error::DatabaseNotEmpty(objects as usize));
+    }
+
+    // `restore` turns it off, but it's the user's connection.
+    let foreign_keys: bool = conn.pragma_query_value(None, "foreign_keys", |row| row.get(0))?;
+    let result = restore(conn, storage, &schema_path(data_dir, schema)?, &tables, schema, options, true);
+    conn.pragma_update(None, "foreign_keys", foreign_keys)?;
+    let (reports, violations) = result?;
+
+    if let Some(violations) = violations {
+        return Err(Error::ForeignKeyViolation(violations));
+    }
+
+    Ok(Report {
+        tables: reports,
+        elapsed: started_at.elapsed(),
+    })
+}
+
+// The tables of `schema` that `options` selects. The manifest and the checksums are checked.
+fn read_tables_to_restore(storage: &dyn Storage, data_dir: &str, schema: &str, options: &FromGitOptions) -> Result<Vec<TableDir>, Error> {
+    let (manifest, mut tables) = read_schema_dir(storage, data_dir, schema)?;
+
+    if let Some(manifest) = &manifest {
+        check_manifest(storage, manifest, &tables)?;
+    }
+
+    tables.retain(|table| options.tables.matches(&table.schema.name));
+    Ok(tables)
+}
+
+// It restores `tables` and the rest of `data_dir` (the directory of the schema) into `conn`.
+// It returns a report of each table, and the violations of the foreign keys. Even if there're
+// violations, the database is fully restored, but `from_git_schema` doesn't replace the old
+// database with it.
+//
+// Step 1 ~ 5 are in a savepoint. If `rollback` is set (`conn` is the user's), it's rolled back
+// when they fail. Otherwise, `conn` is a temporary database that is thrown away.
+fn restore(
+    conn: &mut Connection,
+    storage: &dyn Storage,
+    data_dir: &str,
+    tables: &[TableDir],
+    schema: &str,
+    options: &FromGitOptions,
+    rollback: bool,
+) -> Result<(Vec<TableReport>, Option<Vec<String>>), Error> {
+    let pragma_path = join(data_dir, PRAGMA_FILE)?;
+
+    // Old versions of stfg don't create this file.
+    let pragmas = if storage.is_file(&pragma_path) {
+        read_pragmas(&storage.read_string(&pragma_path)?)?
+    } else {
+        vec![]
+    };
+
+    // They only work before the first table is created.
+    apply_pragmas(conn, &pragmas, &["encoding", "page_size", "auto_vacuum"])?;
+
+    // A rollback doesn't work without a journal, so `conn` keeps it in memory if it has to be rolled back.
+    let bulk_load = if options.bulk_load {
+        Some(begin_bulk_load(conn, if rollback { "MEMORY" } else { "OFF" })?)
+    } else {
+        None
+    };
+
+    // Foreign keys are checked at the end, because a row may refer to a row of a table
+    // that is not inserted yet. It's a no-op inside a savepoint.
+    conn.pragma_update(None, "foreign_keys", false)?;
+
+    // The settings are restored even if it fails, because `conn` may be the user's connection.
+    // `journal_mode` cannot be changed inside a savepoint, so it's released first.
+    conn.execute_batch("SAVEPOINT stfg_restore;")?;
+    let result = restore_objects(conn, storage, data_dir, tables, schema, options);
+    let released = match &result {
+        Err(_) if rollback => conn.execute_batch("ROLLBACK TO stfg_restore; RELEASE stfg_restore;"),
+        _ => conn.execute_batch("RELEASE stfg_restore;"),
+    };
+    let ended = match bulk_load {
+        Some(settings) => end_bulk_load(conn, settings),
+        None => Ok(()),
+    };
+    let reports = result?;
+    released?;
+    ended?;
+
+    apply_pragmas(conn, &pragmas, &["application_id", "user_version", "journal_mode"])?;
+
+    if options.integrity_check {
+        check_integrity(conn)?;
+    }
+
+    // 6. Now that everything is in place, check the foreign keys. Even if it fails,
+    //    the database is fully restored.
+    let violations = match check_foreign_keys(conn) {
+        Ok(()) => None,
+        Err(Error::ForeignKeyViolation(violations)) => Some(violations),
+        Err(e) => {
+            return Err(e);
+        },
+    };
+
+    Ok((reports, violations))
+}
+
+// Step 1 ~ 5 of `restore`.
+fn restore_objects(
+    conn: &mut Connection,
+    storage: &dyn Storage,
+    data_dir: &str,
+    tables: &[TableDir],
+    schema: &str,
+    options: &FromGitOptions,
+) -> Result<Vec<TableReport>, Error> {
+    // The restore is done in phases, so that the order of the tables doesn't matter.
+    // 1. Create all the tables.
+    for table in tables.iter() {
+        conn.execute_batch(&table.create_table_sql)?;
+
+        // Some configurations (e.g. `pgsz`) affect how the data is inserted.
+        let fts_config_path = join(&table.path, FTS_CONFIG_FILE)?;
+
+        if storage.is_file(&fts_config_path) {
+            restore_fts_config(conn, &table.schema, &storage.read_string(&fts_config_path)?)?;
+        }
+    }
+
+    // 2. Insert all the data. Foreign keys are turned off by `restore`.
+    let reports = insert_records(conn, storage, tables, schema, options)?;
+
+    // External content fts tables are rebuilt from their content tables, and
+    // contentless fts tables get their index back.
+    for table in tables.iter() {
+        if let Some(virtual_table) = &table.schema.virtual_table && virtual_table.needs_rebuild() {
+            let name = table.schema.name.replace("\"", "\"\"");
+            conn.execute(&format!("INSERT INTO \"{name}\" (\"{name}\") VALUES ('rebuild');"), [])?;
+        }
+
+        let fts_shadow_path = join(&table.path, FTS_SHADOW_FILE)?;
+
+        if let Some(virtual_table) = &table.schema.virtual_table && storage.is_file(&fts_shadow_path) {
+            restore_fts_shadow(conn, &table.schema, virtual_table, &storage.read_string(&fts_shadow_path)?)?;
+        }
+    }
+
+    let sqlite_sequence_path = join(data_dir, SQLITE_SEQUENCE_FILE)?;
+
+    // Old versions of stfg don't create this file.
+    if storage.is_file(&sqlite_sequence_path) {
+        restore_sqlite_sequence(conn, &storage.read_string(&sqlite_sequence_path)?, &options.tables)?;
+    }
+
+    for table in tables.iter() {
+        evaluate_generated_columns(conn, &table.schema)?;
+    }
+
+    // 3. Indexes are created after the data is inserted, because it's faster.
+    for table in tables.iter() {
+        if !table.create_index_sql.trim().is_empty() {
+            conn.execute_batch(&table.create_index_sql)?;
+        }
+    }
+
+    // 4. Views may depend on other views.
+    let view_sql = storage.read_string(&join(data_dir, "view.sql")?)?;
+
+    if !view_sql.trim().is_empty() {
+        let views = get_db_schema_from_raw_sql(&view_sql)?.views;
+
+        for view in sort_by_dependency(views).iter() {
+            conn.execute_batch(&view.create_view_sql)?;
+        }
+    }
+
+    // 5. Triggers are created at the end, so that they're not triggered by the restore.
+    //    Triggers of views are in `trigger.sql` at the root.
+    for table in tables.iter() {
+        if !table.create_trigger_sql.trim().is_empty() {
+            conn.execute_batch(&table.create_trigger_sql)?;
+        }
+    }
+
+    let view_trigger_path = join(data_dir, "trigger.sql")?;
+
+    // Old versions of stfg don't create this file.
+    if storage.is_file(&view_trigger_path) {
+        let view_trigger_sql = storage.read_string(&view_trigger_path)?;
+
+        if !view_trigger_sql.trim().is_empty() {
+            conn.execute_batch(&view_trigger_sql)?;
         }
+    }
+
+    Ok(reports)
+}
+
+// The first 16 bytes of a sqlite database.
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
 
-        let table_sql = read_string(&join(table_dir, "table.sql")?)?;
-        conn.execute(&table_sql, [])?;
-        let tx = conn.transaction()?;
+fn check_argument_order(data_dir: &str, db_path: &str) -> Result<(), Error> {
+    let data_dir_is_database = exists(data_dir) && !is_dir(data_dir) && is_sqlite_database(data_dir)?;
+    let db_path_is_output = is_dir(db_path) && exists(&join(db_path, MANIFEST_FILE)?);
+
+    if data_dir_is_database || db_path_is_output {
+        return Err(Error::SwappedArguments {
+            data_dir: data_dir.to_string(),
+            db_path: db_path.to_string(),
+        });
+    }
+
+    Ok(())
+}
 
-        let table_schema = get_db_schema_from_raw_sql(&table_sql)?;
-        let table_schema = match table_schema.tables.len() {
-            1 => table_schema.tables[0].clone(),
+fn check_overwrite(db_path: &str) -> Result<(), Error> {
+    if !exists(db_path) || (!is_dir(db_path) && is_sqlite_database(db_path)?) {
+        return Ok(());
+    }
+
+    Err(Error::RefusedToOverwrite {
+        path: db_path.to_string(),
+        files: vec![db_path.to_string()],
+    })
+}
+
+// An empty file is also a valid (empty) sqlite database.
+fn is_sqlite_database(path: &str) -> Result<bool, Error> {
+    let mut header = Vec::with_capacity(SQLITE_HEADER.len());
+    File::open(path).and_then(
+        |file| file.take(SQLITE_HEADER.len() as u64).read_to_end(&mut header)
+    ).map_err(|e| FileError::from_std(e, path))?;
+
+    Ok(header.is_empty() || header == SQLITE_HEADER)
+}
+
+// The files that sqlite creates next to a database. If they're left behind, sqlite would
+// apply them to the new database at `db_path`, which corrupts the new database.
+const SIDECAR_SUFFIXES: [&str; 3] = ["-journal", "-wal", "-shm"];
+
+// It opens the old database and holds an exclusive lock, so that nobody can write to
+// the database that is about to be replaced. It fails if another connection has the
+// database open in WAL mode, or is in the middle of a transaction. In the other journal
+// modes, sqlite cannot tell whether an idle connection has the database open.
+//
+// If the old database has a hot journal or a WAL file, sqlite applies it when the
+// database is opened, so the old database is complete (e.g. for a backup).
+fn lock_database(db_path: &str) -> Result<Connection, Error> {
+    let conn = Connection::open(db_path)?;
+    conn.busy_timeout(std::time::Duration::ZERO)?;
+    conn.pragma_update_and_check(None, "locking_mode", "EXCLUSIVE", |_| Ok(()))?;
+
+    if let Err(e) = conn.execute_batch("BEGIN EXCLUSIVE; COMMIT;") {
+        return match e.sqlite_error_code() {
+            Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked) => Err(Error::DatabaseInUse(db_path.to_string())),
+            _ => Err(e.into()),
+        };
+    }
+
+    Ok(conn)
+}
+
+// It closes the old database and replaces it with the new database (`staging`). When the
+// last connection of a WAL database is closed, sqlite moves everything to the database file
+// and removes the `-wal` and `-shm` files. Whatever's left is from a process that has crashed.
+//
+// The lock is released right before the rename, and if the rename fails, the backup is put back.
+fn replace_database(conn: Connection, staging: Staging, db_path: &str, backup: bool) -> Result<(), Error> {
+    conn.close().map_err(|(_, e)| e)?;
+
+    let backup_path = if backup {
+        let timestamp = utc_timestamp();
+        let mut backup_path = format!("{db_path}.{timestamp}");
+        let mut count = 1;
+
+        while exists(&backup_path) {
+            backup_path = format!("{db_path}.{timestamp}-{count}");
+            count += 1;
+        }
+
+        rename(db_path, &backup_path)?;
+        Some(backup_path)
+    } else {
+        None
+    };
+
+    for suffix in SIDECAR_SUFFIXES.iter() {
+        let path = format!("{db_path}{suffix}");
+
+        if exists(&path) {
+            remove_file(&path)?;
+        }
+    }
+
+    if let Err(e) = staging.commit() {
+        if let Some(backup_path) = backup_path {
+            rename(&backup_path, db_path)?;
+        }
+
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+pub(crate) struct TableDir {
+    pub path: String,
+    pub layout: BucketLayout,
+
+    // the number of rows in the manifest (`None` if there's no manifest)
+    pub rows: Option<usize>,
+
+    pub create_table_sql: String,
+    pub create_index_sql: String,
+    pub create_trigger_sql: String,
+    pub schema: Table,
+}
+
+/// It reads the manifest and the tables of `schema`, after making sure that this version
+/// of stfg can read them. The data files are not read (see `check_manifest`).
+pub(crate) fn read_schema_dir(storage: &dyn Storage, root_dir: &str, schema: &str) -> Result<(Option<Manifest>, Vec<TableDir>), Error> {
+    let data_dir = schema_path(root_dir, schema)?;
+
+    if !storage.is_dir(&data_dir) {
+        return Err(Error::NoSuchSchema(schema.to_string()));
+    }
+
+    let manifest = read_manifest(storage, &data_dir)?;
+
+    match &manifest {
+        Some(manifest) => {
+            manifest.check_compatibility()?;
+        },
+        // Old versions of stfg don't create the manifest.
+        None => {
+            check_hash_algorithm(storage, root_dir)?;
+        },
+    }
+
+    let tables = read_tables(storage, &data_dir, manifest.as_ref())?;
+    Ok((manifest, tables))
+}
+
+fn read_tables(storage: &dyn Storage, data_dir: &str, manifest: Option<&Manifest>) -> Result<Vec<TableDir>, Error> {
+    let mut result = vec![];
+
+    for table_dir in storage.read_dir(data_dir)?.iter() {
+        let table_sql_path = join(table_dir, "table.sql")?;
+
+        // It's not made by stfg (e.g. `.git/`).
+        if !storage.is_dir(table_dir) || !storage.is_file(&table_sql_path) {
+            continue;
+        }
+
+        let create_table_sql = storage.read_string(&table_sql_path)?;
+        let schema = get_db_schema_from_raw_sql(&create_table_sql)?;
+        let schema = match schema.tables.len() {
+            1 => schema.tables[0].clone(),
             n => {
-                return Err(Error::CorruptedDataFile(format!("expected exactly 1 `CREATE TABLE` statement from `table.sql`, but got {n}")));
+                return Err(Error::CorruptedDataFile(format!("expected exactly 1 `CREATE TABLE` statement from `{table_sql_path}`, but got {n}")));
             },
         };
-        let insert_stmt = table_schema.insert_stmt();
-        let mut insert_stmt = tx.prepare(&insert_stmt)?;
 
-        for data_file in read_dir(&table_dir, false)?.iter() {
-            let data_file_name = basename(&data_file)?;
+        let dir = basename(table_dir)?;
+        let manifest_table = manifest.and_then(|manifest| manifest.tables.iter().find(|t| t.dir == dir));
 
-            if data_file_name.len() != 3 {
-                continue;
+        result.push(TableDir {
+            path: table_dir.to_string(),
+            // Outputs without a manifest always use the default layout.
+            layout: manifest_table.map(|t| t.layout).unwrap_or_default(),
+            rows: manifest_table.map(|t| t.rows as usize),
+            create_table_sql,
+            create_index_sql: storage.read_string(&join(table_dir, "index.sql")?)?,
+            create_trigger_sql: storage.read_string(&join(table_dir, "trigger.sql")?)?,
+            schema,
+        });
+    }
+
+    Ok(result)
+}
+
+// The manifest and the table directories have to agree on which tables exist
+// and what's in their data files.
+fn check_manifest(storage: &dyn Storage, manifest: &Manifest, tables: &[TableDir]) -> Result<(), Error> {
+    for table in tables.iter() {
+        let dir = basename(&table.path)?;
+
+        if !manifest.tables.iter().any(|t| t.dir == dir) {
+            return Err(Error::CorruptedDataFile(format!("`{}` is not in `{MANIFEST_FILE}`", table.path)));
+        }
+    }
+
+    for manifest_table in manifest.tables.iter() {
+        let table = match tables.iter().find(|table| basename(&table.path).map(|dir| dir == manifest_table.dir).unwrap_or(false)) {
+            Some(table) => table,
+            None => {
+                return Err(Error::CorruptedDataFile(format!("`{MANIFEST_FILE}` has table `{}`, but its directory is missing", manifest_table.name)));
+            },
+        };
+        let checksum = table_checksum(storage, &table.path, table.layout, manifest.format_version)?;
+
+        if checksum != manifest_table.checksum {
+            return Err(Error::ChecksumMismatch {
+                table: manifest_table.name.to_string(),
+                expected: manifest_table.checksum.to_string(),
+                found: checksum,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// It returns a report of each table, and reports the progress.
+fn insert_records(
+    conn: &mut Connection,
+    storage: &dyn Storage,
+    tables: &[TableDir],
+    schema: &str,
+    options: &FromGitOptions,
+) -> Result<Vec<TableReport>, Error> {
+    // (index of the table, data file)
+    // The order has to be deterministic, so that the restored rowids are always the same.
+    let mut data_files = vec![];
+
+    for (table_index, table) in tables.iter().enumerate() {
+        for (_, data_file) in table.layout.list_buckets(storage, &table.path)?.into_iter() {
+            data_files.push((table_index, data_file));
+        }
+    }
+
+    let tx = conn.savepoint()?;
+
+    // (index of the table, insert_stmt, insert_with_rowid_stmt)
+    let mut curr_stmts: Option<(usize, Statement, Option<Statement>)> = None;
+
+    let mut reports = tables.iter().map(
+        |table| TableReport {
+            schema: schema.to_string(),
+            name: table.schema.name.to_string(),
+            rows: 0,
+            elapsed: Duration::ZERO,
+        }
+    ).collect::<Vec<_>>();
+
+    // The files are sorted by table, so the tables before the current one are done.
+    let mut reported = 0;
+    let mut report_until = |table_index: usize, reports: &[TableReport]| {
+        if let Some(on_progress) = &options.on_progress {
+            for report in reports[reported.min(table_index)..table_index].iter() {
+                on_progress.call(report);
+            }
+        }
+
+        reported = reported.max(table_index);
+    };
+
+    read_data_files(
+        storage,
+        &data_files,
+        options.jobs.unwrap_or(1),
+        |(table_index, data_file), records| {
+            let started_at = Instant::now();
+            let table_schema = &tables[*table_index].schema;
+            report_until(*table_index, &reports);
+
+            if curr_stmts.as_ref().map(|(index, _, _)| index) != Some(table_index) {
+                curr_stmts = Some((
+                    *table_index,
+                    tx.prepare(&table_schema.insert_stmt(None))?,
+                    match table_schema.hidden_rowid() {
+                        Some(rowid) => Some(tx.prepare(&table_schema.insert_stmt(Some(rowid)))?),
+                        None => None,
+                    },
+                ));
             }
 
-            let records = read_records(data_file)?;
+            let (_, insert_stmt, insert_with_rowid_stmt) = curr_stmts.as_mut().unwrap();
 
             for record in records.iter() {
-                insert_stmt.execute(params_from_iter(record.fields.iter().map(|(_, v)| v)))?;
+                match (record.rowid, &mut *insert_with_rowid_stmt) {
+                    (Some(rowid), Some(insert_with_rowid_stmt)) => {
+                        insert_with_rowid_stmt.execute(params_from_iter(
+                            record.fields.iter().map(|(_, v)| v.clone()).chain(std::iter::once(Value::Integer(rowid)))
+                        ))?;
+                    },
+                    (Some(_), None) => {
+                        return Err(Error::CorruptedDataFile(format!("`{data_file}` has a rowid, but table `{}` cannot have one", table_schema.name)));
+                    },
+                    (None, _) => {
+                        insert_stmt.execute(params_from_iter(record.fields.iter().map(|(_, v)| v)))?;
+                    },
+                }
             }
+
+            let report = &mut reports[*table_index];
+            report.rows += records.len();
+            report.elapsed += started_at.elapsed();
+            Ok(())
+        },
+    )?;
+
+    drop(curr_stmts);
+
+    // The checksum doesn't cover the manifest itself.
+    for (table, report) in tables.iter().zip(reports.iter()) {
+        if let Some(rows) = table.rows && rows != report.rows {
+            return Err(Error::RowCountMismatch {
+                table: table.schema.name.to_string(),
+                expected: rows,
+                found: report.rows,
+            });
         }
+    }
 
-        drop(insert_stmt);
-        tx.commit()?;
+    tx.commit()?;
+    report_until(tables.len(), &reports);
+    Ok(reports)
+}
 
-        let index_sql = read_string(&join(table_dir, "index.sql")?)?;
+// A parser can't get further than this many files (per thread) ahead of the writer,
+// so that the parsed records don't pile up in memory.
+const READ_AHEAD: usize = 4;
 
-        if !index_sql.trim().is_empty() {
-            conn.execute_batch(&index_sql)?;
+// It parses the data files and calls `f` with the records of each file, in order.
+// If `jobs` is greater than 1, the files are parsed by `jobs` threads, but `f`
+// is still called on the current thread.
+fn read_data_files<F: FnMut(&(usize, String), Vec<Record>) -> Result<(), Error>>(
+    storage: &dyn Storage,
+    data_files: &[(usize, String)],
+    jobs: usize,
+    mut f: F,
+) -> Result<(), Error> {
+    if jobs <= 1 {
+        for data_file in data_files.iter() {
+            f(data_file, read_records(storage, &data_file.1)?)?;
         }
 
-        let trigger_sql = read_string(&join(table_dir, "trigger.sql")?)?;
+        return Ok(());
+    }
+
+    let next_file = AtomicUsize::new(0);
+    let aborted = AtomicBool::new(false);
+
+    // how many files are passed to `f`
+    let written = Mutex::new(0);
+    let written_changed = Condvar::new();
+
+    let (sender, receiver) = mpsc::channel();
+
+    std::thread::scope(|s| {
+        for _ in 0..jobs {
+            let (sender, next_file, aborted, written, written_changed) = (
+                sender.clone(),
+                &next_file,
+                &aborted,
+                &written,
+                &written_changed,
+            );
 
-        if !trigger_sql.trim().is_empty() {
-            conn.execute_batch(&trigger_sql)?;
+            s.spawn(move || {
+                loop {
+                    let index = next_file.fetch_add(1, Ordering::Relaxed);
+
+                    if index >= data_files.len() {
+                        break;
+                    }
+
+                    let mut written = written.lock().unwrap();
+
+                    while index >= *written + READ_AHEAD * jobs && !aborted.load(Ordering::Relaxed) {
+                        written = written_changed.wait(written).unwrap();
+                    }
+
+                    drop(written);
+
+                    if aborted.load(Ordering::Relaxed) || sender.send((index, read_records(storage, &data_files[index].1))).is_err() {
+                        break;
+                    }
+                }
+            });
         }
 
-        let view_sql = read_string(&join(data_dir, "view.sql")?)?;
+        drop(sender);
+        let mut parsed = HashMap::new();
+        let mut result = Ok(());
+
+        for (index, data_file) in data_files.iter().enumerate() {
+            let records = loop {
+                if let Some(records) = parsed.remove(&index) {
+                    break records;
+                }
+
+                match receiver.recv() {
+                    Ok((index, records)) => {
+                        parsed.insert(index, records);
+                    },
+                    Err(_) => {
+                        break Err(Error::EdgeCase(String::from("a parser thread has stopped unexpectedly")));
+                    },
+                }
+            };
+
+            if let Err(e) = records.and_then(|records| f(data_file, records)) {
+                result = Err(e);
+                break;
+            }
+
+            *written.lock().unwrap() = index + 1;
+            written_changed.notify_all();
+        }
+
+        // Otherwise, the parsers would wait forever.
+        if result.is_err() {
+            aborted.store(true, Ordering::Relaxed);
+            let _lock = written.lock().unwrap();
+            written_changed.notify_all();
+        }
+
+        result
+    })
+}
+
+// in KiB (a negative `cache_size` is in KiB), 256 MiB
+const BULK_LOAD_CACHE_SIZE: i64 = -262144;
+
+// The settings of the connection before the bulk load, which `end_bulk_load` restores.
+// None of them is stored in the database, but the connection may be the user's
+// (see `from_git_into_connection`).
+struct BulkLoadSettings {
+    journal_mode: String,
+    synchronous: i64,
+    cache_size: i64,
+}
+
+// `journal_mode` is `OFF`, unless it has to be rolled back.
+fn begin_bulk_load(conn: &Connection, journal_mode: &str) -> Result<BulkLoadSettings, Error> {
+    let settings = BulkLoadSettings {
+        journal_mode: conn.pragma_query_value(None, "journal_mode", |row| row.get(0))?,
+        synchronous: conn.pragma_query_value(None, "synchronous", |row| row.get(0))?,
+        cache_size: conn.pragma_query_value(None, "cache_size", |row| row.get(0))?,
+    };
+
+    // `journal_mode` returns the new value, so `pragma_update` doesn't work.
+    conn.pragma_update_and_check(None, "journal_mode", journal_mode, |_| Ok(()))?;
+    conn.pragma_update(None, "synchronous", "OFF")?;
+    conn.pragma_update(None, "cache_size", BULK_LOAD_CACHE_SIZE)?;
+    Ok(settings)
+}
+
+// `journal_mode` in `PRAGMA_FILE` is applied after this.
+fn end_bulk_load(conn: &Connection, settings: BulkLoadSettings) -> Result<(), Error> {
+    conn.pragma_update_and_check(None, "journal_mode", settings.journal_mode, |_| Ok(()))?;
+    conn.pragma_update(None, "synchronous", settings.synchronous)?;
+    conn.pragma_update(None, "cache_size", settings.cache_size)?;
+    Ok(())
+}
+
+fn check_integrity(conn: &Connection) -> Result<(), Error> {
+    let mut stmt = conn.prepare("PRAGMA integrity_check;")?;
+    let mut rows = stmt.query([])?;
+    let mut messages = vec![];
+
+    while let Some(row) = rows.next()? {
+        messages.push(row.get::<_, String>(0)?);
+    }
+
+    if messages != ["ok"] {
+        return Err(Error::IntegrityCheckFailed(messages));
+    }
 
-        if !view_sql.trim().is_empty() {
-            conn.execute_batch(&view_sql)?;
+    Ok(())
+}
+
+// Generated columns are not dumped, so there's nothing to compare them with. It only makes sure that
+// they evaluate without an error. Virtual columns are computed when they're read, so it reads them all.
+fn evaluate_generated_columns(conn: &Connection, table: &Table) -> Result<(), Error> {
+    if table.generated_columns.is_empty() {
+        return Ok(());
+    }
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM \"{}\";",
+        table.generated_columns.iter().map(
+            |column| format!("\"{}\"", column.replace("\"", "\"\""))
+        ).collect::<Vec<_>>().join(", "),
+        table.name.replace("\"", "\"\""),
+    ))?;
+    let mut rows = stmt.query([])?;
+
+    while let Some(row) = rows.next()? {
+        for i in 0..table.generated_columns.len() {
+            row.get::<_, Value>(i)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn check_foreign_keys(conn: &Connection) -> Result<(), Error> {
+    let mut stmt = conn.prepare("SELECT * FROM pragma_foreign_key_check;")?;
+    let mut rows = stmt.query([])?;
+    let mut violations = vec![];
+
+    while let Some(row) = rows.next()? {
+        let table: String = row.get("table")?;
+        let rowid: Option<i64> = row.get("rowid")?;
+        let parent: String = row.get("parent")?;
+
+        violations.push(format!(
+            "a row{} of `{table}` refers to a row of `{parent}` that doesn't exist",
+            match rowid {
+                Some(rowid) => format!(" (rowid {rowid})"),
+                None => String::new(),
+            },
+        ));
+    }
+
+    if !violations.is_empty() {
+        return Err(Error::ForeignKeyViolation(violations));
+    }
+
+    Ok(())
+}
+
+fn restore_fts_config(conn: &Connection, table: &Table, fts_config: &str) -> Result<(), Error> {
+    let name = table.name.replace("\"", "\"\"");
+
+    for line in fts_config.lines() {
+        if line.is_empty() {
+            continue;
+        }
+
+        let (key, value) = parse_line(line.as_bytes())?;
+
+        // It's how fts5 sets a configuration: `INSERT INTO ft (ft, rank) VALUES ('automerge', 8);`
+        conn.execute(
+            &format!("INSERT INTO \"{name}\" (\"{name}\", rank) VALUES (?1, ?2);"),
+            params![key, value],
+        )?;
+    }
+
+    Ok(())
+}
+
+fn restore_fts_shadow(conn: &Connection, table: &Table, virtual_table: &VirtualTable, fts_shadow: &str) -> Result<(), Error> {
+    let shadow_name = |suffix: &str| format!("\"{}_{suffix}\"", table.name.replace("\"", "\"\""));
+
+    // `CREATE VIRTUAL TABLE` has initialized the shadow tables.
+    for suffix in virtual_table.shadow_tables().iter() {
+        let exists = conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_schema WHERE type = 'table' AND name = ?1;",
+            [format!("{}_{suffix}", table.name)],
+            |row| row.get::<_, i64>(0),
+        )? != 0;
+
+        if exists {
+            conn.execute(&format!("DELETE FROM {};", shadow_name(suffix)), [])?;
+        }
+    }
+
+    for row in fts_shadow.split("\n\n") {
+        let fields = row.lines().filter(
+            |line| !line.is_empty()
+        ).map(
+            |line| parse_line(line.as_bytes())
+        ).collect::<Result<Vec<_>, _>>()?;
+
+        let suffix = match fields.first() {
+            Some((key, Value::Text(suffix))) if key == "table" && virtual_table.shadow_tables().contains(&suffix.as_str()) => suffix,
+            None => {
+                continue;
+            },
+            _ => {
+                return Err(Error::CorruptedDataFile(format!("`{FTS_SHADOW_FILE}` of `{}` has an invalid row", table.name)));
+            },
+        };
+        let columns = &fields[1..];
+
+        conn.execute(
+            &format!(
+                "INSERT INTO {} ({}) VALUES ({});",
+                shadow_name(suffix),
+                columns.iter().map(|(column, _)| format!("\"{}\"", column.replace("\"", "\"\""))).collect::<Vec<_>>().join(", "),
+                vec!["?"; columns.len()].join(", "),
+            ),
+            params_from_iter(columns.iter().map(|(_, value)| value)),
+        )?;
+    }
+
+    Ok(())
+}
+
+// It has to run after all the data is inserted, because inserting rows to an AUTOINCREMENT
+// table also updates `sqlite_sequence`.
+fn restore_sqlite_sequence(conn: &Connection, sqlite_sequence: &str, tables: &TableFilter) -> Result<(), Error> {
+    let lines = sqlite_sequence.lines().filter(
+        |line| !line.is_empty()
+    ).map(
+        |line| parse_line(line.as_bytes())
+    ).collect::<Result<Vec<_>, _>>()?.into_iter().filter(
+        |(name, _)| tables.matches(name)
+    ).collect::<Vec<_>>();
+
+    if lines.is_empty() {
+        return Ok(());
+    }
+
+    // `sqlite_sequence` is created when the first AUTOINCREMENT table is created.
+    let has_sqlite_sequence = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_schema WHERE type = 'table' AND name = 'sqlite_sequence';",
+        [],
+        |row| row.get::<_, i64>(0),
+    )? != 0;
+
+    if !has_sqlite_sequence {
+        return Err(Error::CorruptedDataFile(format!("`{SQLITE_SEQUENCE_FILE}` is not empty, but there's no AUTOINCREMENT table")));
+    }
+
+    for (name, seq) in lines.iter() {
+        let updated = conn.execute(
+            "UPDATE sqlite_sequence SET seq = ?2 WHERE name = ?1;",
+            params![name, seq],
+        )?;
+
+        if updated == 0 {
+            conn.execute(
+                "INSERT INTO sqlite_sequence (name, seq) VALUES (?1, ?2);",
+                params![name, seq],
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_pragmas(s: &str) -> Result<Vec<(String, Value)>, Error> {
+    let mut result = vec![];
+
+    for line in s.lines() {
+        if line.is_empty() {
+            continue;
+        }
+
+        let (name, value) = parse_line(line.as_bytes())?;
+
+        // We don't want to run an arbitrary pragma from a (possibly hand-edited) file.
+        if !PRAGMAS.contains(&name.as_str()) {
+            return Err(Error::CorruptedDataFile(format!("unknown pragma in `{PRAGMA_FILE}`: {name:?}")));
+        }
+
+        result.push((name, value));
+    }
+
+    Ok(result)
+}
+
+fn apply_pragmas(conn: &Connection, pragmas: &[(String, Value)], names: &[&str]) -> Result<(), Error> {
+    for name in names.iter() {
+        for (pragma, value) in pragmas.iter() {
+            if pragma != name {
+                continue;
+            }
+
+            // `journal_mode` returns the new value, so `pragma_update` doesn't work.
+            if pragma == "journal_mode" {
+                conn.pragma_update_and_check(None, pragma, value, |_| Ok(()))?;
+            }
+
+            else {
+                conn.pragma_update(None, pragma, value)?;
+            }
         }
     }
 
@@ -0,0 +1,274 @@
+use super::{FromGitOptions, from_git_into_connection, from_git_with_options};
+use crate::error::Error;
+use crate::options::TableFilter;
+use crate::to_git::{ToGitOptions, to_git_from_connection, to_git_with_options};
+use crate::util::TempDir;
+use rusqlite::Connection;
+
+#[test]
+fn bulk_load() {
+    let tmp = TempDir::new("bulk-load");
+    let db_path = tmp.join("input.db").to_str().unwrap().to_string();
+    let output = tmp.join("output").to_str().unwrap().to_string();
+
+    let conn = Connection::open(&db_path).unwrap();
+    conn.execute_batch("
+        PRAGMA journal_mode = WAL;
+        CREATE TABLE t1 (a INTEGER, b TEXT);
+        CREATE INDEX t1_b ON t1 (b);
+        WITH RECURSIVE n(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n WHERE x < 5000)
+        INSERT INTO t1 SELECT x % 300, 'row ' || (x % 200) FROM n;
+        CREATE TABLE t2 (a TEXT PRIMARY KEY, b BLOB);
+        INSERT INTO t2 VALUES ('a', x'00'), ('b', x'01');
+    ").unwrap();
+    to_git_with_options(&db_path, &output, &ToGitOptions { preserve_rowid: true, ..ToGitOptions::default() }).unwrap();
+
+    let mut dumps = vec![];
+
+    for (name, options) in [
+        ("default.db", FromGitOptions::default()),
+        ("bulk.db", FromGitOptions { bulk_load: true, jobs: Some(4), integrity_check: true, ..FromGitOptions::default() }),
+    ] {
+        let restored_path = tmp.join(name).to_str().unwrap().to_string();
+        from_git_with_options(&output, &restored_path, &options).unwrap();
+
+        let restored = Connection::open(&restored_path).unwrap();
+        let journal_mode: String = restored.query_row("PRAGMA journal_mode;", [], |row| row.get(0)).unwrap();
+        assert_eq!(journal_mode, "wal");
+
+        let dump: Vec<(i64, i64, String)> = restored.prepare("SELECT rowid, a, b FROM t1 ORDER BY rowid;").unwrap().query_map(
+            [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        ).unwrap().map(|row| row.unwrap()).collect();
+        dumps.push(dump);
+    }
+
+    assert_eq!(dumps[0].len(), 5000);
+    assert_eq!(dumps[0], dumps[1]);
+}
+
+#[test]
+fn pragmas() {
+    let tmp = TempDir::new("pragmas");
+    let db_path = tmp.join("input.db").to_str().unwrap().to_string();
+    let restored_path = tmp.join("restored.db").to_str().unwrap().to_string();
+    let output = tmp.join("output").to_str().unwrap().to_string();
+
+    // `page_size`, `encoding` and `auto_vacuum` only work before the first table is created.
+    let conn = Connection::open(&db_path).unwrap();
+    conn.execute_batch("
+        PRAGMA page_size = 8192;
+        PRAGMA encoding = 'UTF-16le';
+        PRAGMA auto_vacuum = INCREMENTAL;
+        PRAGMA user_version = 7;
+        PRAGMA application_id = 1234567;
+        PRAGMA journal_mode = WAL;
+        CREATE TABLE t (a TEXT);
+        INSERT INTO t VALUES ('hello');
+    ").unwrap();
+    to_git_with_options(&db_path, &output, &ToGitOptions::default()).unwrap();
+
+    for bulk_load in [false, true] {
+        from_git_with_options(&output, &restored_path, &FromGitOptions::new().bulk_load(bulk_load)).unwrap();
+        let restored = Connection::open(&restored_path).unwrap();
+
+        for (pragma, value) in [
+            ("page_size", "8192"),
+            ("encoding", "UTF-16le"),
+            ("auto_vacuum", "2"),
+            ("user_version", "7"),
+            ("application_id", "1234567"),
+            ("journal_mode", "wal"),
+        ] {
+            let restored_value: String = restored.query_row(&format!("SELECT CAST({pragma} AS TEXT) FROM pragma_{pragma};"), [], |row| row.get(0)).unwrap();
+            assert_eq!((pragma, restored_value.as_str()), (pragma, value));
+        }
+
+        let a: String = restored.query_row("SELECT a FROM t;", [], |row| row.get(0)).unwrap();
+        assert_eq!(a, "hello");
+    }
+}
+
+#[test]
+fn keep_old_database_on_failure() {
+    let tmp = TempDir::new("keep-old-database");
+    let db_path = tmp.join("input.db").to_str().unwrap().to_string();
+    let restored_path = tmp.join("restored.db").to_str().unwrap().to_string();
+    let output = tmp.join("output").to_str().unwrap().to_string();
+
+    let conn = Connection::open(&db_path).unwrap();
+    conn.execute_batch("
+        CREATE TABLE t (a INTEGER PRIMARY KEY, b TEXT);
+        INSERT INTO t VALUES (1, 'a'), (2, 'b');
+    ").unwrap();
+    to_git_with_options(&db_path, &output, &ToGitOptions::default()).unwrap();
+    from_git_with_options(&output, &restored_path, &FromGitOptions::default()).unwrap();
+
+    // A broken bucket file, without the manifest that would catch it earlier.
+    std::fs::remove_file(tmp.join("output/manifest.toml")).unwrap();
+    let bucket = std::fs::read_dir(tmp.join("output/t")).unwrap().map(
+        |entry| entry.unwrap().path()
+    ).find(
+        |path| path.extension().is_none()
+    ).unwrap();
+    std::fs::write(&bucket, "garbage\n").unwrap();
+
+    for jobs in [None, Some(4)] {
+        assert!(from_git_with_options(&output, &restored_path, &FromGitOptions { jobs, ..FromGitOptions::default() }).is_err());
+    }
+
+    // The foreign keys are checked before the old database is replaced.
+    let fk_output = tmp.join("fk-output").to_str().unwrap().to_string();
+    let fk_conn = Connection::open_in_memory().unwrap();
+    fk_conn.execute_batch("
+        CREATE TABLE parent (id INTEGER PRIMARY KEY);
+        CREATE TABLE child (parent_id INTEGER REFERENCES parent (id));
+        INSERT INTO parent VALUES (1);
+        INSERT INTO child VALUES (1);
+    ").unwrap();
+    to_git_from_connection(&fk_conn, &fk_output, &ToGitOptions::default()).unwrap();
+    let options = FromGitOptions::new().tables(TableFilter::default().exclude("parent"));
+    assert!(matches!(from_git_with_options(&fk_output, &restored_path, &options), Err(Error::ForeignKeyViolation(_))));
+
+    let restored = Connection::open(&restored_path).unwrap();
+    let count: i64 = restored.query_row("SELECT COUNT(*) FROM t;", [], |row| row.get(0)).unwrap();
+    assert_eq!(count, 2);
+
+    // The temporary files are gone.
+    let mut files = std::fs::read_dir(&tmp).unwrap().map(
+        |entry| entry.unwrap().file_name().into_string().unwrap()
+    ).collect::<Vec<_>>();
+    files.sort();
+    assert_eq!(files, ["fk-output", "input.db", "output", "restored.db"]);
+}
+
+#[test]
+fn replace_database() {
+    let tmp = TempDir::new("replace-database");
+    let db_path = tmp.join("input.db").to_str().unwrap().to_string();
+    let restored_path = tmp.join("restored.db").to_str().unwrap().to_string();
+    let output = tmp.join("output").to_str().unwrap().to_string();
+
+    let conn = Connection::open(&db_path).unwrap();
+    conn.execute_batch("CREATE TABLE t (a INTEGER PRIMARY KEY, b TEXT);").unwrap();
+    to_git_with_options(&db_path, &output, &ToGitOptions::default()).unwrap();
+
+    let old = Connection::open(&restored_path).unwrap();
+    old.execute_batch("
+        PRAGMA journal_mode = WAL;
+        PRAGMA wal_autocheckpoint = 0;
+        CREATE TABLE old (a INTEGER);
+        INSERT INTO old VALUES (1);
+    ").unwrap();
+    let options = FromGitOptions { backup: true, ..FromGitOptions::default() };
+
+    // `old` is still open.
+    assert!(matches!(from_git_with_options(&output, &restored_path, &options), Err(Error::DatabaseInUse(_))));
+
+    // `restored.db-wal` is left behind, and it has the only copy of table `old`.
+    std::fs::copy(tmp.join("restored.db-wal"), tmp.join("stale-wal")).unwrap();
+    drop(old);
+    std::fs::rename(tmp.join("stale-wal"), tmp.join("restored.db-wal")).unwrap();
+
+    from_git_with_options(&output, &restored_path, &options).unwrap();
+
+    let mut files = std::fs::read_dir(&tmp).unwrap().map(
+        |entry| entry.unwrap().file_name().into_string().unwrap()
+    ).filter(
+        |name| name.starts_with("restored.db")
+    ).collect::<Vec<_>>();
+    files.sort();
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[0], "restored.db");
+
+    let backup = Connection::open(tmp.join(&files[1])).unwrap();
+    let count: i64 = backup.query_row("SELECT COUNT(*) FROM old;", [], |row| row.get(0)).unwrap();
+    assert_eq!(count, 1);
+
+    let restored = Connection::open(&restored_path).unwrap();
+    let count: i64 = restored.query_row("SELECT COUNT(*) FROM sqlite_master WHERE name = 'old';", [], |row| row.get(0)).unwrap();
+    assert_eq!(count, 0);
+
+    // A file that is not a database is only replaced with `force`.
+    let text_path = tmp.join("target.txt").to_str().unwrap().to_string();
+    std::fs::write(&text_path, "not a database").unwrap();
+    assert!(matches!(from_git_with_options(&output, &text_path, &FromGitOptions::default()), Err(Error::RefusedToOverwrite { .. })));
+    from_git_with_options(&output, &text_path, &FromGitOptions { force: true, ..FromGitOptions::default() }).unwrap();
+    let restored = Connection::open(&text_path).unwrap();
+    let count: i64 = restored.query_row("SELECT COUNT(*) FROM t;", [], |row| row.get(0)).unwrap();
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn swapped_arguments() {
+    let tmp = TempDir::new("swapped-arguments");
+    let output = tmp.join("output").to_str().unwrap().to_string();
+    let db_path = tmp.join("restored.db").to_str().unwrap().to_string();
+
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch("CREATE TABLE t (a); INSERT INTO t VALUES (1);").unwrap();
+    to_git_from_connection(&conn, &output, &ToGitOptions::default()).unwrap();
+    from_git_with_options(&output, &db_path, &FromGitOptions::default()).unwrap();
+
+    // Even with `force`, the output is not touched.
+    assert!(matches!(
+        from_git_with_options(&db_path, &output, &FromGitOptions::new().force(true)),
+        Err(Error::SwappedArguments { .. }),
+    ));
+    assert!(tmp.join("output/manifest.toml").exists());
+    from_git_with_options(&output, &db_path, &FromGitOptions::new().force(true)).unwrap();
+}
+
+#[test]
+fn into_connection() {
+    let tmp = TempDir::new("into-connection");
+    let output = tmp.join("output").to_str().unwrap().to_string();
+
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch("
+        CREATE TABLE parent (id INTEGER PRIMARY KEY);
+        CREATE TABLE child (id INTEGER PRIMARY KEY, parent INTEGER REFERENCES parent(id));
+        INSERT INTO parent VALUES (1), (2);
+        INSERT INTO child VALUES (1, 1), (2, 2);
+    ").unwrap();
+    to_git_from_connection(&conn, &output, &ToGitOptions::default()).unwrap();
+
+    let mut restored = Connection::open_in_memory().unwrap();
+    restored.pragma_update(None, "foreign_keys", true).unwrap();
+    let report = from_git_into_connection(&output, &mut restored, "main", &FromGitOptions::new().bulk_load(true)).unwrap();
+    assert_eq!(report.rows(), 4);
+
+    let count: i64 = restored.query_row("SELECT COUNT(*) FROM child JOIN parent ON child.parent = parent.id;", [], |row| row.get(0)).unwrap();
+    assert_eq!(count, 2);
+
+    // The settings of the connection are kept.
+    let foreign_keys: bool = restored.pragma_query_value(None, "foreign_keys", |row| row.get(0)).unwrap();
+    assert!(foreign_keys);
+
+    assert!(matches!(
+        from_git_into_connection(&output, &mut restored, "main", &FromGitOptions::default()),
+        Err(Error::DatabaseNotEmpty(_)),
+    ));
+
+    // So are the settings that the bulk load changes, even if the restore fails.
+    std::fs::write(tmp.join("output/trigger.sql"), "CREATE TRIGGER broken").unwrap();
+    let mut restored = Connection::open(tmp.join("restored.db")).unwrap();
+    restored.execute_batch("PRAGMA journal_mode = TRUNCATE; PRAGMA synchronous = FULL; PRAGMA cache_size = 1000;").unwrap();
+    assert!(from_git_into_connection(&output, &mut restored, "main", &FromGitOptions::new().bulk_load(true)).is_err());
+
+    let journal_mode: String = restored.pragma_query_value(None, "journal_mode", |row| row.get(0)).unwrap();
+    let synchronous: i64 = restored.pragma_query_value(None, "synchronous", |row| row.get(0)).unwrap();
+    let cache_size: i64 = restored.pragma_query_value(None, "cache_size", |row| row.get(0)).unwrap();
+    assert_eq!((journal_mode.as_str(), synchronous, cache_size), ("truncate", 2, 1000));
+
+    // The tables and the data of the failed restore are rolled back.
+    for bulk_load in [false, true] {
+        let mut restored = Connection::open(tmp.join(format!("rolled-back-{bulk_load}.db"))).unwrap();
+        assert!(from_git_into_connection(&output, &mut restored, "main", &FromGitOptions::new().bulk_load(bulk_load)).is_err());
+
+        let objects: i64 = restored.query_row("SELECT COUNT(*) FROM sqlite_master;", [], |row| row.get(0)).unwrap();
+        assert_eq!(objects, 0);
+        assert!(restored.is_autocommit());
+    }
+
+    drop(restored);
+}
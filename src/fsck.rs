@@ -0,0 +1,406 @@
+use crate::error::Error;
+use crate::layout::BucketLayout;
+use crate::manifest::{
+    FORMAT_VERSION,
+    MANIFEST_FILE,
+    Manifest,
+    ManifestTable,
+    read_manifest,
+    table_checksum,
+};
+use crate::record::{Record, parse_records, records_to_string, write_records};
+use crate::storage::FsStorage;
+use crate::table::Table;
+use crate::to_git::{ATTACHED_SCHEMA_PREFIX, get_db_schema_from_raw_sql};
+use ragit_fs::{
+    WriteMode,
+    basename,
+    exists,
+    is_dir,
+    join,
+    read_bytes,
+    read_dir,
+    read_string,
+    write_string,
+};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[cfg(test)]
+mod tests;
+
+/// A problem that `fsck` has found in an output directory.
+#[derive(Clone, Debug)]
+pub struct FsckIssue {
+    pub path: String,
+
+    // 1-based. It's `None` if the issue is about the whole file.
+    pub line: Option<usize>,
+
+    pub message: String,
+
+    // If it's not set, the user has to fix it by hand.
+    pub fixable: bool,
+
+    // It's only set when `fsck` is run with `fix`.
+    pub fixed: bool,
+}
+
+impl FsckIssue {
+    fn new(path: &str, line: Option<usize>, message: String, fixable: bool) -> Self {
+        FsckIssue {
+            path: path.to_string(),
+            line,
+            message,
+            fixable,
+            fixed: false,
+        }
+    }
+}
+
+impl fmt::Display for FsckIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}{}: {}{}",
+            self.path,
+            match self.line {
+                Some(line) => format!(":{line}"),
+                None => String::new(),
+            },
+            self.message,
+            if self.fixed { " (fixed)" } else if self.fixable { "" } else { " (cannot be fixed automatically)" },
+        )
+    }
+}
+
+/// It validates an output directory of `to_git`, without touching the database.
+/// It recomputes the id of every record, and checks that each record is in the right
+/// bucket file, that the ids are sorted, that the fields match `table.sql`, and that
+/// the manifest matches the data files.
+///
+/// If `fix` is set, it rewrites the data files (and the manifest) exactly like `to_git`
+/// would. A table with a problem that cannot be fixed automatically (e.g. a syntax error)
+/// is left untouched.
+pub fn fsck(data_dir: &str, fix: bool) -> Result<Vec<FsckIssue>, Error> {
+    let mut issues = vec![];
+    fsck_schema(data_dir, fix, &mut issues)?;
+
+    for dir in read_dir(data_dir, true)?.iter() {
+        if is_dir(dir) && basename(dir)?.starts_with(ATTACHED_SCHEMA_PREFIX) {
+            fsck_schema(dir, fix, &mut issues)?;
+        }
+    }
+
+    Ok(issues)
+}
+
+fn fsck_schema(schema_dir: &str, fix: bool, issues: &mut Vec<FsckIssue>) -> Result<(), Error> {
+    let manifest = read_manifest(&FsStorage::default(), schema_dir)?;
+
+    if let Some(manifest) = &manifest {
+        manifest.check_compatibility()?;
+    }
+
+    let old_tables = match &manifest {
+        Some(manifest) => manifest.tables.clone(),
+        None => vec![],
+    };
+
+    // The checksums are compared with the manifest, so they're computed the way the manifest did.
+    let format_version = manifest.as_ref().map(|manifest| manifest.format_version).unwrap_or(FORMAT_VERSION);
+    let mut new_tables = vec![];
+
+    // the tables that have passed `fsck_table`
+    let mut checked_tables = HashSet::new();
+
+    for table_dir in read_dir(schema_dir, true)?.iter() {
+        // It's not made by stfg (e.g. `.git/`).
+        if !is_dir(table_dir) || !exists(&join(table_dir, "table.sql")?) {
+            continue;
+        }
+
+        let dir = basename(table_dir)?;
+
+        // Outputs without a manifest always use the default layout.
+        let layout = old_tables.iter().find(|table| table.dir == dir).map(|table| table.layout).unwrap_or_default();
+
+        match fsck_table(table_dir, layout, format_version, fix, issues)? {
+            Some(table) => {
+                checked_tables.insert(dir);
+                new_tables.push(table);
+            },
+            // We don't want the manifest to bless a broken table.
+            None => {
+                if let Some(table) = old_tables.iter().find(|table| table.dir == dir) {
+                    new_tables.push(table.clone());
+                }
+            },
+        }
+    }
+
+    let manifest_path = join(schema_dir, MANIFEST_FILE)?;
+    let mut manifest_issues = vec![];
+
+    if manifest.is_none() {
+        manifest_issues.push(FsckIssue::new(&manifest_path, None, String::from("the manifest is missing"), true));
+    }
+
+    else {
+        for table in new_tables.iter() {
+            match old_tables.iter().find(|old| old.dir == table.dir) {
+                Some(old) if old == table => {},
+                Some(old) if old.checksum != table.checksum => {
+                    manifest_issues.push(FsckIssue::new(&manifest_path, None, format!("the checksum of table `{}` is {}, but the data files say {}", table.name, old.checksum, table.checksum), true));
+                },
+                Some(_) => {
+                    manifest_issues.push(FsckIssue::new(&manifest_path, None, format!("the entry of table `{}` is outdated", table.name), true));
+                },
+                None => {
+                    manifest_issues.push(FsckIssue::new(&manifest_path, None, format!("table `{}` is missing", table.name), true));
+                },
+            }
+        }
+
+        for old in old_tables.iter() {
+            if !new_tables.iter().any(|table| table.dir == old.dir) {
+                manifest_issues.push(FsckIssue::new(&manifest_path, None, format!("table `{}` is in the manifest, but its directory is missing", old.name), true));
+            }
+        }
+    }
+
+    if fix && !manifest_issues.is_empty() {
+        // The new manifest has the current format version.
+        if format_version != FORMAT_VERSION {
+            for table in new_tables.iter_mut().filter(|table| checked_tables.contains(&table.dir)) {
+                table.checksum = table_checksum(&FsStorage::default(), &join(schema_dir, &table.dir)?, table.layout, FORMAT_VERSION)?;
+            }
+        }
+
+        write_string(&manifest_path, &Manifest::new(new_tables).to_toml(), WriteMode::CreateOrTruncate)?;
+
+        for issue in manifest_issues.iter_mut() {
+            issue.fixed = true;
+        }
+    }
+
+    issues.append(&mut manifest_issues);
+    Ok(())
+}
+
+// It returns the manifest entry of the table, or `None` if the table has a problem that cannot be fixed.
+fn fsck_table(table_dir: &str, layout: BucketLayout, format_version: i64, fix: bool, issues: &mut Vec<FsckIssue>) -> Result<Option<ManifestTable>, Error> {
+    let table_sql_path = join(table_dir, "table.sql")?;
+    let table = match get_db_schema_from_raw_sql(&read_string(&table_sql_path)?) {
+        Ok(db) if db.tables.len() == 1 => db.tables[0].clone(),
+        Ok(db) => {
+            issues.push(FsckIssue::new(&table_sql_path, None, format!("expected exactly 1 `CREATE TABLE` statement, but got {}", db.tables.len()), false));
+            return Ok(None);
+        },
+        Err(e) => {
+            issues.push(FsckIssue::new(&table_sql_path, None, format!("failed to create the table: {e:?}"), false));
+            return Ok(None);
+        },
+    };
+    let dir = basename(table_dir)?;
+
+    // It doesn't affect `from_git`, but it's confusing.
+    if dir != table.escaped_name {
+        issues.push(FsckIssue::new(table_dir, None, format!("the directory of table `{}` has to be `{}`", table.name, table.escaped_name), false));
+    }
+
+    let mut table_issues = vec![];
+
+    // If it's set, the data files are not rewritten.
+    let mut is_broken = false;
+
+    // (path, line, record)
+    let mut records: Vec<(String, usize, Record)> = vec![];
+    let mut bucket_files: HashMap<u64, (String, Vec<u8>)> = HashMap::new();
+
+    for (bucket, file) in layout.list_buckets(&FsStorage::default(), table_dir)?.iter() {
+        let content = read_bytes(file)?;
+        let parsed = match String::from_utf8(content.clone()) {
+            Ok(s) => parse_records(&s),
+            Err(_) => {
+                table_issues.push(FsckIssue::new(file, None, String::from("not a valid utf-8 file"), false));
+                is_broken = true;
+                continue;
+            },
+        };
+        bucket_files.insert(*bucket, (file.to_string(), content));
+
+        let parsed = match parsed {
+            Ok(parsed) => parsed,
+            Err((line, e)) => {
+                table_issues.push(FsckIssue::new(
+                    file,
+                    Some(line),
+                    match e {
+                        Error::CorruptedDataFile(message) => message,
+                        e => format!("{e:?}"),
+                    },
+                    false,
+                ));
+                is_broken = true;
+                continue;
+            },
+        };
+
+        let mut prev_id = None;
+
+        for (line, mut record) in parsed.into_iter() {
+            if record.rowid.is_some() && table.hidden_rowid().is_none() {
+                table_issues.push(FsckIssue::new(file, Some(line), format!("the record has a rowid, but table `{}` cannot have one", table.name), false));
+                is_broken = true;
+            }
+
+            if !check_fields(&table, &mut record) {
+                table_issues.push(FsckIssue::new(
+                    file,
+                    Some(line),
+                    format!(
+                        "the fields ({}) don't match the columns of `table.sql` ({})",
+                        record.fields.iter().map(|(name, _)| format!("{name:?}")).collect::<Vec<_>>().join(", "),
+                        table.columns.iter().map(|name| format!("{name:?}")).collect::<Vec<_>>().join(", "),
+                    ),
+                    false,
+                ));
+                is_broken = true;
+                continue;
+            }
+
+            let written_id = record.id;
+
+            if let Some(prev_id) = prev_id && written_id < prev_id {
+                table_issues.push(FsckIssue::new(file, Some(line), String::from("the records are not sorted by id"), true));
+            }
+
+            prev_id = Some(written_id);
+            record.id = table.record_id(&record.fields)?;
+
+            if record.id != written_id {
+                table_issues.push(FsckIssue::new(file, Some(line), format!("the id has to be {:016x}, not {:016x}", record.id.0, written_id.0), true));
+            }
+
+            else if layout.bucket_of(record.id) != *bucket {
+                table_issues.push(FsckIssue::new(file, Some(line), format!("the record belongs to `{}`", layout.bucket_path(layout.bucket_of(record.id))), true));
+            }
+
+            records.push((file.to_string(), line, record));
+        }
+    }
+
+    if is_broken {
+        issues.append(&mut table_issues);
+        return Ok(None);
+    }
+
+    records.sort_by_key(|(_, _, record)| record.sort_key());
+    check_duplicates(&table, &records, &mut table_issues);
+
+    let mut buckets: HashMap<u64, Vec<Record>> = HashMap::new();
+
+    for (_, _, record) in records.iter() {
+        buckets.entry(layout.bucket_of(record.id)).or_default().push(record.clone());
+    }
+
+    // Whatever is left is written differently from what `to_git` would write
+    // (e.g. an escape sequence, an empty line or an empty bucket file).
+    for (bucket, (path, content)) in bucket_files.iter() {
+        let canonical = match buckets.get(bucket) {
+            Some(records) => records_to_string(records),
+            None => String::new(),
+        };
+
+        if (content != canonical.as_bytes() || canonical.is_empty()) && !table_issues.iter().any(|issue| &issue.path == path) {
+            table_issues.push(FsckIssue::new(path, None, String::from("the file is not in the canonical form"), true));
+        }
+    }
+
+    if fix && table_issues.iter().any(|issue| issue.fixable) {
+        for (bucket, records) in buckets.iter() {
+            write_records(&layout.create_bucket_path(table_dir, *bucket)?, records)?;
+        }
+
+        layout.remove_buckets(table_dir, &buckets.keys().copied().collect())?;
+
+        for issue in table_issues.iter_mut() {
+            if issue.fixable {
+                issue.fixed = true;
+            }
+        }
+    }
+
+    issues.append(&mut table_issues);
+
+    Ok(Some(ManifestTable {
+        name: table.name.to_string(),
+        dir,
+        rows: records.len() as i64,
+        layout,
+        checksum: table_checksum(&FsStorage::default(), table_dir, layout, format_version)?,
+    }))
+}
+
+// If the fields are the columns in a different order, it reorders the fields.
+// It returns false if they're not the same set.
+fn check_fields(table: &Table, record: &mut Record) -> bool {
+    if record.fields.len() != table.columns.len() {
+        return false;
+    }
+
+    if record.fields.iter().zip(table.columns.iter()).all(|((field, _), column)| field == column) {
+        return true;
+    }
+
+    let mut fields = Vec::with_capacity(record.fields.len());
+
+    for column in table.columns.iter() {
+        match record.fields.iter().position(|(field, _)| field == column) {
+            Some(index) => {
+                fields.push(record.fields[index].clone());
+            },
+            None => {
+                return false;
+            },
+        }
+    }
+
+    record.fields = fields;
+    true
+}
+
+// `records` is sorted by id. Records with the same id are either the same
+// row written twice (e.g. a bad merge) or a hash collision.
+fn check_duplicates(table: &Table, records: &[(String, usize, Record)], issues: &mut Vec<FsckIssue>) {
+    for window in records.windows(2) {
+        let (path1, line1, record1) = &window[0];
+        let (path2, line2, record2) = &window[1];
+
+        if record1.id != record2.id {
+            continue;
+        }
+
+        let same_key = if table.primary_key.is_empty() {
+            record1.fields == record2.fields
+        } else {
+            table.primary_key.iter().all(
+                |pk| record1.fields.iter().find(|(name, _)| name == pk) == record2.fields.iter().find(|(name, _)| name == pk)
+            )
+        };
+
+        // A table without a primary key may have the same row multiple times.
+        if table.primary_key.is_empty() && same_key {
+            continue;
+        }
+
+        let message = if same_key {
+            format!("the primary key is the same as the record at {path1}:{line1}")
+        } else {
+            format!("hash collision with the record at {path1}:{line1}")
+        };
+
+        issues.push(FsckIssue::new(path2, Some(*line2), message, false));
+    }
+}
@@ -0,0 +1,55 @@
+use super::fsck;
+use crate::from_git::{FromGitOptions, from_git_with_options};
+use crate::to_git::{ToGitOptions, to_git_from_connection};
+use crate::util::TempDir;
+use ragit_fs::{WriteMode, read_dir, read_string, write_string};
+use rusqlite::Connection;
+
+#[test]
+fn fsck_fix() {
+    let tmp = TempDir::new("fsck");
+    let output = tmp.join("output").to_str().unwrap().to_string();
+    let db_path = tmp.join("restored.db").to_str().unwrap().to_string();
+
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch("
+        CREATE TABLE t (a TEXT, b INTEGER);
+        INSERT INTO t VALUES ('x', 1), ('y', 2);
+    ").unwrap();
+    to_git_from_connection(&conn, &output, &ToGitOptions::default()).unwrap();
+    assert!(fsck(&output, false).unwrap().is_empty());
+
+    // A hand edit: the id of the record has to change, and it has to move to another bucket.
+    let bucket = read_dir(&format!("{output}/t"), true).unwrap().into_iter().find(
+        |file| read_string(file).unwrap().contains("\"x\"")
+    ).unwrap();
+    let edited = read_string(&bucket).unwrap().replace("\"b\"=1", "\"b\"=3");
+    write_string(&bucket, &edited, WriteMode::CreateOrTruncate).unwrap();
+
+    let issues = fsck(&output, false).unwrap();
+    assert!(!issues.is_empty());
+    assert!(issues.iter().all(|issue| issue.fixable && !issue.fixed));
+    assert!(from_git_with_options(&output, &db_path, &FromGitOptions::default()).is_err());
+
+    let issues = fsck(&output, true).unwrap();
+    assert!(issues.iter().all(|issue| issue.fixed));
+    assert!(fsck(&output, false).unwrap().is_empty());
+
+    from_git_with_options(&output, &db_path, &FromGitOptions::default()).unwrap();
+    let restored = Connection::open(&db_path).unwrap();
+    let b: i64 = restored.query_row("SELECT b FROM t WHERE a = 'x';", [], |row| row.get(0)).unwrap();
+    assert_eq!(b, 3);
+
+    // A syntax error cannot be fixed.
+    let bucket = read_dir(&format!("{output}/t"), true).unwrap().into_iter().find(
+        |file| read_string(file).unwrap().contains("\"y\"")
+    ).unwrap();
+    let edited = read_string(&bucket).unwrap().replace("\"b\"=2", "\"b\"=two");
+    write_string(&bucket, &edited, WriteMode::CreateOrTruncate).unwrap();
+
+    let issues = fsck(&output, true).unwrap();
+    assert_eq!(issues.len(), 1);
+    assert_eq!(issues[0].path, bucket);
+    assert_eq!(issues[0].line, Some(3));
+    assert!(!issues[0].fixed);
+}
@@ -0,0 +1,241 @@
+use crate::error::Error;
+use crate::storage::{SpoolWriter, Storage, StorageWriter, is_dir_of, list_dir, normalize_path};
+use ragit_fs::join;
+use std::collections::BTreeMap;
+use std::io::{BufWriter, Cursor, Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::Mutex;
+
+#[cfg(test)]
+mod tests;
+
+/// It writes an output of stfg straight into the object database of a git repository
+/// (with `git fast-import`), and commits it to a ref. Neither the working tree nor the
+/// index is touched, so there's nothing to `git add`.
+///
+/// The files are written as blobs as soon as they're written to the storage, but nothing
+/// is committed until `finish`. The commit's parent is the current commit of the ref (if
+/// there is one), and its tree is exactly the output (see `GitStorage::prefix`). Like
+/// `TarStorage`, it cannot read the files back.
+pub struct GitStorage {
+    repo: String,
+    git_ref: String,
+    prefix: String,
+    message: String,
+    inner: Mutex<GitWriter>,
+}
+
+struct GitWriter {
+    // They're `None` once the import is finished.
+    child: Option<Child>,
+    stdin: Option<BufWriter<ChildStdin>>,
+
+    // normalized path -> mark of the blob
+    files: BTreeMap<String, usize>,
+
+    // A path can be written more than once, so it's not `files.len()`.
+    last_mark: usize,
+}
+
+impl GitStorage {
+    /// `repo` is a path to a local repository (or its worktree), and `git_ref` is a full
+    /// name of the ref (e.g. `refs/heads/db`) that the commit goes to.
+    pub fn new(repo: &str, git_ref: &str) -> Result<Self, Error> {
+        let mut child = Command::new("git")
+            .args(["-C", repo, "fast-import", "--quiet", "--done"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::GitError(format!("failed to run `git fast-import`: {e}")))?;
+        let stdin = child.stdin.take().map(BufWriter::new);
+
+        Ok(GitStorage {
+            repo: repo.to_string(),
+            git_ref: git_ref.to_string(),
+            prefix: String::new(),
+            message: String::from("stfg to-git"),
+            inner: Mutex::new(GitWriter {
+                child: Some(child),
+                stdin,
+                files: BTreeMap::new(),
+                last_mark: 0,
+            }),
+        })
+    }
+
+    /// By default, the output is the entire tree of the commit. If it's set, the output
+    /// replaces `prefix` (a directory), and the rest of the parent's tree is kept.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = normalize_path(prefix);
+        self
+    }
+
+    pub fn message(mut self, message: &str) -> Self {
+        self.message = message.to_string();
+        self
+    }
+
+    /// It commits the files, updates the ref and returns the id of the commit. The committer
+    /// is whoever `git commit` would use (`git var GIT_COMMITTER_IDENT`).
+    pub fn finish(self) -> Result<String, Error> {
+        let mut inner = self.inner.into_inner().unwrap();
+        let committer = git(&self.repo, &["var", "GIT_COMMITTER_IDENT"])?;
+        let parent = rev_parse(&self.repo, &format!("{}^{{commit}}", self.git_ref))?;
+
+        let mut commit = vec![
+            format!("commit {}\n", self.git_ref),
+            format!("committer {committer}\n"),
+            format!("data {}\n{}\n", self.message.len(), self.message),
+        ];
+
+        if let Some(parent) = parent {
+            commit.push(format!("from {parent}\n"));
+        }
+
+        if self.prefix.is_empty() {
+            commit.push(String::from("deleteall\n"));
+        } else {
+            commit.push(format!("D {}\n", quote_path(&self.prefix)));
+        }
+
+        for (path, mark) in inner.files.iter() {
+            let path = if self.prefix.is_empty() { path.to_string() } else { format!("{}/{path}", self.prefix) };
+            commit.push(format!("M 100644 :{mark} {}\n", quote_path(&path)));
+        }
+
+        commit.push(String::from("\ndone\n"));
+        let mut stdin = inner.stdin.take().unwrap();
+        let result = stdin.write_all(commit.concat().as_bytes()).and_then(|_| stdin.flush());
+
+        // Otherwise, `git fast-import` would wait for more commands.
+        drop(stdin);
+        let output = inner.child.take().unwrap().wait_with_output().map_err(|e| Error::GitError(format!("{e}")))?;
+
+        if !output.status.success() {
+            return Err(Error::GitError(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+        }
+
+        result.map_err(|e| Error::GitError(format!("{e}")))?;
+        git(&self.repo, &["rev-parse", "--verify", &self.git_ref])
+    }
+
+    fn write_from(&self, path: &str, contents: &mut dyn Read, size: u64) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_mark += 1;
+        let mark = inner.last_mark;
+        let stdin = inner.stdin.as_mut().unwrap();
+
+        stdin.write_all(format!("blob\nmark :{mark}\ndata {size}\n").as_bytes()).and_then(
+            |_| std::io::copy(&mut contents.take(size), stdin)
+        ).and_then(
+            |copied| if copied == size {
+                stdin.write_all(b"\n")
+            } else {
+                Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "the file is shorter than its size"))
+            }
+        ).map_err(
+            |e| Error::GitError(format!("failed to write `{path}` to `git fast-import`: {e}"))
+        )?;
+
+        // If the same path is written twice, the last one wins.
+        inner.files.insert(normalize_path(path), mark);
+        Ok(())
+    }
+}
+
+impl Storage for GitStorage {
+    fn read(&self, path: &str) -> Result<Vec<u8>, Error> {
+        Err(Error::StorageError(format!("cannot read `{path}`: `GitStorage` is write-only")))
+    }
+
+    fn write(&self, path: &str, contents: &[u8]) -> Result<(), Error> {
+        self.write_from(path, &mut Cursor::new(contents), contents.len() as u64)
+    }
+
+    // `git fast-import` has to know the size of a blob before its contents.
+    fn create(&self, path: &str) -> Result<Box<dyn StorageWriter + '_>, Error> {
+        let path = path.to_string();
+        Ok(Box::new(SpoolWriter::new(move |contents, size| self.write_from(&path, contents, size))?))
+    }
+
+    fn read_dir(&self, dir: &str) -> Result<Vec<String>, Error> {
+        let inner = self.inner.lock().unwrap();
+        let mut result = vec![];
+
+        for name in list_dir(inner.files.keys(), &normalize_path(dir)).iter() {
+            result.push(join(dir, name)?);
+        }
+
+        Ok(result)
+    }
+
+    fn is_dir(&self, path: &str) -> bool {
+        is_dir_of(self.inner.lock().unwrap().files.keys(), &normalize_path(path))
+    }
+
+    fn is_file(&self, path: &str) -> bool {
+        self.inner.lock().unwrap().files.contains_key(&normalize_path(path))
+    }
+}
+
+// If it's dropped without `finish`, nothing is committed. The blobs that are
+// already written are left to `git gc`.
+impl Drop for GitWriter {
+    fn drop(&mut self) {
+        self.stdin = None;
+
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+// It returns the stdout of git, without the trailing newline.
+fn git(repo: &str, args: &[&str]) -> Result<String, Error> {
+    let output = Command::new("git")
+        .args(["-C", repo])
+        .args(args)
+        .output()
+        .map_err(|e| Error::GitError(format!("failed to run `git {}`: {e}", args.join(" "))))?;
+
+    if !output.status.success() {
+        return Err(Error::GitError(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// `None` if `rev` doesn't exist (e.g. a new branch).
+fn rev_parse(repo: &str, rev: &str) -> Result<Option<String>, Error> {
+    match Command::new("git").args(["-C", repo, "rev-parse", "--verify", "--quiet", rev]).output() {
+        Ok(output) if output.status.success() => Ok(Some(String::from_utf8_lossy(&output.stdout).trim().to_string())),
+        Ok(_) => Ok(None),
+        Err(e) => Err(Error::GitError(format!("failed to run `git rev-parse`: {e}"))),
+    }
+}
+
+// `git fast-import` reads a C-style quoted path, so a path can have any character.
+fn quote_path(path: &str) -> String {
+    let mut result = String::with_capacity(path.len() + 2);
+    result.push('"');
+
+    for ch in path.chars() {
+        match ch {
+            '"' | '\\' => {
+                result.push('\\');
+                result.push(ch);
+            },
+            '\n' => {
+                result.push_str("\\n");
+            },
+            _ => {
+                result.push(ch);
+            },
+        }
+    }
+
+    result.push('"');
+    result
+}
@@ -0,0 +1,73 @@
+use super::{GitStorage, git};
+use crate::from_git::{FromGitOptions, from_storage_into_connection};
+use crate::storage::{MemoryStorage, Storage};
+use crate::to_git::{ToGitOptions, to_git_into_storage};
+use crate::util::TempDir;
+use rusqlite::Connection;
+
+#[test]
+fn commit_to_ref() {
+    let tmp = TempDir::new("commit-to-ref");
+    let repo = tmp.to_str().unwrap().to_string();
+
+    git(&repo, &["init", "--quiet"]).unwrap();
+    git(&repo, &["config", "user.name", "stfg"]).unwrap();
+    git(&repo, &["config", "user.email", "stfg@example.com"]).unwrap();
+
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch("
+        CREATE TABLE t (a INTEGER PRIMARY KEY, b TEXT);
+        INSERT INTO t VALUES (1, 'one'), (2, 'two\nlines');
+        CREATE TABLE \"a \"\"quoted\"\" name\" (a TEXT);
+        INSERT INTO \"a \"\"quoted\"\" name\" VALUES ('x');
+    ").unwrap();
+
+    let storage = GitStorage::new(&repo, "refs/heads/db").unwrap().message("first dump");
+    to_git_into_storage(&conn, &storage, &ToGitOptions::new()).unwrap();
+    let first = storage.finish().unwrap();
+
+    // The tree of the commit is exactly what `to_git` would write.
+    let memory = MemoryStorage::new();
+    to_git_into_storage(&conn, &memory, &ToGitOptions::new()).unwrap();
+    assert_eq!(archive(&repo, "db").files(), memory.files());
+
+    let mut restored = Connection::open_in_memory().unwrap();
+    from_storage_into_connection(&archive(&repo, "db"), &mut restored, "main", &FromGitOptions::new()).unwrap();
+    assert_eq!(restored.query_row("SELECT b FROM t WHERE a = 2;", [], |row| row.get::<_, String>(0)).unwrap(), "two\nlines");
+
+    // The working tree is untouched.
+    assert_eq!(std::fs::read_dir(&tmp).unwrap().count(), 1);
+    assert_eq!(git(&repo, &["log", "--format=%s", "db"]).unwrap(), "first dump");
+
+    // With a prefix, the rest of the tree is kept.
+    conn.execute("DELETE FROM t WHERE a = 1;", []).unwrap();
+    let storage = GitStorage::new(&repo, "refs/heads/db").unwrap().prefix("dump");
+    to_git_into_storage(&conn, &storage, &ToGitOptions::new()).unwrap();
+    let second = storage.finish().unwrap();
+
+    assert_eq!(git(&repo, &["rev-parse", "db^"]).unwrap(), first);
+    assert_eq!(git(&repo, &["rev-parse", "db"]).unwrap(), second);
+    assert!(git(&repo, &["ls-tree", "--name-only", "db"]).unwrap().lines().any(|name| name == "manifest.toml"));
+    assert!(git(&repo, &["cat-file", "-e", "db:dump/manifest.toml"]).is_ok());
+
+    // If a path is written twice, the last one wins, and the other blobs are intact.
+    let storage = GitStorage::new(&repo, "refs/heads/overwrite").unwrap();
+    storage.write("a", b"A1").unwrap();
+    storage.write("a", b"A2").unwrap();
+    storage.write("b", b"B").unwrap();
+    storage.finish().unwrap();
+    assert_eq!(git(&repo, &["show", "overwrite:a"]).unwrap(), "A2");
+    assert_eq!(git(&repo, &["show", "overwrite:b"]).unwrap(), "B");
+
+    // Nothing is committed if it's not finished.
+    let storage = GitStorage::new(&repo, "refs/heads/db").unwrap();
+    to_git_into_storage(&conn, &storage, &ToGitOptions::new()).unwrap();
+    drop(storage);
+    assert_eq!(git(&repo, &["rev-parse", "db"]).unwrap(), second);
+}
+
+fn archive(repo: &str, rev: &str) -> MemoryStorage {
+    let output = std::process::Command::new("git").args(["-C", repo, "archive", "--format=tar", rev]).output().unwrap();
+    assert!(output.status.success());
+    MemoryStorage::from_tar(output.stdout.as_slice()).unwrap()
+}
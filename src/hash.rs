@@ -0,0 +1,128 @@
+use crate::error::Error;
+use crate::storage::Storage;
+use ragit_fs::join;
+
+/// The name and version of the algorithm that `RecordId::hash` uses. It's written
+/// to every output directory, so that stfg never mixes ids from different algorithms.
+///
+/// If you change anything in this file (or in `RecordId::hash`), every record
+/// will move to a different bucket file and git will see a giant meaningless diff.
+/// In that case, you have to bump the version.
+pub const HASH_ALGORITHM: &str = "siphash-1-3-v1";
+
+/// stfg writes `HASH_ALGORITHM` to this file, at the root of the output directory.
+pub(crate) const HASH_ALGORITHM_FILE: &str = "hash-algorithm";
+
+/// It makes sure that the ids in `output_path` are created by `HASH_ALGORITHM`.
+///
+/// Outputs of old versions of stfg don't have the file. They were created by
+/// `DefaultHasher`, which is exactly the same as "siphash-1-3-v1", so it's okay.
+pub(crate) fn check_hash_algorithm(storage: &dyn Storage, output_path: &str) -> Result<(), Error> {
+    let path = join(output_path, HASH_ALGORITHM_FILE)?;
+
+    if !storage.is_file(&path) {
+        return Ok(());
+    }
+
+    let found = storage.read_string(&path)?;
+    let found = found.trim();
+
+    if found != HASH_ALGORITHM {
+        return Err(Error::HashAlgorithmMismatch {
+            expected: HASH_ALGORITHM.to_string(),
+            found: found.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+/// SipHash-1-3 with zero keys.
+///
+/// It used to be `std::collections::hash_map::DefaultHasher`, but std doesn't guarantee
+/// that the algorithm of `DefaultHasher` stays the same across rust versions. This is
+/// a frozen copy of what `DefaultHasher::new()` did when stfg was first written, so that
+/// the old outputs still have the same ids.
+#[derive(Clone, Copy)]
+pub(crate) struct StableHasher {
+    v0: u64,
+    v1: u64,
+    v2: u64,
+    v3: u64,
+
+    // bytes that are not processed yet (less than 8 bytes)
+    tail: u64,
+    tail_len: usize,
+
+    // total number of bytes written
+    length: usize,
+}
+
+impl StableHasher {
+    pub fn new() -> Self {
+        let (k0, k1) = (0u64, 0u64);
+
+        StableHasher {
+            v0: k0 ^ 0x736f6d6570736575,
+            v1: k1 ^ 0x646f72616e646f6d,
+            v2: k0 ^ 0x6c7967656e657261,
+            v3: k1 ^ 0x7465646279746573,
+            tail: 0,
+            tail_len: 0,
+            length: 0,
+        }
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        self.length += bytes.len();
+
+        for b in bytes.iter() {
+            self.tail |= (*b as u64) << (8 * self.tail_len);
+            self.tail_len += 1;
+
+            if self.tail_len == 8 {
+                let m = self.tail;
+                self.compress(m);
+                self.tail = 0;
+                self.tail_len = 0;
+            }
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        let mut state = *self;
+        let b = ((state.length as u64 & 0xff) << 56) | state.tail;
+
+        state.compress(b);
+        state.v2 ^= 0xff;
+
+        for _ in 0..3 {
+            state.round();
+        }
+
+        state.v0 ^ state.v1 ^ state.v2 ^ state.v3
+    }
+
+    fn compress(&mut self, m: u64) {
+        self.v3 ^= m;
+        self.round();
+        self.v0 ^= m;
+    }
+
+    fn round(&mut self) {
+        self.v0 = self.v0.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(13);
+        self.v1 ^= self.v0;
+        self.v0 = self.v0.rotate_left(32);
+        self.v2 = self.v2.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(16);
+        self.v3 ^= self.v2;
+        self.v0 = self.v0.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(21);
+        self.v3 ^= self.v0;
+        self.v2 = self.v2.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(17);
+        self.v1 ^= self.v2;
+        self.v2 = self.v2.rotate_left(32);
+    }
+}
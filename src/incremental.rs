@@ -0,0 +1,313 @@
+use crate::error::Error;
+use crate::manifest::MANIFEST_FILE;
+use crate::staging::Staging;
+use crate::storage::{Storage, StorageWriter, is_dir_of, list_dir, normalize_path};
+use crate::to_git::{ATTACHED_SCHEMA_PREFIX, is_stfg_output};
+use ragit_fs::{
+    FileError,
+    basename,
+    create_dir_all,
+    exists,
+    is_dir,
+    join,
+    parent,
+    read_dir,
+    remove_dir_all,
+    remove_file,
+    rename,
+};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::sync::Mutex;
+
+#[cfg(test)]
+mod tests;
+
+/// An output of an incremental dump (see `ToGitOptions::incremental`). The paths are
+/// relative to `output_path`, the existing output.
+///
+/// A file is compared with the existing file while it's written, and it's only written
+/// (to a temporary directory) once its contents turn out to be different. So a file that
+/// hasn't changed is neither written nor read twice. `commit` moves the changed files
+/// into `output_path`, and then removes the files that are no longer in the output.
+/// Until then, `output_path` is untouched.
+pub(crate) struct IncrementalStorage {
+    output_path: String,
+    staging: Staging,
+    inner: Mutex<IncrementalFiles>,
+}
+
+#[derive(Default)]
+struct IncrementalFiles {
+    // normalized paths of every file in the new output, whether it has changed or not
+    files: BTreeSet<String>,
+
+    // normalized paths of the files that are written to `staging`
+    changed: BTreeSet<String>,
+}
+
+impl IncrementalStorage {
+    pub fn new(output_path: &str) -> Result<Self, Error> {
+        Ok(IncrementalStorage {
+            output_path: output_path.to_string(),
+            staging: Staging::new_dir(output_path)?,
+            inner: Mutex::new(IncrementalFiles::default()),
+        })
+    }
+
+    /// It makes `output_path` the same as the new output. It's not atomic (see
+    /// `ToGitOptions::incremental`), but the order is chosen so that an interrupted
+    /// commit is caught by the checksums: the changed files are moved first, then the
+    /// old files are removed, and the manifests come last.
+    pub fn commit(self) -> Result<(), Error> {
+        let inner = std::mem::take(&mut *self.inner.lock().unwrap());
+        let mut stale = vec![];
+        find_stale_files(&self.output_path, "", &inner.files, true, &mut stale)?;
+
+        // A file cannot be renamed over a directory (or into a file), so such
+        // files have to wait until the old ones are removed.
+        let mut blocked = vec![];
+        let mut manifests = vec![];
+
+        for path in inner.changed.iter() {
+            if basename(path)? == MANIFEST_FILE {
+                manifests.push(path);
+            }
+
+            else if is_blocked(&self.output_path, path)? {
+                blocked.push(path);
+            }
+
+            else {
+                self.move_into_place(path)?;
+            }
+        }
+
+        for path in stale.iter() {
+            if is_dir(path) {
+                remove_dir_all(path)?;
+            }
+
+            else {
+                remove_file(path)?;
+            }
+        }
+
+        for path in blocked.into_iter().chain(manifests) {
+            self.move_into_place(path)?;
+        }
+
+        Ok(())
+    }
+
+    fn move_into_place(&self, path: &str) -> Result<(), Error> {
+        let target = join(&self.output_path, path)?;
+        let parent_dir = parent(&target)?;
+
+        if !exists(&parent_dir) {
+            create_dir_all(&parent_dir)?;
+        }
+
+        rename(&join(&self.staging.path, path)?, &target)?;
+        Ok(())
+    }
+}
+
+impl Storage for IncrementalStorage {
+    fn read(&self, path: &str) -> Result<Vec<u8>, Error> {
+        Err(Error::StorageError(format!("cannot read `{path}`: `IncrementalStorage` is write-only")))
+    }
+
+    fn write(&self, path: &str, contents: &[u8]) -> Result<(), Error> {
+        let mut writer = self.create(path)?;
+        writer.write_all(contents).map_err(|e| FileError::from_std(e, path))?;
+        writer.finish()
+    }
+
+    fn create(&self, path: &str) -> Result<Box<dyn StorageWriter + '_>, Error> {
+        let old_path = join(&self.output_path, path)?;
+        let old = if exists(&old_path) && !is_dir(&old_path) {
+            Some(BufReader::new(File::open(&old_path).map_err(|e| FileError::from_std(e, &old_path))?))
+        } else {
+            None
+        };
+
+        let mut writer = IncrementalWriter {
+            storage: self,
+            path: normalize_path(path),
+            old_path,
+            old,
+            same: 0,
+            new: None,
+        };
+
+        if writer.old.is_none() {
+            writer.start_new().map_err(|e| FileError::from_std(e, path))?;
+        }
+
+        Ok(Box::new(writer))
+    }
+
+    fn read_dir(&self, dir: &str) -> Result<Vec<String>, Error> {
+        let inner = self.inner.lock().unwrap();
+        let mut result = vec![];
+
+        for name in list_dir(inner.files.iter(), &normalize_path(dir)).iter() {
+            result.push(join(dir, name)?);
+        }
+
+        Ok(result)
+    }
+
+    fn is_dir(&self, path: &str) -> bool {
+        is_dir_of(self.inner.lock().unwrap().files.iter(), &normalize_path(path))
+    }
+
+    fn is_file(&self, path: &str) -> bool {
+        self.inner.lock().unwrap().files.contains(&normalize_path(path))
+    }
+}
+
+struct IncrementalWriter<'a> {
+    storage: &'a IncrementalStorage,
+    path: String,
+    old_path: String,
+
+    // The existing file, as long as the contents are the same as it.
+    old: Option<BufReader<File>>,
+
+    // How many bytes are the same as the existing file.
+    same: u64,
+
+    // The file in the staging directory, once the contents are different.
+    new: Option<BufWriter<File>>,
+}
+
+impl IncrementalWriter<'_> {
+    // The contents turn out to be different. The bytes that are the same as the existing
+    // file are copied from it, and the rest goes to the new file.
+    fn start_new(&mut self) -> std::io::Result<()> {
+        let path = std::path::Path::new(&self.storage.staging.path).join(&self.path);
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut new = BufWriter::new(File::create(path)?);
+
+        if self.old.take().is_some() {
+            std::io::copy(&mut File::open(&self.old_path)?.take(self.same), &mut new)?;
+        }
+
+        self.new = Some(new);
+        Ok(())
+    }
+}
+
+impl Write for IncrementalWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(old) = &mut self.old {
+            let mut chunk = vec![0; buf.len()];
+            let len = read_up_to(old, &mut chunk)?;
+
+            if chunk[..len] == *buf {
+                self.same += buf.len() as u64;
+                return Ok(buf.len());
+            }
+
+            self.start_new()?;
+        }
+
+        self.new.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.new {
+            Some(new) => new.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl StorageWriter for IncrementalWriter<'_> {
+    fn finish(mut self: Box<Self>) -> Result<(), Error> {
+        // The existing file is longer.
+        if let Some(old) = &mut self.old
+            && read_up_to(old, &mut [0]).map_err(|e| FileError::from_std(e, &self.old_path))? != 0
+        {
+            self.start_new().map_err(|e| FileError::from_std(e, &self.path))?;
+        }
+
+        let mut inner = self.storage.inner.lock().unwrap();
+        inner.files.insert(self.path.clone());
+
+        if let Some(new) = &mut self.new {
+            new.flush().map_err(|e| FileError::from_std(e, &self.path))?;
+            inner.changed.insert(self.path.clone());
+        }
+
+        Ok(())
+    }
+}
+
+// `Read::read_exact`, but it stops at the end of the file.
+fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+
+    while len < buf.len() {
+        match reader.read(&mut buf[len..])? {
+            0 => break,
+            n => {
+                len += n;
+            },
+        }
+    }
+
+    Ok(len)
+}
+
+// Files and directories in `output_path/dir` that are not in the new output. In the root of
+// an output (`is_root`), it only removes what stfg has created (see `is_stfg_output`), so that
+// it doesn't remove `.git/` or anything that the user has put there.
+fn find_stale_files(output_path: &str, dir: &str, files: &BTreeSet<String>, is_root: bool, result: &mut Vec<String>) -> Result<(), Error> {
+    for old_path in read_dir(&join(output_path, dir)?, false)?.iter() {
+        let name = basename(old_path)?;
+        let path = if dir.is_empty() { name.clone() } else { format!("{dir}/{name}") };
+
+        if is_root && !is_stfg_output(old_path)? {
+            continue;
+        }
+
+        if is_dir(old_path) && is_dir_of(files.iter(), &path) {
+            find_stale_files(output_path, &path, files, is_root && name.starts_with(ATTACHED_SCHEMA_PREFIX), result)?;
+        }
+
+        else if is_dir(old_path) || !files.contains(&path) {
+            result.push(old_path.to_string());
+        }
+    }
+
+    Ok(())
+}
+
+// If a directory is in the way of `path`, or a file is in the way of its parent
+// directories, it cannot be moved into place before they're removed.
+fn is_blocked(output_path: &str, path: &str) -> Result<bool, Error> {
+    let mut curr = output_path.to_string();
+    let components = path.split('/').collect::<Vec<_>>();
+
+    for (i, component) in components.iter().enumerate() {
+        curr = join(&curr, component)?;
+
+        if !exists(&curr) {
+            return Ok(false);
+        }
+
+        if is_dir(&curr) == (i == components.len() - 1) {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
@@ -0,0 +1,70 @@
+use super::IncrementalStorage;
+use crate::storage::Storage;
+use crate::util::TempDir;
+use std::io::Write;
+
+#[test]
+fn only_changed_files_are_staged() {
+    let tmp = TempDir::new("incremental-storage");
+    let output = tmp.join("output");
+    std::fs::create_dir_all(output.join("t/0")).unwrap();
+
+    for (path, contents) in [
+        ("manifest.toml", "old manifest"),
+        ("t/table.sql", "CREATE TABLE t (a);"),
+        ("t/same", "same contents"),
+        ("t/longer", "a"),
+        ("t/shorter", "abc"),
+        ("t/0/1", "a bucket of the old layout"),
+        ("t/stale", "stale"),
+        ("notes.txt", "not an output of stfg"),
+    ] {
+        std::fs::write(output.join(path), contents).unwrap();
+    }
+
+    let storage = IncrementalStorage::new(output.to_str().unwrap()).unwrap();
+    storage.write("manifest.toml", b"new manifest").unwrap();
+    storage.write("t/table.sql", b"CREATE TABLE t (a);").unwrap();
+    storage.write("t/longer", b"ab").unwrap();
+    storage.write("t/shorter", b"ab").unwrap();
+    storage.write("t/0", b"a bucket of the new layout").unwrap();
+
+    // It's written a chunk at a time, like a bucket.
+    let mut writer = storage.create("t/same").unwrap();
+    writer.write_all(b"same ").unwrap();
+    writer.write_all(b"contents").unwrap();
+    writer.finish().unwrap();
+
+    // Only the changed files are written, and the output is untouched until `commit`.
+    let staged = walk(std::path::Path::new(&storage.staging.path));
+    assert_eq!(staged, vec!["manifest.toml", "t/0", "t/longer", "t/shorter"]);
+    assert!(output.join("t/stale").exists());
+
+    storage.commit().unwrap();
+    assert_eq!(walk(&output), vec!["manifest.toml", "notes.txt", "t/0", "t/longer", "t/same", "t/shorter", "t/table.sql"]);
+    assert_eq!(std::fs::read_to_string(output.join("t/0")).unwrap(), "a bucket of the new layout");
+    assert_eq!(std::fs::read_to_string(output.join("t/longer")).unwrap(), "ab");
+    assert_eq!(std::fs::read_to_string(output.join("t/shorter")).unwrap(), "ab");
+    assert_eq!(std::fs::read_dir(&tmp).unwrap().count(), 1);
+}
+
+// relative paths of the files under `dir`, sorted
+fn walk(dir: &std::path::Path) -> Vec<String> {
+    let mut result = vec![];
+
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+
+        if path.is_dir() {
+            result.extend(walk(&path).into_iter().map(|child| format!("{name}/{child}")));
+        }
+
+        else {
+            result.push(name);
+        }
+    }
+
+    result.sort();
+    result
+}
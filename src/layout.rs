@@ -0,0 +1,201 @@
+use crate::error::Error;
+use crate::record::RecordId;
+use crate::storage::{FsStorage, Storage};
+use ragit_fs::{
+    basename,
+    create_dir_all,
+    exists,
+    join,
+    parent,
+    read_dir,
+    remove_dir,
+    remove_file,
+};
+use std::collections::HashSet;
+use std::fmt;
+
+#[cfg(test)]
+mod tests;
+
+/// The file of `BucketLayout::Single`.
+pub(crate) const SINGLE_BUCKET_FILE: &str = "records";
+
+/// How the records of a table are split into files (buckets). A record goes to
+/// a bucket based on the first bits of its id (see `RecordId`).
+///
+/// A bucket is named with octal digits of the bits, so that `Flat(3)`, the
+/// default, has the same file names as the old versions of stfg (`000` ~ `777`).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum BucketLayout {
+    /// All the records are in a single file, `records`. It's good for small tables.
+    Single,
+
+    /// `8^n` files, named with n octal digits. `Flat(3)` has 512 files.
+    Flat(u32),
+
+    /// `8^n` files in 2 levels. The first 3 digits are the directory and the rest are
+    /// the file, so that a directory doesn't have too many files. `Nested(6)` has
+    /// 512 directories (`000/` ~ `777/`), and each has 512 files. It's good for huge tables.
+    Nested(u32),
+}
+
+impl Default for BucketLayout {
+    fn default() -> Self {
+        BucketLayout::Flat(3)
+    }
+}
+
+impl BucketLayout {
+    /// `"single"`, `"flat-3"` or `"nested-6"`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let result = match s {
+            "single" => BucketLayout::Single,
+            _ => match s.split_once('-') {
+                Some(("flat", n)) => BucketLayout::Flat(n.parse().ok()?),
+                Some(("nested", n)) => BucketLayout::Nested(n.parse().ok()?),
+                _ => {
+                    return None;
+                },
+            },
+        };
+
+        if result.is_valid() {
+            Some(result)
+        }
+
+        else {
+            None
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        match self {
+            BucketLayout::Single => true,
+            BucketLayout::Flat(n) => (1..=4).contains(n),
+            BucketLayout::Nested(n) => (4..=7).contains(n),
+        }
+    }
+
+    fn digits(&self) -> u32 {
+        match self {
+            BucketLayout::Single => 0,
+            BucketLayout::Flat(n) | BucketLayout::Nested(n) => *n,
+        }
+    }
+
+    pub fn bucket_count(&self) -> u64 {
+        1 << (3 * self.digits())
+    }
+
+    pub(crate) fn bucket_of(&self, id: RecordId) -> u64 {
+        match self.digits() {
+            0 => 0,
+            n => id.0 >> (64 - 3 * n),
+        }
+    }
+
+    /// A path of the bucket, relative to the directory of the table.
+    pub(crate) fn bucket_path(&self, bucket: u64) -> String {
+        match self {
+            BucketLayout::Single => SINGLE_BUCKET_FILE.to_string(),
+            BucketLayout::Flat(n) => format!("{bucket:0width$o}", width = *n as usize),
+            BucketLayout::Nested(n) => {
+                let s = format!("{bucket:0width$o}", width = *n as usize);
+                format!("{}/{}", &s[..3], &s[3..])
+            },
+        }
+    }
+
+    /// It creates the parent directory of the bucket if it doesn't exist.
+    pub(crate) fn create_bucket_path(&self, data_dir: &str, bucket: u64) -> Result<String, Error> {
+        let path = join(data_dir, &self.bucket_path(bucket))?;
+        let parent_dir = parent(&path)?;
+
+        if !exists(&parent_dir) {
+            create_dir_all(&parent_dir)?;
+        }
+
+        Ok(path)
+    }
+
+    /// (bucket, path) of the existing bucket files in `data_dir`, sorted by the bucket.
+    /// Files that don't belong to this layout are ignored.
+    pub(crate) fn list_buckets(&self, storage: &dyn Storage, data_dir: &str) -> Result<Vec<(u64, String)>, Error> {
+        let mut result = vec![];
+
+        match self {
+            BucketLayout::Single => {
+                let path = join(data_dir, SINGLE_BUCKET_FILE)?;
+
+                if storage.is_file(&path) {
+                    result.push((0, path));
+                }
+            },
+            BucketLayout::Flat(n) => {
+                for file in storage.read_dir(data_dir)?.iter() {
+                    if let Some(bucket) = parse_octal(&basename(file)?, *n) && !storage.is_dir(file) {
+                        result.push((bucket, file.to_string()));
+                    }
+                }
+            },
+            BucketLayout::Nested(n) => {
+                for dir in storage.read_dir(data_dir)?.iter() {
+                    let Some(high) = parse_octal(&basename(dir)?, 3) else { continue; };
+
+                    if !storage.is_dir(dir) {
+                        continue;
+                    }
+
+                    for file in storage.read_dir(dir)?.iter() {
+                        if let Some(low) = parse_octal(&basename(file)?, n - 3) && !storage.is_dir(file) {
+                            result.push(((high << (3 * (n - 3))) | low, file.to_string()));
+                        }
+                    }
+                }
+            },
+        }
+
+        result.sort_by_key(|(bucket, _)| *bucket);
+        Ok(result)
+    }
+
+    /// It removes the bucket files that are not in `keep`, and the directories that become empty.
+    pub(crate) fn remove_buckets(&self, data_dir: &str, keep: &HashSet<u64>) -> Result<(), Error> {
+        let mut parents = HashSet::new();
+
+        for (bucket, path) in self.list_buckets(&FsStorage::default(), data_dir)?.iter() {
+            if !keep.contains(bucket) {
+                remove_file(path)?;
+                parents.insert(parent(path)?);
+            }
+        }
+
+        if let BucketLayout::Nested(_) = self {
+            for dir in parents.iter() {
+                if read_dir(dir, false)?.is_empty() {
+                    remove_dir(dir)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for BucketLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BucketLayout::Single => write!(f, "single"),
+            BucketLayout::Flat(n) => write!(f, "flat-{n}"),
+            BucketLayout::Nested(n) => write!(f, "nested-{n}"),
+        }
+    }
+}
+
+fn parse_octal(s: &str, digits: u32) -> Option<u64> {
+    if s.len() != digits as usize || !s.bytes().all(|b| matches!(b, b'0'..=b'7')) {
+        return None;
+    }
+
+    u64::from_str_radix(s, 8).ok()
+}
@@ -0,0 +1,27 @@
+use super::BucketLayout;
+use crate::record::RecordId;
+
+#[test]
+fn bucket_layout() {
+    let id = RecordId(0xd1fba762150c532c);
+
+    for (layout, s, bucket_path) in [
+        (BucketLayout::Single, "single", "records"),
+        (BucketLayout::Flat(1), "flat-1", "6"),
+        (BucketLayout::Flat(3), "flat-3", "643"),
+        (BucketLayout::Nested(6), "nested-6", "643/756"),
+    ] {
+        assert_eq!(BucketLayout::parse(s), Some(layout));
+        assert_eq!(layout.to_string(), s);
+        assert_eq!(layout.bucket_path(layout.bucket_of(id)), bucket_path);
+    }
+
+    // `Flat(3)` has to be compatible with the old versions of stfg.
+    assert_eq!(BucketLayout::default(), BucketLayout::Flat(3));
+    assert_eq!(BucketLayout::default().bucket_count(), 512);
+    assert_eq!(BucketLayout::default().bucket_of(id), id.0 >> 55);
+
+    for invalid in ["flat-0", "flat-5", "nested-3", "nested", "double"] {
+        assert_eq!(BucketLayout::parse(invalid), None);
+    }
+}
@@ -1,13 +1,54 @@
 mod db;
 mod error;
+mod export;
 mod from_git;
+mod fsck;
+mod git;
+mod hash;
+mod incremental;
+mod layout;
+mod manifest;
+mod options;
 mod record;
+#[cfg(feature = "serde")]
+mod record_serde;
+mod report;
+mod snapshot;
+mod sort;
+mod staging;
+mod storage;
 mod table;
+mod tar;
 mod to_git;
 mod util;
 mod value;
 mod view;
+mod vtable;
 
 pub use error::Error;
-pub use to_git::to_git;
+pub use export::{ExportDir, Records, TableReader};
+pub use git::GitStorage;
+pub use hash::HASH_ALGORITHM;
+pub use layout::BucketLayout;
+pub use options::{ProgressCallback, TableFilter};
+pub use record::{Record, RecordId};
+#[cfg(feature = "serde")]
+pub use record_serde::{from_record, to_record};
+pub use report::{Report, TableReport};
+pub use sort::DEFAULT_SPILL_THRESHOLD;
+pub use storage::{FsStorage, MemoryStorage, Storage, StorageWriter};
+pub use tar::TarStorage;
+pub use value::Value;
+pub use to_git::{ToGitOptions, to_git, to_git_from_connection, to_git_into_storage, to_git_with_options};
+#[cfg(feature = "serialize")]
+pub use to_git::to_git_from_bytes;
+#[allow(deprecated)]
 pub use from_git::from_git;
+pub use from_git::{
+    FromGitOptions,
+    from_git_into_connection,
+    from_git_schema,
+    from_git_with_options,
+    from_storage_into_connection,
+};
+pub use fsck::{FsckIssue, fsck};
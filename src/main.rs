@@ -5,7 +5,21 @@ use ragit_cli::{
     Span,
     get_closest_string,
 };
-use stfg::{Error, from_git, to_git};
+use rusqlite::{Connection, OpenFlags};
+use stfg::{
+    BucketLayout,
+    Error,
+    FromGitOptions,
+    GitStorage,
+    TableFilter,
+    ToGitOptions,
+    from_git_schema,
+    from_git_with_options,
+    fsck,
+    to_git_from_connection,
+    to_git_into_storage,
+};
+use std::collections::HashMap;
 
 fn main() {
     let args = std::env::args().collect::<Vec<_>>();
@@ -22,6 +36,15 @@ fn main() {
                     },
                 );
             },
+            Error::RefusedToOverwrite { path, files } => {
+                eprintln!("refused to overwrite `{path}`. These were not created by stfg, and would be removed:");
+
+                for file in files.iter() {
+                    eprintln!("    {file}");
+                }
+
+                eprintln!("Run it again with `--force` if you're sure.");
+            },
             _ => {
                 eprintln!("{e:?}");
             },
@@ -35,25 +58,139 @@ fn run(args: &[String]) -> Result<(), Error> {
     match args.get(1).map(|arg| arg.as_str()) {
         Some("to-git" | "from-sql") => {
             let parsed_args = ArgParser::new()
+                .optional_flag(&["--incremental"])
+                .optional_flag(&["--preserve-rowid"])
+                .optional_flag(&["--force"])
                 .arg_flag("--output", ArgType::String)
+                .optional_arg_flag("--attach", ArgType::String)
+                .optional_arg_flag("--layout", ArgType::String)
+                .optional_arg_flag("--spill-threshold", ArgType::integer_between(Some(1), None))
+                .optional_arg_flag("--jobs", ArgType::integer_between(Some(1), None))
+                .optional_arg_flag("--tables", ArgType::String)
+                .optional_arg_flag("--exclude-tables", ArgType::String)
+                .optional_arg_flag("--git-ref", ArgType::String)
+                .optional_arg_flag("--git-prefix", ArgType::String)
                 .short_flag(&["--output"])
                 .args(ArgType::String, ArgCount::Exact(1))
-                .parse(&args, 2)?;
+                .parse(args, 2)?;
 
             let input = parsed_args.get_args_exact(1)?[0].clone();
             let output = parsed_args.arg_flags.get("--output").unwrap().to_string();
-            to_git(&input, &output)?;
+            let attachments = parse_pairs(parsed_args.arg_flags.get("--attach"), args, "name=path")?;
+            let tables = parse_table_filter(parsed_args.arg_flags.get("--tables"), parsed_args.arg_flags.get("--exclude-tables"));
+            let mut bucket_layouts = HashMap::new();
+            let mut default_bucket_layout = None;
+
+            for (table, layout) in parse_pairs(parsed_args.arg_flags.get("--layout"), args, "table=layout")?.iter() {
+                let layout = match BucketLayout::parse(layout) {
+                    Some(layout) => layout,
+                    None => {
+                        return Err(Error::CliError {
+                            message: format!("`{layout}` is not a valid layout. It has to be `single`, `flat-1` ~ `flat-4` or `nested-4` ~ `nested-7`."),
+                            span: None,
+                        });
+                    },
+                };
+
+                // `*=flat-2` applies to every table.
+                if table == "*" {
+                    default_bucket_layout = Some(layout);
+                }
+
+                else {
+                    bucket_layouts.insert(table.to_string(), layout);
+                }
+            }
+
+            let options = ToGitOptions {
+                incremental: parsed_args.get_flag(0).is_some(),
+                preserve_rowid: parsed_args.get_flag(1).is_some(),
+                bucket_layouts,
+                default_bucket_layout,
+                spill_threshold: parsed_args.arg_flags.get("--spill-threshold").map(|n| n.parse::<usize>().unwrap()),
+                jobs: parsed_args.arg_flags.get("--jobs").map(|n| n.parse::<usize>().unwrap()),
+                force: parsed_args.get_flag(2).is_some(),
+                tables,
+                on_progress: None,
+            };
+            let conn = Connection::open_with_flags(&input, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+            for (schema, path) in attachments.iter() {
+                conn.execute("ATTACH DATABASE ?1 AS ?2;", [path, schema])?;
+            }
+
+            match parsed_args.arg_flags.get("--git-ref") {
+                // `output` is a git repository, and the output is committed to the ref.
+                Some(git_ref) => {
+                    let mut storage = GitStorage::new(&output, git_ref)?;
+
+                    if let Some(prefix) = parsed_args.arg_flags.get("--git-prefix") {
+                        storage = storage.prefix(prefix);
+                    }
+
+                    to_git_into_storage(&conn, &storage, &options)?;
+                    println!("{}", storage.finish()?);
+                },
+                None => {
+                    to_git_from_connection(&conn, &output, &options)?;
+                },
+            }
         },
         Some("from-git" | "to-sql") => {
             let parsed_args = ArgParser::new()
+                .optional_flag(&["--bulk-load"])
+                .optional_flag(&["--integrity-check"])
+                .optional_flag(&["--force"])
+                .optional_flag(&["--backup"])
                 .arg_flag("--output", ArgType::String)
+                .optional_arg_flag("--attach", ArgType::String)
+                .optional_arg_flag("--jobs", ArgType::integer_between(Some(1), None))
+                .optional_arg_flag("--tables", ArgType::String)
+                .optional_arg_flag("--exclude-tables", ArgType::String)
                 .short_flag(&["--output"])
                 .args(ArgType::String, ArgCount::Exact(1))
-                .parse(&args, 2)?;
+                .parse(args, 2)?;
 
             let input = parsed_args.get_args_exact(1)?[0].clone();
             let output = parsed_args.arg_flags.get("--output").unwrap().to_string();
-            from_git(&output, &input)?;
+            let attachments = parse_pairs(parsed_args.arg_flags.get("--attach"), args, "name=path")?;
+            let tables = parse_table_filter(parsed_args.arg_flags.get("--tables"), parsed_args.arg_flags.get("--exclude-tables"));
+            let options = FromGitOptions {
+                bulk_load: parsed_args.get_flag(0).is_some(),
+                integrity_check: parsed_args.get_flag(1).is_some(),
+                jobs: parsed_args.arg_flags.get("--jobs").map(|n| n.parse::<usize>().unwrap()),
+                force: parsed_args.get_flag(2).is_some(),
+                backup: parsed_args.get_flag(3).is_some(),
+                tables,
+                on_progress: None,
+            };
+            from_git_with_options(&input, &output, &options)?;
+
+            for (schema, path) in attachments.iter() {
+                from_git_schema(&input, path, schema, &options)?;
+            }
+        },
+        Some("fsck") => {
+            let parsed_args = ArgParser::new()
+                .optional_flag(&["--fix"])
+                .args(ArgType::String, ArgCount::Exact(1))
+                .parse(args, 2)?;
+
+            let input = parsed_args.get_args_exact(1)?[0].clone();
+            let issues = fsck(&input, parsed_args.get_flag(0).is_some())?;
+
+            for issue in issues.iter() {
+                println!("{issue}");
+            }
+
+            let remaining = issues.iter().filter(|issue| !issue.fixed).count();
+
+            if remaining > 0 {
+                return Err(Error::CorruptedDataFile(format!(
+                    "found {remaining} problem(s) in `{input}`{}",
+                    if issues.iter().any(|issue| issue.fixable && !issue.fixed) { ". Run `stfg fsck --fix` to fix them." } else { "" },
+                )));
+            }
         },
         Some(invalid_command) => {
             let similar_command = get_closest_string(
@@ -62,6 +199,7 @@ fn run(args: &[String]) -> Result<(), Error> {
                     "to-sql",
                     "from-git",
                     "from-sql",
+                    "fsck",
                 ].iter().map(|s| s.to_string()).collect::<Vec<_>>(),
                 invalid_command,
             );
@@ -75,7 +213,7 @@ fn run(args: &[String]) -> Result<(), Error> {
                         String::new()
                     },
                 ),
-                span: Span::NthArg(0).render(&args, 1),
+                span: Span::NthArg(0).render(args, 1),
             });
         },
         _ => todo!(),
@@ -83,3 +221,43 @@ fn run(args: &[String]) -> Result<(), Error> {
 
     Ok(())
 }
+
+// `--attach aux=aux.db,logs=logs.db` -> `[("aux", "aux.db"), ("logs", "logs.db")]`
+fn parse_pairs(flag: Option<&String>, args: &[String], form: &str) -> Result<Vec<(String, String)>, Error> {
+    let Some(flag) = flag else { return Ok(vec![]); };
+    let mut result = vec![];
+
+    for pair in flag.split(',') {
+        match pair.split_once('=') {
+            Some((key, value)) if !key.is_empty() && !value.is_empty() => {
+                result.push((key.to_string(), value.to_string()));
+            },
+            _ => {
+                return Err(Error::CliError {
+                    message: format!("`{pair}` is invalid. It has to look like `{form}`."),
+                    span: match args.iter().position(|arg| arg == flag) {
+                        Some(index) => Span::Exact(index).render(args, 0),
+                        None => None,
+                    },
+                });
+            },
+        }
+    }
+
+    Ok(result)
+}
+
+// `--tables a,b --exclude-tables c` -> only `a` and `b`, but not `c`
+fn parse_table_filter(tables: Option<&String>, exclude_tables: Option<&String>) -> TableFilter {
+    let mut result = TableFilter::default();
+
+    for table in tables.map(|tables| tables.split(',').collect::<Vec<_>>()).unwrap_or_default() {
+        result = result.include(table);
+    }
+
+    for table in exclude_tables.map(|tables| tables.split(',').collect::<Vec<_>>()).unwrap_or_default() {
+        result = result.exclude(table);
+    }
+
+    result
+}
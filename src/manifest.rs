@@ -0,0 +1,462 @@
+use crate::error::Error;
+use crate::hash::{HASH_ALGORITHM, StableHasher, check_hash_algorithm};
+use crate::layout::BucketLayout;
+use crate::to_git::{FTS_CONFIG_FILE, FTS_SHADOW_FILE};
+use crate::value::Value;
+use crate::storage::{FsStorage, Storage};
+use ragit_fs::{FileError, join};
+use std::io::Write;
+
+#[cfg(test)]
+mod tests;
+
+/// Every output directory of stfg (and every `@{schema}` directory) has this file.
+/// It tells how the directory was created, so that a newer version of stfg can
+/// read an older output, and an older version refuses to read a newer output.
+pub(crate) const MANIFEST_FILE: &str = "manifest.toml";
+
+/// Bump it when the layout of the output directory changes in a way that an older
+/// version of stfg cannot read.
+///
+/// 1: every table has 512 buckets
+/// 2: each table has its own `BucketLayout`
+/// 3: the checksum of a table covers `TABLE_FILES`, and each file is hashed separately
+pub(crate) const FORMAT_VERSION: i64 = 3;
+
+/// Files in the directory of a table, other than the buckets. `config` and `shadow` are
+/// only for fts tables (see `FTS_CONFIG_FILE` and `FTS_SHADOW_FILE`).
+pub(crate) const TABLE_FILES: [&str; 5] = ["table.sql", "index.sql", "trigger.sql", FTS_CONFIG_FILE, FTS_SHADOW_FILE];
+
+/// How `value_to_string` writes values to the data files.
+pub(crate) const VALUE_ENCODING: &str = "stfg-v1";
+
+/// It's a small subset of TOML. Each top-level key is written as `key = value`, and
+/// each table is written as a `[[tables]]` entry. The values are integers and strings
+/// (see `toml_string`).
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Manifest {
+    pub format_version: i64,
+
+    // It's just for the users. stfg doesn't read this.
+    pub stfg_version: String,
+
+    pub hash_algorithm: String,
+    pub value_encoding: String,
+
+    // sorted by `dir`
+    pub tables: Vec<ManifestTable>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ManifestTable {
+    pub name: String,
+
+    // `Table::escaped_name`
+    pub dir: String,
+
+    pub rows: i64,
+
+    // Format version 1 doesn't have this, and it's always `BucketLayout::Flat(3)`.
+    pub layout: BucketLayout,
+
+    // see `table_checksum`
+    pub checksum: String,
+}
+
+impl Manifest {
+    pub fn new(mut tables: Vec<ManifestTable>) -> Self {
+        tables.sort_by(|a, b| a.dir.cmp(&b.dir));
+
+        Manifest {
+            format_version: FORMAT_VERSION,
+            stfg_version: env!("CARGO_PKG_VERSION").to_string(),
+            hash_algorithm: HASH_ALGORITHM.to_string(),
+            value_encoding: VALUE_ENCODING.to_string(),
+            tables,
+        }
+    }
+
+    pub fn to_toml(&self) -> String {
+        let mut lines = vec![
+            String::from("# Generated by stfg. Run `stfg fsck --fix` after editing the data files by hand."),
+            format!("format_version = {}", self.format_version),
+            format!("stfg_version = {}", toml_string(&self.stfg_version)),
+            format!("hash_algorithm = {}", toml_string(&self.hash_algorithm)),
+            format!("value_encoding = {}", toml_string(&self.value_encoding)),
+        ];
+
+        for table in self.tables.iter() {
+            lines.push(String::new());
+            lines.push(String::from("[[tables]]"));
+            lines.push(format!("name = {}", toml_string(&table.name)));
+            lines.push(format!("dir = {}", toml_string(&table.dir)));
+            lines.push(format!("rows = {}", table.rows));
+            lines.push(format!("layout = {}", toml_string(&table.layout.to_string())));
+            lines.push(format!("checksum = {}", toml_string(&table.checksum)));
+        }
+
+        lines.push(String::new());
+        lines.join("\n")
+    }
+
+    pub fn from_toml(s: &str) -> Result<Self, Error> {
+        let mut top_level = vec![];
+        let mut tables: Vec<Vec<(String, Value)>> = vec![];
+
+        for line in s.lines() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line == "[[tables]]" {
+                tables.push(vec![]);
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => match toml_value(value.trim()) {
+                    Some(value) => (key.trim().to_string(), value),
+                    None => {
+                        return Err(Error::CorruptedDataFile(format!("failed to parse `{MANIFEST_FILE}`: {line}")));
+                    },
+                },
+                None => {
+                    return Err(Error::CorruptedDataFile(format!("failed to parse `{MANIFEST_FILE}`: {line}")));
+                },
+            };
+
+            match tables.last_mut() {
+                Some(table) => {
+                    table.push((key, value));
+                },
+                None => {
+                    top_level.push((key, value));
+                },
+            }
+        }
+
+        let mut result = Manifest {
+            format_version: get_integer(&top_level, "format_version")?,
+            stfg_version: get_text(&top_level, "stfg_version")?,
+            hash_algorithm: get_text(&top_level, "hash_algorithm")?,
+            value_encoding: get_text(&top_level, "value_encoding")?,
+            tables: vec![],
+        };
+
+        for table in tables.iter() {
+            result.tables.push(ManifestTable {
+                name: get_text(table, "name")?,
+                dir: get_text(table, "dir")?,
+                rows: match get_integer(table, "rows")? {
+                    rows @ 0.. => rows,
+                    rows => {
+                        return Err(Error::CorruptedDataFile(format!("invalid rows in `{MANIFEST_FILE}`: {rows}")));
+                    },
+                },
+                layout: match table.iter().find(|(k, _)| k == "layout") {
+                    Some(_) => {
+                        let layout = get_text(table, "layout")?;
+                        BucketLayout::parse(&layout).ok_or_else(
+                            || Error::CorruptedDataFile(format!("invalid layout in `{MANIFEST_FILE}`: {layout:?}"))
+                        )?
+                    },
+                    None => BucketLayout::default(),
+                },
+                checksum: get_text(table, "checksum")?,
+            });
+        }
+
+        Ok(result)
+    }
+
+    /// It makes sure that this version of stfg can read the output.
+    pub fn check_compatibility(&self) -> Result<(), Error> {
+        if self.format_version > FORMAT_VERSION {
+            return Err(Error::UnsupportedFormatVersion {
+                supported: FORMAT_VERSION,
+                found: self.format_version,
+            });
+        }
+
+        if self.hash_algorithm != HASH_ALGORITHM {
+            return Err(Error::HashAlgorithmMismatch {
+                expected: HASH_ALGORITHM.to_string(),
+                found: self.hash_algorithm.to_string(),
+            });
+        }
+
+        if self.value_encoding != VALUE_ENCODING {
+            return Err(Error::UnsupportedValueEncoding(self.value_encoding.to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Old versions of stfg don't create the manifest.
+pub(crate) fn read_manifest(storage: &dyn Storage, output_path: &str) -> Result<Option<Manifest>, Error> {
+    let path = join(output_path, MANIFEST_FILE)?;
+
+    if !storage.is_file(&path) {
+        return Ok(None);
+    }
+
+    Ok(Some(Manifest::from_toml(&storage.read_string(&path)?)?))
+}
+
+/// It makes sure that stfg can read (and update) `output_path`. If it has no manifest,
+/// it falls back to the `hash-algorithm` file, which was written by older versions.
+pub(crate) fn check_output_dir(output_path: &str) -> Result<(), Error> {
+    let storage = FsStorage::default();
+
+    match read_manifest(&storage, output_path)? {
+        Some(manifest) => manifest.check_compatibility(),
+        None => check_hash_algorithm(&storage, output_path),
+    }
+}
+
+/// A checksum of the files in `data_dir`: their paths (relative to `data_dir`) and contents,
+/// the buckets in order, then `TABLE_FILES` (since format version 3). It's not cryptographic.
+/// It's for catching bad merges and partial checkouts.
+pub(crate) fn table_checksum(storage: &dyn Storage, data_dir: &str, layout: BucketLayout, format_version: i64) -> Result<String, Error> {
+    let mut checksum = TableChecksum::with_format_version(layout, format_version);
+
+    for (bucket, file) in layout.list_buckets(storage, data_dir)?.iter() {
+        if format_version >= 3 {
+            // A bucket can be huge, and it doesn't have to be in memory.
+            let mut writer = ChecksumWriter::new(std::io::sink());
+            std::io::copy(&mut storage.open(file)?, &mut writer).map_err(|e| FileError::from_std(e, file))?;
+            checksum.add_bucket_checksum(*bucket, writer.finish().1);
+        }
+
+        else {
+            checksum.add_file(&layout.bucket_path(*bucket), &storage.read(file)?);
+        }
+    }
+
+    if format_version >= 3 {
+        for name in TABLE_FILES.iter() {
+            let path = join(data_dir, name)?;
+
+            if storage.is_file(&path) {
+                checksum.add_file(name, &storage.read(&path)?);
+            }
+        }
+    }
+
+    Ok(checksum.finish())
+}
+
+/// `table_checksum` of the files that are being written, so that `to_git` doesn't
+/// have to read them back. The buckets have to be added in order, and then `TABLE_FILES`.
+///
+/// Since format version 3, a file is hashed on its own, and the checksum of the table
+/// only has the path, the hash and the length of each file. So a bucket can be hashed
+/// while it's written (see `ChecksumWriter`), before its length is known.
+#[derive(Clone)]
+pub(crate) struct TableChecksum {
+    layout: BucketLayout,
+    format_version: i64,
+    hasher: StableHasher,
+}
+
+impl TableChecksum {
+    pub fn new(layout: BucketLayout) -> Self {
+        TableChecksum::with_format_version(layout, FORMAT_VERSION)
+    }
+
+    pub fn with_format_version(layout: BucketLayout, format_version: i64) -> Self {
+        TableChecksum {
+            layout,
+            format_version,
+            hasher: StableHasher::new(),
+        }
+    }
+
+    pub fn add_bucket_checksum(&mut self, bucket: u64, checksum: FileChecksum) {
+        self.add_file_checksum(&self.layout.bucket_path(bucket), checksum);
+    }
+
+    pub fn add_file(&mut self, path: &str, content: &[u8]) {
+        if self.format_version >= 3 {
+            let mut checksum = FileChecksum::default();
+            checksum.update(content);
+            self.add_file_checksum(path, checksum);
+        }
+
+        else {
+            self.hasher.write(path.as_bytes());
+            self.hasher.write(&(content.len() as u64).to_le_bytes());
+            self.hasher.write(content);
+        }
+    }
+
+    fn add_file_checksum(&mut self, path: &str, checksum: FileChecksum) {
+        self.hasher.write(path.as_bytes());
+        self.hasher.write(&checksum.hasher.finish().to_le_bytes());
+        self.hasher.write(&checksum.len.to_le_bytes());
+    }
+
+    pub fn finish(&self) -> String {
+        format!("{:016x}", self.hasher.finish())
+    }
+}
+
+/// A hash and the length of a file (see `TableChecksum`).
+#[derive(Clone, Copy)]
+pub(crate) struct FileChecksum {
+    hasher: StableHasher,
+    len: u64,
+}
+
+impl Default for FileChecksum {
+    fn default() -> Self {
+        FileChecksum {
+            hasher: StableHasher::new(),
+            len: 0,
+        }
+    }
+}
+
+impl FileChecksum {
+    fn update(&mut self, content: &[u8]) {
+        self.hasher.write(content);
+        self.len += content.len() as u64;
+    }
+}
+
+/// It passes the contents of a file to `inner`, and hashes them on the way.
+pub(crate) struct ChecksumWriter<W: Write> {
+    inner: W,
+    checksum: FileChecksum,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ChecksumWriter {
+            inner,
+            checksum: FileChecksum::default(),
+        }
+    }
+
+    pub fn finish(self) -> (W, FileChecksum) {
+        (self.inner, self.checksum)
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.checksum.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// A TOML basic string. `value_to_string` is not used, because TOML doesn't have `\0`
+// and doesn't allow the other control characters in a string.
+fn toml_string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+
+    for ch in s.chars() {
+        match ch {
+            '"' | '\\' => {
+                result.push('\\');
+                result.push(ch);
+            },
+            '\n' => {
+                result.push_str("\\n");
+            },
+            '\r' => {
+                result.push_str("\\r");
+            },
+            '\t' => {
+                result.push_str("\\t");
+            },
+            '\0'..='\x1f' | '\x7f' => {
+                result.push_str(&format!("\\u{:04X}", ch as u32));
+            },
+            _ => {
+                result.push(ch);
+            },
+        }
+    }
+
+    result.push('"');
+    result
+}
+
+// An integer, or a string that `toml_string` (or an older version of stfg,
+// which writes `\0`) has written.
+fn toml_value(s: &str) -> Option<Value> {
+    let Some(s) = s.strip_prefix('"') else {
+        return s.parse::<i64>().ok().map(Value::Integer);
+    };
+    let mut result = String::new();
+    let mut chars = s.chars();
+
+    loop {
+        match chars.next()? {
+            '"' => {
+                break;
+            },
+            '\\' => {
+                let ch = match chars.next()? {
+                    'b' => '\x08',
+                    't' => '\t',
+                    'n' => '\n',
+                    'f' => '\x0c',
+                    'r' => '\r',
+                    '0' => '\0',
+                    '"' => '"',
+                    '\\' => '\\',
+                    c @ ('u' | 'U') => {
+                        let len = if c == 'u' { 4 } else { 8 };
+                        let hex = chars.by_ref().take(len).collect::<String>();
+
+                        if hex.len() != len {
+                            return None;
+                        }
+
+                        char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                    },
+                    _ => {
+                        return None;
+                    },
+                };
+                result.push(ch);
+            },
+            ch => {
+                result.push(ch);
+            },
+        }
+    }
+
+    if chars.next().is_some() {
+        return None;
+    }
+
+    Some(Value::Text(result))
+}
+
+fn get_integer(entries: &[(String, Value)], key: &str) -> Result<i64, Error> {
+    match entries.iter().find(|(k, _)| k == key) {
+        Some((_, Value::Integer(n))) => Ok(*n),
+        Some(_) => Err(Error::CorruptedDataFile(format!("`{key}` of `{MANIFEST_FILE}` has to be an integer"))),
+        None => Err(Error::CorruptedDataFile(format!("`{MANIFEST_FILE}` doesn't have `{key}`"))),
+    }
+}
+
+fn get_text(entries: &[(String, Value)], key: &str) -> Result<String, Error> {
+    match entries.iter().find(|(k, _)| k == key) {
+        Some((_, Value::Text(s))) => Ok(s.to_string()),
+        Some(_) => Err(Error::CorruptedDataFile(format!("`{key}` of `{MANIFEST_FILE}` has to be a string"))),
+        None => Err(Error::CorruptedDataFile(format!("`{MANIFEST_FILE}` doesn't have `{key}`"))),
+    }
+}
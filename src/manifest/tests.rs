@@ -0,0 +1,125 @@
+use super::{FORMAT_VERSION, MANIFEST_FILE, Manifest, ManifestTable, table_checksum};
+use crate::layout::BucketLayout;
+use crate::error::Error;
+use crate::from_git::{FromGitOptions, from_git_into_connection};
+use crate::fsck::fsck;
+use crate::storage::FsStorage;
+use crate::to_git::{ToGitOptions, to_git_from_connection};
+use crate::util::TempDir;
+use rusqlite::Connection;
+
+#[test]
+fn manifest_roundtrip() {
+    let manifest = Manifest::new(vec![
+        ManifestTable {
+            name: String::from("users"),
+            dir: String::from("users"),
+            rows: 3,
+            layout: BucketLayout::Single,
+            checksum: String::from("0123456789abcdef"),
+        },
+        ManifestTable {
+            name: String::from("a \"b\"\n\0\x01\x7f\\"),
+            dir: String::from("a$20$$22$b$22$$0a$"),
+            rows: 0,
+            layout: BucketLayout::Nested(6),
+            checksum: String::from("d1fba762150c532c"),
+        },
+    ]);
+    let toml = manifest.to_toml();
+    let parsed = Manifest::from_toml(&toml).unwrap();
+    assert_eq!(manifest, parsed);
+
+    // TOML doesn't have `\0`, and doesn't allow control characters in a string.
+    assert!(toml.contains(r#"name = "a \"b\"\n\u0000\u0001\u007F\\""#));
+    assert!(!toml.chars().any(|ch| ch.is_control() && ch != '\n'));
+    assert!(parsed.check_compatibility().is_ok());
+
+    let mut newer = manifest.clone();
+    newer.format_version = FORMAT_VERSION + 1;
+    assert!(matches!(
+        Manifest::from_toml(&newer.to_toml()).unwrap().check_compatibility(),
+        Err(Error::UnsupportedFormatVersion { .. }),
+    ));
+}
+
+#[test]
+fn manifest_version_1() {
+    let manifest = Manifest::from_toml("
+format_version = 1
+stfg_version = \"0.1.0\"
+hash_algorithm = \"siphash-1-3-v1\"
+value_encoding = \"stfg-v1\"
+bucket_count = 512
+
+[[tables]]
+name = \"t\"
+dir = \"t\"
+rows = 1
+checksum = \"0123456789abcdef\"
+").unwrap();
+    assert!(manifest.check_compatibility().is_ok());
+    assert_eq!(manifest.tables[0].layout, BucketLayout::Flat(3));
+}
+
+#[test]
+fn invalid_rows() {
+    let manifest = "format_version = 3
+stfg_version = \"0.1.0\"
+hash_algorithm = \"siphash-1-3-v1\"
+value_encoding = \"stfg-v1\"
+
+[[tables]]
+name = \"t\"
+dir = \"t\"
+rows = -1
+checksum = \"0123456789abcdef\"
+";
+    assert!(matches!(Manifest::from_toml(manifest), Err(Error::CorruptedDataFile(_))));
+
+    // Older versions write `\0`, which is not TOML.
+    let manifest = Manifest::from_toml(&manifest.replace("-1", "1").replace("name = \"t\"", "name = \"t\\0\"")).unwrap();
+    assert_eq!(manifest.tables[0].name, "t\0");
+}
+
+#[test]
+fn validate_table() {
+    let tmp = TempDir::new("validate-table");
+    let output = tmp.join("output").to_str().unwrap().to_string();
+    let manifest_path = tmp.join("output").join(MANIFEST_FILE);
+
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch("
+        CREATE TABLE t (a INTEGER PRIMARY KEY, b TEXT);
+        CREATE INDEX t_b ON t (b);
+        INSERT INTO t VALUES (1, 'x'), (2, 'y');
+    ").unwrap();
+    to_git_from_connection(&conn, &output, &ToGitOptions::new()).unwrap();
+    let manifest = std::fs::read_to_string(&manifest_path).unwrap();
+    let restore = || from_git_into_connection(&output, &mut Connection::open_in_memory().unwrap(), "main", &FromGitOptions::new());
+    restore().unwrap();
+
+    // The checksum covers the schema files.
+    let index_sql = std::fs::read_to_string(tmp.join("output/t/index.sql")).unwrap();
+    std::fs::write(tmp.join("output/t/index.sql"), "").unwrap();
+    assert!(matches!(restore(), Err(Error::ChecksumMismatch { .. })));
+    std::fs::write(tmp.join("output/t/index.sql"), &index_sql).unwrap();
+
+    // It doesn't cover the number of rows.
+    std::fs::write(&manifest_path, manifest.replace("rows = 2", "rows = 3")).unwrap();
+    assert!(matches!(restore(), Err(Error::RowCountMismatch { expected: 3, found: 2, .. })));
+
+    // In format version 2, the checksum only covers the buckets.
+    let mut old = Manifest::from_toml(&manifest).unwrap();
+    old.format_version = 2;
+    old.tables[0].checksum = table_checksum(&FsStorage::default(), tmp.join("output/t").to_str().unwrap(), old.tables[0].layout, 2).unwrap();
+    assert_ne!(old.tables[0].checksum, Manifest::from_toml(&manifest).unwrap().tables[0].checksum);
+    std::fs::write(&manifest_path, old.to_toml()).unwrap();
+    restore().unwrap();
+    assert!(fsck(&output, false).unwrap().is_empty());
+
+    // `fsck --fix` rewrites an outdated manifest in the current format.
+    std::fs::write(&manifest_path, old.to_toml().replace("rows = 2", "rows = 3")).unwrap();
+    assert_eq!(fsck(&output, true).unwrap().len(), 1);
+    assert_eq!(std::fs::read_to_string(&manifest_path).unwrap(), manifest);
+}
@@ -0,0 +1,54 @@
+use crate::report::TableReport;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+
+/// Which tables `to_git` dumps and `from_git` restores, by the name of the table. It
+/// applies to every schema. Indexes and triggers go with their table. Views are always
+/// dumped and restored, even if they refer to a table that is filtered out.
+#[derive(Clone, Debug, Default)]
+pub struct TableFilter {
+    /// If it's set, only these tables are included.
+    pub include: Option<HashSet<String>>,
+
+    /// These tables are excluded, even if they're in `include`.
+    pub exclude: HashSet<String>,
+}
+
+impl TableFilter {
+    pub fn include(mut self, table: &str) -> Self {
+        self.include.get_or_insert_with(HashSet::new).insert(table.to_string());
+        self
+    }
+
+    pub fn exclude(mut self, table: &str) -> Self {
+        self.exclude.insert(table.to_string());
+        self
+    }
+
+    pub fn matches(&self, table: &str) -> bool {
+        !self.exclude.contains(table) && self.include.as_ref().map(|include| include.contains(table)).unwrap_or(true)
+    }
+}
+
+/// It's called every time a table is dumped (or restored). If there are multiple
+/// threads, it's called by the thread that has finished the table, so the tables
+/// may be reported in any order.
+#[derive(Clone)]
+pub struct ProgressCallback(Arc<dyn Fn(&TableReport) + Send + Sync>);
+
+impl ProgressCallback {
+    pub fn new<F: Fn(&TableReport) + Send + Sync + 'static>(f: F) -> Self {
+        ProgressCallback(Arc::new(f))
+    }
+
+    pub(crate) fn call(&self, report: &TableReport) {
+        (self.0)(report)
+    }
+}
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ProgressCallback")
+    }
+}
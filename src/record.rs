@@ -2,36 +2,62 @@ use crate::error::Error;
 use crate::util::parse_string_literal;
 use crate::value::{
     Value,
+    string_literal,
     value_from_string,
     value_to_string,
 };
+use crate::hash::StableHasher;
+use crate::storage::Storage;
 use ragit_fs::{
+    FileError,
     WriteMode,
-    read_string,
     write_string,
 };
-use std::collections::hash_map::DefaultHasher;
-use std::hash::Hasher;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Lines, Read, Write};
 
-#[derive(Clone)]
+#[cfg(test)]
+mod tests;
+
+/// A row of a table, as it's written in a data file.
+#[derive(Clone, Debug)]
 pub struct Record {
     // Read the comments of `RecordId`.
     pub id: RecordId,
 
     // The order matters a lot!!
     pub fields: Vec<(String, Value)>,
+
+    // It's only set when the user wants to preserve rowids (and the table has a rowid
+    // that is not stored in any column). It's written as a reserved field, `@rowid=N`.
+    pub rowid: Option<i64>,
+}
+
+impl Record {
+    /// Records in a bucket are sorted by this key. Records with the same id (e.g. identical
+    /// rows of a table without a primary key) are sorted by rowid if it's preserved, so that
+    /// the output doesn't depend on the order sqlite returns the rows.
+    pub(crate) fn sort_key(&self) -> (RecordId, Option<i64>) {
+        (self.id, self.rowid)
+    }
+
+    pub fn get(&self, column: &str) -> Option<&Value> {
+        self.fields.iter().find(|(name, _)| name == column).map(|(_, value)| value)
+    }
 }
 
 /// A table is splitted into multiple files based on `RecordId`. In order to do that,
 /// 1. it has to be a hash value so that the records are evenly distributed.
 /// 2. when a field of a record is updated, its id MUST NOT change (so that `git diff` can easily spot the difference).
 /// 3. it has to be deterministic and only depends on values, not internal data structure like rowid (so that git can easily track the history).
+/// 4. it must not change across rust versions or platforms. That's why it uses `StableHasher` (see `crate::hash::HASH_ALGORITHM`).
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct RecordId(pub(crate) u64);
 
 impl RecordId {
     pub fn hash(data: &[Value]) -> RecordId {
-        let mut hasher = DefaultHasher::new();
+        let mut hasher = StableHasher::new();
 
         for value in data.iter() {
             match value {
@@ -52,41 +78,185 @@ impl RecordId {
                 },
                 Value::Blob(v) => {
                     hasher.write(b"4");
-                    hasher.write(&v);
+                    hasher.write(v);
                 },
             }
         }
 
         RecordId(hasher.finish())
     }
+}
+
+/// How it's written in a data file: 16 hex digits.
+impl fmt::Display for RecordId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
 
-    /// stfg splits a table into 512 files.
-    pub fn prefix(&self) -> u64 {
-        self.0 >> 55
+pub(crate) fn read_records(storage: &dyn Storage, path: &str) -> Result<Vec<Record>, Error> {
+    match parse_records(&storage.read_string(path)?) {
+        Ok(records) => Ok(records.into_iter().map(|(_, record)| record).collect()),
+        Err((line, Error::CorruptedDataFile(message))) => Err(Error::CorruptedDataFile(format!("{path}:{line}: {message}"))),
+        Err((_, e)) => Err(e),
     }
 }
 
-pub(crate) fn read_records(path: &str) -> Result<Vec<Record>, Error> {
-    let r = read_string(path)?;
+/// Each record comes with the line number (1-based) of its id. If it fails,
+/// it returns the line number where it failed, so that the caller can tell
+/// the user where to look.
+pub(crate) fn parse_records(s: &str) -> Result<Vec<(usize, Record)>, (usize, Error)> {
     let mut result = vec![];
+    let mut parser = RecordParser::default();
 
-    let mut id = None;
-    let mut fields = vec![];
+    for (index, line) in s.lines().enumerate() {
+        let line_no = index + 1;
+
+        if let Some(record) = parser.feed(line_no, line).map_err(|e| (line_no, e))? {
+            result.push(record);
+        }
+    }
+
+    if let Some(record) = parser.finish() {
+        result.push(record);
+    }
+
+    Ok(result)
+}
+
+/// Unlike `read_records`, it reads a file one record at a time, so that the
+/// memory usage doesn't depend on the size of the file.
+pub(crate) struct RecordReader {
+    path: String,
+    lines: Lines<BufReader<Box<dyn Read + Send>>>,
+    line_no: usize,
+    parser: RecordParser,
+}
+
+impl RecordReader {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let file = File::open(path).map_err(|e| FileError::from_std(e, path))?;
+        Ok(RecordReader::new(path, Box::new(file)))
+    }
+
+    /// `path` is only for error messages.
+    pub fn new(path: &str, reader: Box<dyn Read + Send>) -> Self {
+        RecordReader {
+            path: path.to_string(),
+            lines: BufReader::new(reader).lines(),
+            line_no: 0,
+            parser: RecordParser::default(),
+        }
+    }
+
+    pub fn next_record(&mut self) -> Result<Option<Record>, Error> {
+        loop {
+            match self.lines.next() {
+                Some(Ok(line)) => {
+                    self.line_no += 1;
+
+                    match self.parser.feed(self.line_no, &line) {
+                        Ok(Some((_, record))) => {
+                            return Ok(Some(record));
+                        },
+                        Ok(None) => {},
+                        Err(Error::CorruptedDataFile(message)) => {
+                            return Err(Error::CorruptedDataFile(format!("{}:{}: {message}", self.path, self.line_no)));
+                        },
+                        Err(e) => {
+                            return Err(e);
+                        },
+                    }
+                },
+                Some(Err(e)) => {
+                    return Err(FileError::from_std(e, &self.path).into());
+                },
+                None => {
+                    return Ok(self.parser.finish().map(|(_, record)| record));
+                },
+            }
+        }
+    }
+}
+
+/// It writes records one at a time. The result is exactly the same as `records_to_string`.
+/// `path` is only for error messages.
+pub(crate) struct RecordWriter<W: Write = BufWriter<File>> {
+    path: String,
+    writer: W,
+    is_empty: bool,
+}
+
+impl RecordWriter {
+    pub fn create(path: &str) -> Result<Self, Error> {
+        let file = File::create(path).map_err(|e| FileError::from_std(e, path))?;
+        Ok(RecordWriter::new(path, BufWriter::new(file)))
+    }
+}
 
-    for line in r.lines() {
+impl<W: Write> RecordWriter<W> {
+    pub fn new(path: &str, writer: W) -> Self {
+        RecordWriter {
+            path: path.to_string(),
+            writer,
+            is_empty: true,
+        }
+    }
+
+    pub fn write(&mut self, record: &Record) -> Result<(), Error> {
+        // records are separated by an empty line
+        if !self.is_empty {
+            self.writer.write_all(b"\n").map_err(|e| FileError::from_std(e, &self.path))?;
+        }
+
+        self.writer.write_all(record_to_string(record).as_bytes()).map_err(|e| FileError::from_std(e, &self.path))?;
+        self.is_empty = false;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.writer.flush().map_err(|e| FileError::from_std(e, &self.path))?;
+        Ok(self.writer)
+    }
+}
+
+// A record is an id, an optional rowid and the fields, followed by an empty line.
+// It reads a file line by line, and returns a record when it's complete.
+#[derive(Default)]
+struct RecordParser {
+    id: Option<RecordId>,
+    id_line: usize,
+    fields: Vec<(String, Value)>,
+    rowid: Option<i64>,
+}
+
+impl RecordParser {
+    fn feed(&mut self, line_no: usize, line: &str) -> Result<Option<(usize, Record)>, Error> {
         let bytes = line.as_bytes();
 
-        match bytes.get(0) {
+        match bytes.first() {
             Some(b'"') => {
-                fields.push(parse_line(bytes)?);
+                self.fields.push(parse_line(bytes)?);
+            },
+            Some(b'@') => match (self.id, self.rowid) {
+                (None, _) => {
+                    return Err(Error::CorruptedDataFile(format!("a reserved field without an id: {line}")));
+                },
+                (Some(_), Some(_)) => {
+                    return Err(Error::CorruptedDataFile(String::from("rowid appears twice")));
+                },
+                (Some(_), None) => {
+                    self.rowid = Some(parse_rowid(line)?);
+                },
             },
             Some(b'0'..=b'9' | b'a'..=b'f') => match u64::from_str_radix(line, 16) {
-                Ok(n) => match id {
+                Ok(n) => match self.id {
                     Some(_) => {
                         return Err(Error::CorruptedDataFile(String::from("id appears twice")));
                     },
                     None => {
-                        id = Some(RecordId(n));
+                        self.id = Some(RecordId(n));
+                        self.id_line = line_no;
                     },
                 },
                 Err(_) => {
@@ -94,7 +264,7 @@ pub(crate) fn read_records(path: &str) -> Result<Vec<Record>, Error> {
                 },
             },
             Some(b) => {
-                if let Some(id) = id {
+                if let Some(id) = self.id {
                     return Err(Error::CorruptedDataFile(format!(
                         "expected a field name, got {} (at id {:016x})",
                         String::from_utf8_lossy(&[*b]),
@@ -103,59 +273,64 @@ pub(crate) fn read_records(path: &str) -> Result<Vec<Record>, Error> {
                 }
 
                 else {
-                    return Err(Error::CorruptedDataFile(format!("expected an id, got {}", String::from_utf8_lossy(&[*b]))))
+                    return Err(Error::CorruptedDataFile(format!("expected an id, got {}", String::from_utf8_lossy(&[*b]))));
                 }
             },
-            None => match id {
-                Some(id_) => {
-                    result.push(Record {
-                        id: id_,
-                        fields,
-                    });
-
-                    id = None;
-                    fields = vec![];
+            None => match self.finish() {
+                Some(record) => {
+                    return Ok(Some(record));
                 },
                 None => {
                     return Err(Error::CorruptedDataFile(String::from("a record without an id")));
                 },
             },
         }
+
+        Ok(None)
     }
 
-    if let Some(id) = id {
-        result.push(Record {
+    // A file may not end with an empty line.
+    fn finish(&mut self) -> Option<(usize, Record)> {
+        let id = self.id.take()?;
+
+        Some((self.id_line, Record {
             id,
-            fields,
-        });
+            fields: std::mem::take(&mut self.fields),
+            rowid: self.rowid.take(),
+        }))
     }
-
-    Ok(result)
 }
 
 pub(crate) fn write_records(path: &str, records: &[Record]) -> Result<(), Error> {
-    let mut lines = vec![];
-
-    for record in records.iter() {
-        lines.push(format!("{:016x}", record.id.0));
-
-        for (field, value) in record.fields.iter() {
-            // `field` can have an arbitrary character, so we have to use `Debug` format instead of `Display`.
-            lines.push(format!("{field:?}={}", value_to_string(value)));
-        }
-
-        lines.push(String::new());
-    }
-
     write_string(
         path,
-        &lines.join("\n"),
+        &records_to_string(records),
         WriteMode::CreateOrTruncate,
     )?;
     Ok(())
 }
 
-fn parse_line(s: &[u8]) -> Result<(String, Value), Error> {
+pub(crate) fn records_to_string(records: &[Record]) -> String {
+    records.iter().map(record_to_string).collect::<Vec<_>>().join("\n")
+}
+
+fn record_to_string(record: &Record) -> String {
+    let mut lines = vec![];
+    lines.push(format!("{}\n", record.id));
+
+    if let Some(rowid) = record.rowid {
+        lines.push(format!("@rowid={rowid}\n"));
+    }
+
+    for (field, value) in record.fields.iter() {
+        // `field` can have an arbitrary character, so we have to use `Debug` format instead of `Display`.
+        lines.push(format!("{}={}\n", string_literal(field), value_to_string(value)));
+    }
+
+    lines.concat()
+}
+
+pub(crate) fn parse_line(s: &[u8]) -> Result<(String, Value), Error> {
     let (field_name, mut cursor) = match parse_string_literal(s) {
         Some((s, i)) => (s, i + 1),
         None => {
@@ -178,7 +353,7 @@ fn parse_line(s: &[u8]) -> Result<(String, Value), Error> {
     let value_s = match String::from_utf8(s[cursor..].to_vec()) {
         Ok(s) => s,
         Err(_) => {
-            return Err(Error::CorruptedDataFile(format!("corrupted value")));
+            return Err(Error::CorruptedDataFile(String::from("corrupted value")));
         },
     };
 
@@ -191,3 +366,11 @@ fn parse_line(s: &[u8]) -> Result<(String, Value), Error> {
 
     Ok((field_name, value))
 }
+
+fn parse_rowid(line: &str) -> Result<i64, Error> {
+    match line.strip_prefix("@rowid=").map(|n| n.parse::<i64>()) {
+        Some(Ok(n)) => Ok(n),
+        Some(Err(_)) => Err(Error::CorruptedDataFile(format!("failed to parse rowid: {line}"))),
+        None => Err(Error::CorruptedDataFile(format!("unknown reserved field: {line}"))),
+    }
+}
@@ -0,0 +1,43 @@
+use super::{RecordId, Value};
+use crate::hash::StableHasher;
+
+// If this test fails, you've changed the hash algorithm. Every record of every
+// existing output would move to a different bucket file. If that's intended, bump
+// `crate::hash::HASH_ALGORITHM` and update the values here.
+#[test]
+fn record_id_is_stable() {
+    for (data, id) in vec![
+        (vec![], 0xd1fba762150c532c),
+        (vec![Value::Null], 0x49bc192c478bfc2e),
+        (vec![Value::Integer(0)], 0xf3042b21d6cf6441),
+        (vec![Value::Integer(1)], 0x95848ae8b5140c4a),
+        (vec![Value::Integer(-1)], 0xee6dd12a3e28d524),
+        (vec![Value::Integer(i64::MAX)], 0x86f6b66cf58e746a),
+        (vec![Value::Real(1.5)], 0x2f9d2734164dd3fc),
+        (vec![Value::Text(String::new())], 0xa940f47c5500b556),
+        (vec![Value::Text(String::from("Hello, world!"))], 0x676df817cb1d30c3),
+        (vec![Value::Text(String::from("가나다"))], 0x7c1ced68cf4ff6ba),
+        (vec![Value::Text(String::from("This string is longer than sixteen bytes."))], 0x895306531dc5226c),
+        (vec![Value::Blob(b"Hello, world!".to_vec())], 0xce97c44c454280b1),
+        (vec![Value::Integer(1), Value::Text(String::from("a")), Value::Null], 0x111251aa982b0d62),
+    ] {
+        assert_eq!(RecordId::hash(&data), RecordId(id));
+    }
+}
+
+#[test]
+fn hasher_is_streaming() {
+    let data = b"The quick brown fox jumps over the lazy dog.";
+    let mut one_shot = StableHasher::new();
+    one_shot.write(data);
+
+    for chunk_size in 1..data.len() {
+        let mut chunked = StableHasher::new();
+
+        for chunk in data.chunks(chunk_size) {
+            chunked.write(chunk);
+        }
+
+        assert_eq!(one_shot.finish(), chunked.finish());
+    }
+}
@@ -0,0 +1,414 @@
+use crate::error::Error;
+use crate::export::TableReader;
+use crate::record::Record;
+use crate::value::Value;
+use serde::de::{self, DeserializeOwned, IntoDeserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{self, Impossible, Serialize};
+use std::fmt;
+
+#[cfg(test)]
+mod tests;
+
+/// It deserializes the fields of a record, by the name of the column. A struct
+/// (or a map) takes the fields by name, and a tuple takes them in order.
+///
+/// sqlite doesn't have booleans, so `bool` is read from `0` and `1`. An enum without
+/// data is read from its name. A blob can be read as `Vec<u8>`.
+pub fn from_record<T: DeserializeOwned>(record: &Record) -> Result<T, Error> {
+    Ok(T::deserialize(RecordDeserializer { fields: &record.fields })?)
+}
+
+/// It serializes a struct (or a map) to a record of `table`. Each field has to be a
+/// column of the table, and every column has to be there. The fields are sorted in
+/// the order of the columns and the id is calculated, so that the record is exactly
+/// what `to_git` would write.
+pub fn to_record<T: Serialize>(value: &T, table: &TableReader) -> Result<Record, Error> {
+    let table = table.schema_table();
+    let mut fields = value.serialize(RecordSerializer)?;
+    let mut result = Vec::with_capacity(table.columns.len());
+
+    for column in table.columns.iter() {
+        match fields.iter().position(|(name, _)| name == column) {
+            Some(index) => {
+                result.push(fields.swap_remove(index));
+            },
+            None => {
+                return Err(Error::SerdeError(format!("column `{column}` of table `{}` is missing", table.name)));
+            },
+        }
+    }
+
+    if let Some((name, _)) = fields.first() {
+        return Err(Error::SerdeError(format!("table `{}` doesn't have column `{name}`", table.name)));
+    }
+
+    Ok(Record {
+        id: table.record_id(&result)?,
+        fields: result,
+        rowid: None,
+    })
+}
+
+// serde needs an error type that implements `std::error::Error`.
+#[derive(Debug)]
+struct SerdeError(String);
+
+impl fmt::Display for SerdeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SerdeError {}
+
+impl de::Error for SerdeError {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        SerdeError(message.to_string())
+    }
+}
+
+impl ser::Error for SerdeError {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        SerdeError(message.to_string())
+    }
+}
+
+impl From<SerdeError> for Error {
+    fn from(e: SerdeError) -> Error {
+        Error::SerdeError(e.0)
+    }
+}
+
+struct RecordDeserializer<'a> {
+    fields: &'a [(String, Value)],
+}
+
+impl<'de> de::Deserializer<'de> for RecordDeserializer<'_> {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_map(FieldsAccess { fields: self.fields.iter(), value: None })
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_seq(ValuesAccess { values: self.fields.iter().map(|(_, value)| value) })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, _len: usize, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct map struct enum identifier ignored_any
+    }
+}
+
+struct FieldsAccess<'a, I: Iterator<Item = &'a (String, Value)>> {
+    fields: I,
+
+    // the value of the key that `next_key_seed` has returned
+    value: Option<&'a Value>,
+}
+
+impl<'de, 'a, I: Iterator<Item = &'a (String, Value)>> MapAccess<'de> for FieldsAccess<'a, I> {
+    type Error = SerdeError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, SerdeError> {
+        match self.fields.next() {
+            Some((name, value)) => {
+                self.value = Some(value);
+                seed.deserialize(name.as_str().into_deserializer()).map(Some)
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, SerdeError> {
+        match self.value.take() {
+            Some(value) => seed.deserialize(ValueDeserializer(value)),
+            None => Err(de::Error::custom("a value without a key")),
+        }
+    }
+}
+
+struct ValuesAccess<'a, I: Iterator<Item = &'a Value>> {
+    values: I,
+}
+
+impl<'de, 'a, I: Iterator<Item = &'a Value>> SeqAccess<'de> for ValuesAccess<'a, I> {
+    type Error = SerdeError;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, SerdeError> {
+        match self.values.next() {
+            Some(value) => seed.deserialize(ValueDeserializer(value)).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+struct ValueDeserializer<'a>(&'a Value);
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer<'_> {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Value::Null => visitor.visit_unit(),
+            Value::Integer(n) => visitor.visit_i64(*n),
+            Value::Real(n) => visitor.visit_f64(*n),
+            Value::Text(s) => visitor.visit_str(s),
+            Value::Blob(v) => visitor.visit_bytes(v),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Value::Integer(0) => visitor.visit_bool(false),
+            Value::Integer(1) => visitor.visit_bool(true),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Value::Blob(v) => visitor.visit_seq(de::value::SeqDeserializer::new(v.iter().copied())),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Value::Text(s) => visitor.visit_enum(s.as_str().into_deserializer()),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+// It only serializes structs and maps, whose values are `Value`s.
+struct RecordSerializer;
+
+impl ser::Serializer for RecordSerializer {
+    type Ok = Vec<(String, Value)>;
+    type Error = SerdeError;
+    type SerializeSeq = Impossible<Self::Ok, SerdeError>;
+    type SerializeTuple = Impossible<Self::Ok, SerdeError>;
+    type SerializeTupleStruct = Impossible<Self::Ok, SerdeError>;
+    type SerializeTupleVariant = Impossible<Self::Ok, SerdeError>;
+    type SerializeMap = FieldsSerializer;
+    type SerializeStruct = FieldsSerializer;
+    type SerializeStructVariant = Impossible<Self::Ok, SerdeError>;
+
+    fn serialize_map(self, len: Option<usize>) -> Result<FieldsSerializer, SerdeError> {
+        Ok(FieldsSerializer { fields: Vec::with_capacity(len.unwrap_or(0)), key: None })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<FieldsSerializer, SerdeError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<Self::Ok, SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_bool(self, _: bool) -> Result<Self::Ok, SerdeError> { not_a_record() }
+    fn serialize_i8(self, _: i8) -> Result<Self::Ok, SerdeError> { not_a_record() }
+    fn serialize_i16(self, _: i16) -> Result<Self::Ok, SerdeError> { not_a_record() }
+    fn serialize_i32(self, _: i32) -> Result<Self::Ok, SerdeError> { not_a_record() }
+    fn serialize_i64(self, _: i64) -> Result<Self::Ok, SerdeError> { not_a_record() }
+    fn serialize_u8(self, _: u8) -> Result<Self::Ok, SerdeError> { not_a_record() }
+    fn serialize_u16(self, _: u16) -> Result<Self::Ok, SerdeError> { not_a_record() }
+    fn serialize_u32(self, _: u32) -> Result<Self::Ok, SerdeError> { not_a_record() }
+    fn serialize_u64(self, _: u64) -> Result<Self::Ok, SerdeError> { not_a_record() }
+    fn serialize_f32(self, _: f32) -> Result<Self::Ok, SerdeError> { not_a_record() }
+    fn serialize_f64(self, _: f64) -> Result<Self::Ok, SerdeError> { not_a_record() }
+    fn serialize_char(self, _: char) -> Result<Self::Ok, SerdeError> { not_a_record() }
+    fn serialize_str(self, _: &str) -> Result<Self::Ok, SerdeError> { not_a_record() }
+    fn serialize_bytes(self, _: &[u8]) -> Result<Self::Ok, SerdeError> { not_a_record() }
+    fn serialize_none(self) -> Result<Self::Ok, SerdeError> { not_a_record() }
+    fn serialize_some<T: Serialize + ?Sized>(self, _: &T) -> Result<Self::Ok, SerdeError> { not_a_record() }
+    fn serialize_unit(self) -> Result<Self::Ok, SerdeError> { not_a_record() }
+    fn serialize_unit_struct(self, _: &'static str) -> Result<Self::Ok, SerdeError> { not_a_record() }
+    fn serialize_unit_variant(self, _: &'static str, _: u32, _: &'static str) -> Result<Self::Ok, SerdeError> { not_a_record() }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _: &'static str, _: u32, _: &'static str, _: &T) -> Result<Self::Ok, SerdeError> { not_a_record() }
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, SerdeError> { not_a_record() }
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, SerdeError> { not_a_record() }
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeTupleStruct, SerdeError> { not_a_record() }
+    fn serialize_tuple_variant(self, _: &'static str, _: u32, _: &'static str, _: usize) -> Result<Self::SerializeTupleVariant, SerdeError> { not_a_record() }
+    fn serialize_struct_variant(self, _: &'static str, _: u32, _: &'static str, _: usize) -> Result<Self::SerializeStructVariant, SerdeError> { not_a_record() }
+}
+
+fn not_a_record<T>() -> Result<T, SerdeError> {
+    Err(SerdeError(String::from("only a struct or a map can be serialized to a record")))
+}
+
+struct FieldsSerializer {
+    fields: Vec<(String, Value)>,
+
+    // the key that `serialize_key` has received
+    key: Option<String>,
+}
+
+impl ser::SerializeStruct for FieldsSerializer {
+    type Ok = Vec<(String, Value)>;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), SerdeError> {
+        self.fields.push((key.to_string(), value.serialize(ValueSerializer)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, SerdeError> {
+        Ok(self.fields)
+    }
+}
+
+impl ser::SerializeMap for FieldsSerializer {
+    type Ok = Vec<(String, Value)>;
+    type Error = SerdeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerdeError> {
+        match key.serialize(ValueSerializer)? {
+            Value::Text(key) => {
+                self.key = Some(key);
+                Ok(())
+            },
+            _ => Err(SerdeError(String::from("a name of a column has to be a string"))),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        match self.key.take() {
+            Some(key) => {
+                self.fields.push((key, value.serialize(ValueSerializer)?));
+                Ok(())
+            },
+            None => Err(SerdeError(String::from("a value without a key"))),
+        }
+    }
+
+    fn end(self) -> Result<Self::Ok, SerdeError> {
+        Ok(self.fields)
+    }
+}
+
+// It serializes a field. `bool` is `0` or `1`, and an enum without data is its name.
+struct ValueSerializer;
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Value;
+    type Error = SerdeError;
+    type SerializeSeq = BlobSerializer;
+    type SerializeTuple = Impossible<Value, SerdeError>;
+    type SerializeTupleStruct = Impossible<Value, SerdeError>;
+    type SerializeTupleVariant = Impossible<Value, SerdeError>;
+    type SerializeMap = Impossible<Value, SerdeError>;
+    type SerializeStruct = Impossible<Value, SerdeError>;
+    type SerializeStructVariant = Impossible<Value, SerdeError>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, SerdeError> { Ok(Value::Integer(v as i64)) }
+    fn serialize_i8(self, v: i8) -> Result<Value, SerdeError> { Ok(Value::Integer(v as i64)) }
+    fn serialize_i16(self, v: i16) -> Result<Value, SerdeError> { Ok(Value::Integer(v as i64)) }
+    fn serialize_i32(self, v: i32) -> Result<Value, SerdeError> { Ok(Value::Integer(v as i64)) }
+    fn serialize_i64(self, v: i64) -> Result<Value, SerdeError> { Ok(Value::Integer(v)) }
+    fn serialize_u8(self, v: u8) -> Result<Value, SerdeError> { Ok(Value::Integer(v as i64)) }
+    fn serialize_u16(self, v: u16) -> Result<Value, SerdeError> { Ok(Value::Integer(v as i64)) }
+    fn serialize_u32(self, v: u32) -> Result<Value, SerdeError> { Ok(Value::Integer(v as i64)) }
+    fn serialize_f32(self, v: f32) -> Result<Value, SerdeError> { Ok(Value::Real(v as f64)) }
+    fn serialize_f64(self, v: f64) -> Result<Value, SerdeError> { Ok(Value::Real(v)) }
+    fn serialize_char(self, v: char) -> Result<Value, SerdeError> { Ok(Value::Text(v.to_string())) }
+    fn serialize_str(self, v: &str) -> Result<Value, SerdeError> { Ok(Value::Text(v.to_string())) }
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, SerdeError> { Ok(Value::Blob(v.to_vec())) }
+    fn serialize_none(self) -> Result<Value, SerdeError> { Ok(Value::Null) }
+    fn serialize_unit(self) -> Result<Value, SerdeError> { Ok(Value::Null) }
+    fn serialize_unit_struct(self, _: &'static str) -> Result<Value, SerdeError> { Ok(Value::Null) }
+
+    // sqlite's integers are 64-bit signed integers.
+    fn serialize_u64(self, v: u64) -> Result<Value, SerdeError> {
+        match i64::try_from(v) {
+            Ok(v) => Ok(Value::Integer(v)),
+            Err(_) => Err(SerdeError(format!("{v} is too large for sqlite"))),
+        }
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit_variant(self, _: &'static str, _: u32, variant: &'static str) -> Result<Value, SerdeError> {
+        Ok(Value::Text(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<Value, SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _: &'static str, _: u32, _: &'static str, _: &T) -> Result<Value, SerdeError> { not_a_value() }
+    fn serialize_seq(self, len: Option<usize>) -> Result<BlobSerializer, SerdeError> {
+        Ok(BlobSerializer { bytes: Vec::with_capacity(len.unwrap_or(0)) })
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, SerdeError> { not_a_value() }
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeTupleStruct, SerdeError> { not_a_value() }
+    fn serialize_tuple_variant(self, _: &'static str, _: u32, _: &'static str, _: usize) -> Result<Self::SerializeTupleVariant, SerdeError> { not_a_value() }
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, SerdeError> { not_a_value() }
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeStruct, SerdeError> { not_a_value() }
+    fn serialize_struct_variant(self, _: &'static str, _: u32, _: &'static str, _: usize) -> Result<Self::SerializeStructVariant, SerdeError> { not_a_value() }
+}
+
+fn not_a_value<T>() -> Result<T, SerdeError> {
+    Err(SerdeError(String::from("a field has to be null, an integer, a real, a text or a blob")))
+}
+
+// `Vec<u8>` is serialized as a sequence of `u8`, unless it's `serde_bytes`.
+struct BlobSerializer {
+    bytes: Vec<u8>,
+}
+
+impl ser::SerializeSeq for BlobSerializer {
+    type Ok = Value;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        match value.serialize(ValueSerializer)? {
+            Value::Integer(n @ 0..=255) => {
+                self.bytes.push(n as u8);
+                Ok(())
+            },
+            _ => not_a_value(),
+        }
+    }
+
+    fn end(self) -> Result<Value, SerdeError> {
+        Ok(Value::Blob(self.bytes))
+    }
+}
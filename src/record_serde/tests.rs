@@ -0,0 +1,61 @@
+use super::{from_record, to_record};
+use crate::error::Error;
+use crate::export::ExportDir;
+use crate::to_git::{ToGitOptions, to_git_from_connection};
+use crate::util::TempDir;
+use crate::value::Value;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Role {
+    Admin,
+    Guest,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct User {
+    id: u32,
+    name: String,
+    score: Option<f64>,
+    active: bool,
+    role: Role,
+    avatar: Vec<u8>,
+}
+
+#[test]
+fn round_trip() {
+    let tmp = TempDir::new("serde-round-trip");
+    let output = tmp.join("output").to_str().unwrap().to_string();
+
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch("
+        CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT, score REAL, active INTEGER, role TEXT, avatar BLOB);
+        INSERT INTO users VALUES (1, 'alice', 3.5, 1, 'admin', x'0102'), (2, 'bob', NULL, 0, 'guest', x'');
+    ").unwrap();
+    to_git_from_connection(&conn, &output, &ToGitOptions::default()).unwrap();
+
+    let export = ExportDir::open(&output).unwrap();
+    let users = export.table("users").unwrap();
+    let alice = users.get(&[Value::Integer(1)]).unwrap().unwrap();
+    let user: User = from_record(&alice).unwrap();
+    assert_eq!(user, User { id: 1, name: String::from("alice"), score: Some(3.5), active: true, role: Role::Admin, avatar: vec![1, 2] });
+
+    let (id, name): (i64, String) = from_record(&users.get(&[Value::Integer(2)]).unwrap().unwrap()).unwrap();
+    assert_eq!((id, name.as_str()), (2, "bob"));
+
+    // It's exactly what `to_git` has written.
+    let record = to_record(&user, users).unwrap();
+    assert_eq!(record.id, alice.id);
+    assert_eq!(record.fields, alice.fields);
+
+    #[derive(Serialize)]
+    struct Partial {
+        id: u32,
+        name: String,
+    }
+
+    assert!(matches!(to_record(&Partial { id: 3, name: String::from("carol") }, users), Err(Error::SerdeError(_))));
+    assert!(matches!(from_record::<User>(&export.table("users").unwrap().get(&[Value::Integer(2)]).unwrap().unwrap()), Ok(User { score: None, .. })));
+}
@@ -0,0 +1,30 @@
+use std::time::Duration;
+
+/// What `to_git` and `from_git` have done.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    /// In the order they're dumped (or restored): `main` first, then the attached databases.
+    /// The tables that are filtered out are not here.
+    pub tables: Vec<TableReport>,
+
+    /// The entire run, including what's not a part of any table (e.g. views and pragmas).
+    pub elapsed: Duration,
+}
+
+impl Report {
+    pub fn rows(&self) -> usize {
+        self.tables.iter().map(|table| table.rows).sum()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TableReport {
+    pub schema: String,
+    pub name: String,
+    pub rows: usize,
+
+    /// `to_git`: from when it starts reading the table to when its data files are written.
+    /// `from_git`: how long it takes to insert the records (parsing is not included if
+    /// there are multiple threads, because it overlaps with the other tables).
+    pub elapsed: Duration,
+}
@@ -0,0 +1,133 @@
+use crate::error::Error;
+use rusqlite::{Connection, OpenFlags};
+
+// If another connection keeps committing while the workers are starting their
+// transactions, it gives up and dumps the database with a single connection.
+const MAX_RETRIES: usize = 8;
+
+/// Read-only connections that see exactly the same data as `conn`, so that
+/// multiple threads can read the database and the result is the same as reading
+/// it with `conn` alone.
+///
+/// sqlite doesn't let connections share a snapshot (without `SQLITE_ENABLE_SNAPSHOT`),
+/// so it opens a read transaction on `conn` and on each worker, and makes sure that
+/// nothing was committed to any database file while they were being opened (with
+/// `PRAGMA data_version`). A read transaction keeps its snapshot until it ends.
+///
+/// `workers` is empty if the databases cannot be opened by another connection (e.g.
+/// in-memory databases), or `conn` is already in a transaction. In that case, use `conn`.
+pub(crate) struct SharedSnapshot<'a> {
+    conn: &'a Connection,
+    pub workers: Vec<Connection>,
+}
+
+impl<'a> SharedSnapshot<'a> {
+    pub fn begin(conn: &'a Connection, jobs: usize) -> Result<Self, Error> {
+        let mut result = SharedSnapshot { conn, workers: vec![] };
+
+        if jobs <= 1 || !conn.is_autocommit() {
+            return Ok(result);
+        }
+
+        let Some(files) = get_database_files(conn)? else { return Ok(result); };
+        let checker = open_connection(&files)?;
+
+        for _ in 0..jobs {
+            result.workers.push(open_connection(&files)?);
+        }
+
+        for _ in 0..MAX_RETRIES {
+            let data_versions = get_data_versions(&checker, &files)?;
+            begin_read(conn, &files)?;
+
+            for worker in result.workers.iter() {
+                begin_read(worker, &files)?;
+            }
+
+            if get_data_versions(&checker, &files)? == data_versions {
+                return Ok(result);
+            }
+
+            conn.execute_batch("ROLLBACK;")?;
+
+            for worker in result.workers.iter() {
+                worker.execute_batch("ROLLBACK;")?;
+            }
+        }
+
+        result.workers = vec![];
+        Ok(result)
+    }
+}
+
+impl Drop for SharedSnapshot<'_> {
+    fn drop(&mut self) {
+        // It's a read transaction, so there's nothing to commit.
+        if !self.workers.is_empty() {
+            let _ = self.conn.execute_batch("ROLLBACK;");
+        }
+    }
+}
+
+// (schema, file) of every database of `conn`, except `temp`. It's `None` if a database
+// doesn't have a file.
+fn get_database_files(conn: &Connection) -> Result<Option<Vec<(String, String)>>, Error> {
+    let mut stmt = conn.prepare("SELECT name, file FROM pragma_database_list ORDER BY seq;")?;
+    let mut rows = stmt.query([])?;
+    let mut result = vec![];
+
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(0)?;
+        let file: String = row.get(1)?;
+
+        if name == "temp" {
+            continue;
+        }
+
+        if file.is_empty() {
+            return Ok(None);
+        }
+
+        result.push((name, file));
+    }
+
+    Ok(Some(result))
+}
+
+// The first one is always `main`. Attached databases inherit the read-only flag.
+fn open_connection(files: &[(String, String)]) -> Result<Connection, Error> {
+    let conn = Connection::open_with_flags(&files[0].1, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    for (schema, file) in files[1..].iter() {
+        conn.execute("ATTACH DATABASE ?1 AS ?2;", [file, schema])?;
+    }
+
+    Ok(conn)
+}
+
+// A transaction doesn't read anything until it's used, so it reads every
+// database in order to fix the snapshot.
+fn begin_read(conn: &Connection, files: &[(String, String)]) -> Result<(), Error> {
+    conn.execute_batch("BEGIN;")?;
+
+    for (schema, _) in files.iter() {
+        conn.query_row(
+            &format!("SELECT COUNT(*) FROM \"{}\".sqlite_master;", schema.replace("\"", "\"\"")),
+            [],
+            |row| row.get::<_, i64>(0),
+        )?;
+    }
+
+    Ok(())
+}
+
+// It changes iff another connection has committed to the database.
+fn get_data_versions(conn: &Connection, files: &[(String, String)]) -> Result<Vec<i64>, Error> {
+    let mut result = Vec::with_capacity(files.len());
+
+    for (schema, _) in files.iter() {
+        result.push(conn.pragma_query_value(Some(schema.as_str()), "data_version", |row| row.get(0))?);
+    }
+
+    Ok(result)
+}
@@ -0,0 +1,228 @@
+use crate::error::Error;
+use crate::record::{Record, RecordId, RecordReader, RecordWriter};
+use ragit_fs::{create_dir_all, join, remove_dir_all, remove_file, temp_dir};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// `ExternalSorter` keeps at most this many records in memory (see `ToGitOptions::spill_threshold`).
+pub const DEFAULT_SPILL_THRESHOLD: usize = 65536;
+
+// If there are more runs than this, they're merged in multiple passes,
+// so that it doesn't open too many files at once.
+const MAX_MERGE_WIDTH: usize = 64;
+
+/// It sorts records by `Record::sort_key` with bounded memory. When it has `threshold`
+/// records in memory, it sorts them and spills them to a temporary file (a run). At the
+/// end, the runs are merged (k-way merge). Records with the same key keep the order they
+/// were pushed in, so the result is exactly the same as `records.sort_by_key(|r| r.sort_key())`.
+pub(crate) struct ExternalSorter {
+    threshold: usize,
+    buffer: Vec<Record>,
+
+    // It's created when the first run is spilled.
+    temp_dir: Option<TempDir>,
+
+    // paths of the runs, in the order they're spilled
+    runs: Vec<String>,
+}
+
+impl ExternalSorter {
+    pub fn new(threshold: usize) -> Self {
+        let threshold = threshold.max(1);
+
+        ExternalSorter {
+            threshold,
+            buffer: Vec::with_capacity(threshold.min(DEFAULT_SPILL_THRESHOLD)),
+            temp_dir: None,
+            runs: vec![],
+        }
+    }
+
+    pub fn push(&mut self, record: Record) -> Result<(), Error> {
+        self.buffer.push(record);
+
+        if self.buffer.len() >= self.threshold {
+            self.spill()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<SortedRecords, Error> {
+        while self.runs.len() > MAX_MERGE_WIDTH {
+            let mut merged_runs = vec![];
+            let runs = std::mem::take(&mut self.runs);
+
+            for runs in runs.chunks(MAX_MERGE_WIDTH) {
+                let mut merger = Merger::new(runs.iter().map(
+                    |run| RecordReader::open(run).map(Source::File)
+                ).collect::<Result<Vec<_>, _>>()?)?;
+                let path = self.new_run_path()?;
+                let mut writer = RecordWriter::create(&path)?;
+
+                while let Some(record) = merger.next_record()? {
+                    writer.write(&record)?;
+                }
+
+                writer.finish()?;
+                merged_runs.push(path);
+
+                for run in runs.iter() {
+                    remove_file(run)?;
+                }
+            }
+
+            self.runs = merged_runs;
+        }
+
+        self.buffer.sort_by_key(|r| r.sort_key());
+        let mut sources = self.runs.iter().map(
+            |run| RecordReader::open(run).map(Source::File)
+        ).collect::<Result<Vec<_>, _>>()?;
+
+        // The records in memory are the newest, so they come last.
+        sources.push(Source::Memory(std::mem::take(&mut self.buffer).into_iter()));
+
+        Ok(SortedRecords {
+            merger: Merger::new(sources)?,
+            _temp_dir: self.temp_dir.take(),
+        })
+    }
+
+    fn spill(&mut self) -> Result<(), Error> {
+        self.buffer.sort_by_key(|r| r.sort_key());
+        let path = self.new_run_path()?;
+        let mut writer = RecordWriter::create(&path)?;
+
+        for record in self.buffer.iter() {
+            writer.write(record)?;
+        }
+
+        writer.finish()?;
+        self.runs.push(path);
+        self.buffer.clear();
+        Ok(())
+    }
+
+    fn new_run_path(&mut self) -> Result<String, Error> {
+        if self.temp_dir.is_none() {
+            self.temp_dir = Some(TempDir::new()?);
+        }
+
+        let temp_dir = self.temp_dir.as_mut().unwrap();
+        temp_dir.files += 1;
+        Ok(join(&temp_dir.path, &format!("run-{}", temp_dir.files))?)
+    }
+}
+
+/// The result of `ExternalSorter::finish`. The temporary files are removed when it's dropped.
+pub(crate) struct SortedRecords {
+    merger: Merger,
+    _temp_dir: Option<TempDir>,
+}
+
+impl SortedRecords {
+    /// It merges the results of multiple sorters. If records have the same key,
+    /// the one from the earlier sorter comes first.
+    pub fn merge(sorted: Vec<SortedRecords>) -> Result<Self, Error> {
+        Ok(SortedRecords {
+            merger: Merger::new(sorted.into_iter().map(Source::Sorted).collect())?,
+            _temp_dir: None,
+        })
+    }
+
+    pub fn next_record(&mut self) -> Result<Option<Record>, Error> {
+        self.merger.next_record()
+    }
+}
+
+enum Source {
+    File(RecordReader),
+    Memory(std::vec::IntoIter<Record>),
+    Sorted(SortedRecords),
+}
+
+impl Source {
+    fn next_record(&mut self) -> Result<Option<Record>, Error> {
+        match self {
+            Source::File(reader) => reader.next_record(),
+            Source::Memory(records) => Ok(records.next()),
+            Source::Sorted(records) => records.next_record(),
+        }
+    }
+}
+
+// see `Record::sort_key`
+type SortKey = (RecordId, Option<i64>);
+
+// k-way merge of sorted sources. If multiple sources have the same key, the
+// source that comes first wins, so that the merge is stable.
+struct Merger {
+    sources: Vec<Source>,
+    heads: Vec<Option<Record>>,
+    heap: BinaryHeap<Reverse<(SortKey, usize)>>,
+}
+
+impl Merger {
+    fn new(mut sources: Vec<Source>) -> Result<Self, Error> {
+        let mut heads = Vec::with_capacity(sources.len());
+        let mut heap = BinaryHeap::with_capacity(sources.len());
+
+        for (index, source) in sources.iter_mut().enumerate() {
+            let head = source.next_record()?;
+
+            if let Some(record) = &head {
+                heap.push(Reverse((record.sort_key(), index)));
+            }
+
+            heads.push(head);
+        }
+
+        Ok(Merger { sources, heads, heap })
+    }
+
+    fn next_record(&mut self) -> Result<Option<Record>, Error> {
+        let Some(Reverse((_, index))) = self.heap.pop() else { return Ok(None); };
+        let record = self.heads[index].take();
+        let next = self.sources[index].next_record()?;
+
+        if let Some(next) = &next {
+            self.heap.push(Reverse((next.sort_key(), index)));
+        }
+
+        self.heads[index] = next;
+        Ok(record)
+    }
+}
+
+// A directory in the system's temp dir, which is removed when it's dropped.
+struct TempDir {
+    path: String,
+    files: usize,
+}
+
+static TEMP_DIR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+impl TempDir {
+    fn new() -> Result<Self, Error> {
+        let path = join(
+            &temp_dir()?,
+            &format!(
+                "stfg-sort-{}-{}",
+                std::process::id(),
+                TEMP_DIR_COUNT.fetch_add(1, Ordering::Relaxed),
+            ),
+        )?;
+        create_dir_all(&path)?;
+
+        Ok(TempDir { path, files: 0 })
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        // There's nothing we can do if it fails.
+        let _ = remove_dir_all(&self.path);
+    }
+}
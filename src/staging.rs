@@ -0,0 +1,109 @@
+use crate::error::Error;
+use ragit_fs::{
+    FileError,
+    create_dir_all,
+    exists,
+    is_dir,
+    join,
+    remove_dir_all,
+    remove_file,
+    rename,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static STAGING_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A temporary sibling of `target` (`.{name}.stfg-tmp-{pid}-{n}` in the same directory),
+/// where the new output is built. Once it's complete, it's renamed to `target`, so
+/// that `target` is never half-written. If anything goes wrong before that, the
+/// temporary path is removed when it's dropped, and `target` is untouched.
+///
+/// It's a sibling, not in the system's temp dir, because a rename doesn't work
+/// across file systems.
+pub(crate) struct Staging {
+    pub path: String,
+    pub target: String,
+}
+
+impl Staging {
+    /// It creates an empty directory at `path`.
+    pub fn new_dir(target: &str) -> Result<Self, Error> {
+        let result = Staging::new(target)?;
+        create_dir_all(&result.path)?;
+        Ok(result)
+    }
+
+    /// `path` doesn't exist yet.
+    pub fn new_file(target: &str) -> Result<Self, Error> {
+        Staging::new(target)
+    }
+
+    fn new(target: &str) -> Result<Self, Error> {
+        let absolute = std::path::absolute(target).map_err(|e| FileError::from_std(e, target))?;
+        let (Some(parent), Some(name)) = (
+            absolute.parent().and_then(|p| p.to_str()),
+            absolute.file_name().and_then(|n| n.to_str()),
+        ) else {
+            return Err(Error::EdgeCase(format!("cannot create a temporary sibling of `{target}`")));
+        };
+        let path = join(
+            parent,
+            &format!(
+                ".{name}.stfg-tmp-{}-{}",
+                std::process::id(),
+                STAGING_COUNT.fetch_add(1, Ordering::Relaxed),
+            ),
+        )?;
+
+        Ok(Staging {
+            path,
+            target: absolute.to_str().unwrap().to_string(),
+        })
+    }
+
+    /// It replaces `target` with `path`. A directory cannot be renamed over a non-empty
+    /// directory, so the old directory is moved away first, and removed at the end.
+    pub fn commit(self) -> Result<(), Error> {
+        if is_dir(&self.path) && exists(&self.target) {
+            let old = format!("{}.old", self.path);
+            rename(&self.target, &old)?;
+
+            if let Err(e) = rename(&self.path, &self.target) {
+                // Let's at least put the old one back.
+                rename(&old, &self.target)?;
+                return Err(e.into());
+            }
+
+            // `target` may have been a file (e.g. with `--force`).
+            if is_dir(&old) {
+                remove_dir_all(&old)?;
+            } else {
+                remove_file(&old)?;
+            }
+        }
+
+        else {
+            rename(&self.path, &self.target)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        // There's nothing we can do if it fails.
+        if is_dir(&self.path) {
+            let _ = remove_dir_all(&self.path);
+        }
+
+        // A sqlite database may leave its journal files if the connection wasn't closed.
+        for suffix in ["", "-journal", "-wal", "-shm"] {
+            let path = format!("{}{suffix}", self.path);
+
+            if exists(&path) && !is_dir(&path) {
+                let _ = remove_file(&path);
+            }
+        }
+    }
+}
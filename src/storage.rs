@@ -0,0 +1,375 @@
+use crate::error::Error;
+use ragit_fs::{
+    FileError,
+    FileErrorKind,
+    WriteMode,
+    basename,
+    create_dir_all,
+    exists,
+    is_dir,
+    join,
+    parent,
+    read_bytes,
+    read_dir,
+    remove_file,
+    temp_dir,
+    write_bytes,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Cursor, Read, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(test)]
+mod tests;
+
+/// Where an output of stfg is written to (`to_git_into_storage`) and read from
+/// (`from_storage_into_connection`, `ExportDir::from_storage`).
+///
+/// The paths are relative to the root of the storage, and the directories are implicit:
+/// `write` creates the parent directories of the file. If the database is dumped with
+/// multiple `jobs`, the tables are written by multiple threads at the same time.
+pub trait Storage: Send + Sync {
+    fn read(&self, path: &str) -> Result<Vec<u8>, Error>;
+
+    /// It creates (or truncates) a file, and its parent directories.
+    fn write(&self, path: &str, contents: &[u8]) -> Result<(), Error>;
+
+    /// Like `write`, but the contents are written a chunk at a time, so that a large file
+    /// (e.g. a bucket of a huge table) doesn't have to be in memory. The file is complete
+    /// when `StorageWriter::finish` returns. The default implementation collects the
+    /// contents in memory, and calls `write`.
+    fn create(&self, path: &str) -> Result<Box<dyn StorageWriter + '_>, Error> {
+        Ok(Box::new(BufferedWriter {
+            storage: self,
+            path: path.to_string(),
+            contents: vec![],
+        }))
+    }
+
+    /// Paths of the files and directories in `dir` (`dir` joined with their names), sorted.
+    fn read_dir(&self, dir: &str) -> Result<Vec<String>, Error>;
+
+    fn is_dir(&self, path: &str) -> bool;
+    fn is_file(&self, path: &str) -> bool;
+
+    fn exists(&self, path: &str) -> bool {
+        self.is_file(path) || self.is_dir(path)
+    }
+
+    /// The default implementation reads the entire file at once.
+    fn open(&self, path: &str) -> Result<Box<dyn Read + Send>, Error> {
+        Ok(Box::new(Cursor::new(self.read(path)?)))
+    }
+
+    fn read_string(&self, path: &str) -> Result<String, Error> {
+        String::from_utf8(self.read(path)?).map_err(
+            |_| Error::CorruptedDataFile(format!("`{path}` is not a valid utf-8 file"))
+        )
+    }
+}
+
+/// See `Storage::create`. If it's dropped without `finish`, the file may or may not exist.
+pub trait StorageWriter: Write {
+    fn finish(self: Box<Self>) -> Result<(), Error>;
+}
+
+struct BufferedWriter<'a, S: Storage + ?Sized> {
+    storage: &'a S,
+    path: String,
+    contents: Vec<u8>,
+}
+
+impl<S: Storage + ?Sized> Write for BufferedWriter<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.contents.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: Storage + ?Sized> StorageWriter for BufferedWriter<'_, S> {
+    fn finish(self: Box<Self>) -> Result<(), Error> {
+        self.storage.write(&self.path, &self.contents)
+    }
+}
+
+/// A directory in the local file system. If `root` is empty, the paths are used as they are.
+#[derive(Clone, Debug, Default)]
+pub struct FsStorage {
+    root: String,
+}
+
+impl FsStorage {
+    pub fn new(root: &str) -> Self {
+        FsStorage { root: root.to_string() }
+    }
+
+    fn path(&self, path: &str) -> Result<String, Error> {
+        if self.root.is_empty() {
+            Ok(path.to_string())
+        } else {
+            Ok(join(&self.root, path)?)
+        }
+    }
+
+    // The path in the file system, after its parent directories are created.
+    fn create_path(&self, path: &str) -> Result<String, Error> {
+        let path = self.path(path)?;
+        let parent_dir = parent(&path)?;
+
+        if !parent_dir.is_empty() && !exists(&parent_dir) {
+            create_dir_all(&parent_dir)?;
+        }
+
+        Ok(path)
+    }
+}
+
+impl Storage for FsStorage {
+    fn read(&self, path: &str) -> Result<Vec<u8>, Error> {
+        Ok(read_bytes(&self.path(path)?)?)
+    }
+
+    fn write(&self, path: &str, contents: &[u8]) -> Result<(), Error> {
+        write_bytes(&self.create_path(path)?, contents, WriteMode::CreateOrTruncate)?;
+        Ok(())
+    }
+
+    fn create(&self, path: &str) -> Result<Box<dyn StorageWriter + '_>, Error> {
+        let path = self.create_path(path)?;
+        let file = File::create(&path).map_err(|e| FileError::from_std(e, &path))?;
+
+        Ok(Box::new(FileWriter {
+            path,
+            writer: BufWriter::new(file),
+        }))
+    }
+
+    fn read_dir(&self, dir: &str) -> Result<Vec<String>, Error> {
+        let mut result = vec![];
+
+        for entry in read_dir(&self.path(dir)?, false)?.iter() {
+            result.push(join(dir, &basename(entry)?)?);
+        }
+
+        result.sort();
+        Ok(result)
+    }
+
+    fn is_dir(&self, path: &str) -> bool {
+        self.path(path).map(|path| is_dir(&path)).unwrap_or(false)
+    }
+
+    fn is_file(&self, path: &str) -> bool {
+        self.path(path).map(|path| exists(&path) && !is_dir(&path)).unwrap_or(false)
+    }
+
+    // A bucket file can be huge. It's read a line at a time (see `RecordReader`).
+    fn open(&self, path: &str) -> Result<Box<dyn Read + Send>, Error> {
+        let path = self.path(path)?;
+        let file = File::open(&path).map_err(|e| FileError::from_std(e, &path))?;
+        Ok(Box::new(file))
+    }
+}
+
+struct FileWriter {
+    path: String,
+    writer: BufWriter<File>,
+}
+
+impl Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl StorageWriter for FileWriter {
+    fn finish(mut self: Box<Self>) -> Result<(), Error> {
+        self.writer.flush().map_err(|e| FileError::from_std(e, &self.path))?;
+        Ok(())
+    }
+}
+
+/// It keeps every file in memory. It's useful for tests, or for comparing two dumps
+/// without a temporary directory.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    // normalized path (see `normalize_path`) -> contents
+    files: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+
+    /// A copy of every file, by its path (separated by `/`).
+    pub fn files(&self) -> BTreeMap<String, Vec<u8>> {
+        self.files.lock().unwrap().clone()
+    }
+
+    pub fn into_files(self) -> BTreeMap<String, Vec<u8>> {
+        self.files.into_inner().unwrap()
+    }
+}
+
+impl From<BTreeMap<String, Vec<u8>>> for MemoryStorage {
+    fn from(files: BTreeMap<String, Vec<u8>>) -> Self {
+        MemoryStorage {
+            files: Mutex::new(files.into_iter().map(|(path, contents)| (normalize_path(&path), contents)).collect()),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read(&self, path: &str) -> Result<Vec<u8>, Error> {
+        match self.files.lock().unwrap().get(&normalize_path(path)) {
+            Some(contents) => Ok(contents.clone()),
+            None => Err(FileError {
+                kind: FileErrorKind::FileNotFound,
+                given_path: Some(path.to_string()),
+            }.into()),
+        }
+    }
+
+    fn write(&self, path: &str, contents: &[u8]) -> Result<(), Error> {
+        self.files.lock().unwrap().insert(normalize_path(path), contents.to_vec());
+        Ok(())
+    }
+
+    fn read_dir(&self, dir: &str) -> Result<Vec<String>, Error> {
+        let files = self.files.lock().unwrap();
+        let names = list_dir(files.keys(), &normalize_path(dir));
+
+        if names.is_empty() && !normalize_path(dir).is_empty() {
+            return Err(FileError {
+                kind: FileErrorKind::FileNotFound,
+                given_path: Some(dir.to_string()),
+            }.into());
+        }
+
+        let mut result = Vec::with_capacity(names.len());
+
+        for name in names.iter() {
+            result.push(join(dir, name)?);
+        }
+
+        Ok(result)
+    }
+
+    fn is_dir(&self, path: &str) -> bool {
+        is_dir_of(self.files.lock().unwrap().keys(), &normalize_path(path))
+    }
+
+    fn is_file(&self, path: &str) -> bool {
+        self.files.lock().unwrap().contains_key(&normalize_path(path))
+    }
+}
+
+static SPOOL_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// It takes the contents of the file and its size.
+type OnFinish<'a> = Box<dyn FnOnce(&mut dyn Read, u64) -> Result<(), Error> + 'a>;
+
+/// `Storage::create` of a storage that has to know the size of a file before its contents
+/// (e.g. `TarStorage`). The contents are written to a file in the system's temp dir, and
+/// `finish` calls `on_finish` with the file and its size. The file is removed when it's dropped.
+pub(crate) struct SpoolWriter<'a> {
+    path: String,
+    writer: BufWriter<File>,
+    size: u64,
+    on_finish: OnFinish<'a>,
+}
+
+impl<'a> SpoolWriter<'a> {
+    pub fn new<F: FnOnce(&mut dyn Read, u64) -> Result<(), Error> + 'a>(on_finish: F) -> Result<Self, Error> {
+        let path = join(
+            &temp_dir()?,
+            &format!(
+                "stfg-spool-{}-{}",
+                std::process::id(),
+                SPOOL_COUNT.fetch_add(1, Ordering::Relaxed),
+            ),
+        )?;
+        let file = File::create(&path).map_err(|e| FileError::from_std(e, &path))?;
+
+        Ok(SpoolWriter {
+            path,
+            writer: BufWriter::new(file),
+            size: 0,
+            on_finish: Box::new(on_finish),
+        })
+    }
+}
+
+impl Write for SpoolWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl StorageWriter for SpoolWriter<'_> {
+    fn finish(mut self: Box<Self>) -> Result<(), Error> {
+        self.writer.flush().map_err(|e| FileError::from_std(e, &self.path))?;
+        let file = File::open(&self.path).map_err(|e| FileError::from_std(e, &self.path))?;
+        let on_finish = std::mem::replace(&mut self.on_finish, Box::new(|_, _| Ok(())));
+        on_finish(&mut BufReader::new(file), self.size)
+    }
+}
+
+impl Drop for SpoolWriter<'_> {
+    fn drop(&mut self) {
+        // There's nothing we can do if it fails.
+        let _ = remove_file(&self.path);
+    }
+}
+
+/// `a\b`, `./a/b` and `a//b/` are all `a/b`. The root is an empty string.
+pub(crate) fn normalize_path(path: &str) -> String {
+    path.split(['/', '\\']).filter(
+        |component| !component.is_empty() && *component != "."
+    ).collect::<Vec<_>>().join("/")
+}
+
+// Names of the files and directories in `dir`, sorted. `paths` and `dir` are normalized.
+// A storage without directories (e.g. `MemoryStorage`) only knows the paths of the files.
+pub(crate) fn list_dir<'a, I: Iterator<Item = &'a String>>(paths: I, dir: &str) -> Vec<String> {
+    let mut result = BTreeSet::new();
+
+    for path in paths {
+        let rest = if dir.is_empty() {
+            path.as_str()
+        } else {
+            match path.strip_prefix(dir).and_then(|rest| rest.strip_prefix('/')) {
+                Some(rest) => rest,
+                None => {
+                    continue;
+                },
+            }
+        };
+
+        if let Some(name) = rest.split('/').next() {
+            result.insert(name.to_string());
+        }
+    }
+
+    result.into_iter().collect()
+}
+
+pub(crate) fn is_dir_of<'a, I: Iterator<Item = &'a String>>(mut paths: I, dir: &str) -> bool {
+    dir.is_empty() || paths.any(|path| path.strip_prefix(dir).map(|rest| rest.starts_with('/')).unwrap_or(false))
+}
@@ -0,0 +1,68 @@
+use super::{FsStorage, MemoryStorage, Storage};
+use crate::export::ExportDir;
+use crate::from_git::{FromGitOptions, from_storage_into_connection};
+use crate::layout::BucketLayout;
+use crate::tar::TarStorage;
+use crate::to_git::{ToGitOptions, to_git_from_connection, to_git_into_storage};
+use crate::util::TempDir;
+use rusqlite::Connection;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[test]
+fn storages() {
+    let tmp = TempDir::new("storages");
+    let output = tmp.join("output").to_str().unwrap().to_string();
+
+    // The name of the table is too long for a ustar header.
+    let long_name = "t".repeat(120);
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch(&format!("
+        CREATE TABLE t (a INTEGER PRIMARY KEY, b TEXT);
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 300)
+        INSERT INTO t SELECT i, 'row ' || i FROM n;
+        CREATE TABLE \"{long_name}\" (a TEXT);
+        INSERT INTO \"{long_name}\" VALUES ('x'), ('y');
+        CREATE VIEW v AS SELECT b FROM t;
+    ")).unwrap();
+    let options = ToGitOptions::new().bucket_layout("t", BucketLayout::Nested(4));
+
+    to_git_from_connection(&conn, &output, &options).unwrap();
+    let memory = MemoryStorage::new();
+    to_git_into_storage(&conn, &memory, &options).unwrap();
+    assert_eq!(read_tree(&FsStorage::new(&output), ""), memory.files());
+
+    let tar = TarStorage::new(vec![]);
+    to_git_into_storage(&conn, &tar, &options.clone().jobs(2)).unwrap();
+    assert!(tar.read("manifest.toml").is_err());
+    let archive = MemoryStorage::from_tar(tar.finish().unwrap().as_slice()).unwrap();
+    assert_eq!(archive.files(), memory.files());
+
+    let mut restored = Connection::open_in_memory().unwrap();
+    let report = from_storage_into_connection(&archive, &mut restored, "main", &FromGitOptions::new()).unwrap();
+    assert_eq!(report.rows(), 302);
+    assert_eq!(
+        restored.query_row("SELECT COUNT(*) FROM v WHERE b LIKE 'row %';", [], |row| row.get::<_, i64>(0)).unwrap(),
+        300,
+    );
+
+    let export = ExportDir::from_storage(Arc::new(archive), "main").unwrap();
+    assert_eq!(export.table(&long_name).unwrap().records().unwrap().count(), 2);
+}
+
+// every file under `dir`, by its path relative to the root
+fn read_tree(storage: &dyn Storage, dir: &str) -> BTreeMap<String, Vec<u8>> {
+    let mut result = BTreeMap::new();
+
+    for path in storage.read_dir(dir).unwrap().iter() {
+        if storage.is_dir(path) {
+            result.extend(read_tree(storage, path));
+        }
+
+        else {
+            result.insert(path.to_string(), storage.read(path).unwrap());
+        }
+    }
+
+    result
+}
@@ -1,3 +1,9 @@
+use crate::error::Error;
+use crate::record::RecordId;
+use crate::to_git::RESERVED_FILES;
+use crate::value::Value;
+use crate::vtable::{FtsContent, VirtualTable};
+
 #[derive(Clone, Debug)]
 pub struct Table {
     // In most cases `name == escaped_name`.
@@ -5,6 +11,9 @@ pub struct Table {
     // can have an arbitrary character!), it'd escaped.
     pub escaped_name: String,
 
+    // `main`, or the name of an attached database.
+    pub schema: String,
+
     pub name: String,
     pub create_table_sql: String,
     pub create_index_sql: String,
@@ -15,35 +24,157 @@ pub struct Table {
     // All we need is the names so that we can read the values. We don't even
     // need the types because 1) sqlite DB is usually loosely-typed and 2)
     // stfg uses a dynamic-typed object to read the values.
+    // Generated columns and hidden columns (of virtual tables) are not included
+    // because they cannot be inserted.
     pub columns: Vec<String>,
 
+    // They're not dumped, but `from_git` checks that they can be computed.
+    pub generated_columns: Vec<String>,
+
     // This affects how stfg creates an id of a record.
-    pub primary_key: Option<String>,
+    // It's sorted by the order in `PRIMARY KEY (...)`, not by the order of the columns.
+    // If it's empty, all the columns are used to create the id.
+    pub primary_key: Vec<String>,
+
+    pub is_virtual: bool,
+
+    // It's `Some` iff `is_virtual` is set.
+    pub virtual_table: Option<VirtualTable>,
+
+    pub without_rowid: bool,
+
+    // `INTEGER PRIMARY KEY` column is an alias of the rowid.
+    pub has_rowid_alias: bool,
 }
 
 impl Table {
-    pub fn record_stmt(&self) -> String {
+    /// If `rowid` is set, the rowid is selected as the last column.
+    pub fn record_stmt(&self, rowid: Option<&str>) -> String {
+        self.select_records(rowid, "")
+    }
+
+    /// Like `record_stmt`, but it only selects the records whose rowid is
+    /// between `?1` and `?2` (inclusive). See `rowid_name`.
+    pub fn record_stmt_in_range(&self, rowid: Option<&str>, rowid_name: &str) -> String {
+        self.select_records(rowid, &format!(" WHERE {rowid_name} BETWEEN ?1 AND ?2"))
+    }
+
+    fn select_records(&self, rowid: Option<&str>, condition: &str) -> String {
         format!(
-            "SELECT {} FROM '{}';",
+            "SELECT {}{} FROM \"{}\".\"{}\"{condition};",
             self.columns.iter().map(
                 |column| format!("\"{}\"", column.replace("\"", "\"\""))
             ).collect::<Vec<_>>().join(", "),
-            self.name.replace("'", "''"),
+            match rowid {
+                Some(rowid) => format!(", {rowid}"),
+                None => String::new(),
+            },
+            self.schema.replace("\"", "\"\""),
+            self.name.replace("\"", "\"\""),
         )
     }
 
-    pub fn insert_stmt(&self) -> String {
+    /// If `rowid` is set, the rowid is bound to the last parameter.
+    pub fn insert_stmt(&self, rowid: Option<&str>) -> String {
+        let mut columns = self.columns.iter().map(
+            |column| format!("'{}'", column.replace("'", "''"))
+        ).collect::<Vec<_>>();
+
+        if let Some(rowid) = rowid {
+            columns.push(rowid.to_string());
+        }
+
         format!(
             "INSERT INTO '{}' ({}) VALUES ({})",
             self.name.replace("'", "''"),
-            self.columns.iter().map(
-                |column| format!("'{}'", column.replace("'", "''"))
-            ).collect::<Vec<_>>().join(", "),
-            self.columns.iter().enumerate().map(
+            columns.join(", "),
+            columns.iter().enumerate().map(
                 |(n, _)| format!("?{}", n + 1)
             ).collect::<Vec<_>>().join(", "),
         )
     }
+
+    /// The id of a record is a hash of its primary key. If the table doesn't have
+    /// a primary key, it's a hash of all the fields.
+    pub fn record_id(&self, fields: &[(String, Value)]) -> Result<RecordId, Error> {
+        let hash_data = if self.primary_key.is_empty() {
+            fields.iter().map(|(_, value)| value.clone()).collect::<Vec<_>>()
+        } else {
+            self.primary_key.iter().map(
+                |pk| fields.iter().find(
+                    |(column_name, _)| column_name == pk
+                ).map(
+                    |(_, value)| value.clone()
+                ).ok_or_else(
+                    || Error::EdgeCase(format!("primary key `{pk}` is not a column of table `{}`", self.name))
+                )
+            ).collect::<Result<Vec<_>, _>>()?
+        };
+
+        Ok(RecordId::hash(&hash_data))
+    }
+
+    /// Some virtual tables don't store their rows (see `VirtualTable::dumps_rows`).
+    pub fn dumps_rows(&self) -> bool {
+        match &self.virtual_table {
+            Some(virtual_table) => virtual_table.dumps_rows(),
+            None => true,
+        }
+    }
+
+    /// A rowid of a table is only worth preserving if it's not stored in any column.
+    /// It returns the name that can be used to read/write the rowid (see `rowid_name`).
+    /// If all the names are hidden, there's nothing we can do.
+    pub fn hidden_rowid(&self) -> Option<&'static str> {
+        let is_hidden = match &self.virtual_table {
+            // The rowid (docid) of an fts table is usually a reference to another table.
+            Some(VirtualTable::Fts { content: FtsContent::Internal, .. }) => true,
+            Some(_) => false,
+            None => !self.is_virtual && !self.without_rowid && !self.has_rowid_alias,
+        };
+
+        if !is_hidden {
+            return None;
+        }
+
+        self.rowid_name()
+    }
+
+    /// A name that can be used to read the rowid of a rowid table (a column named `rowid`
+    /// hides the rowid, but `_rowid_` or `oid` still works). It's `None` if the table
+    /// doesn't have a rowid or all the names are hidden.
+    pub fn rowid_name(&self) -> Option<&'static str> {
+        let has_rowid = match &self.virtual_table {
+            Some(VirtualTable::Fts { .. }) => true,
+            Some(_) => false,
+            None => !self.is_virtual && !self.without_rowid,
+        };
+
+        if !has_rowid {
+            return None;
+        }
+
+        ["rowid", "_rowid_", "oid"].into_iter().find(
+            |name| self.columns.iter().all(|column| !column.eq_ignore_ascii_case(name))
+        )
+    }
+}
+
+// `escape_path`, but if a table has the same name as a file that stfg creates next to the tables
+// (e.g. `CREATE TABLE pragma`), its first character is escaped too. `escape_path` never escapes
+// an alphanumeric character, so it doesn't collide with any other table. It ignores the case,
+// because the file system might.
+pub(crate) fn escape_table_name(name: &str) -> String {
+    let escaped = escape_path(name);
+
+    if RESERVED_FILES.iter().any(|file| file.eq_ignore_ascii_case(&escaped)) {
+        // The reserved names are all ascii.
+        format!("${:02x}${}", escaped.as_bytes()[0], &escaped[1..])
+    }
+
+    else {
+        escaped
+    }
 }
 
 // It converts `s` into a string that's safe to use in file names.
@@ -0,0 +1,233 @@
+use crate::error::Error;
+use crate::storage::{MemoryStorage, SpoolWriter, Storage, StorageWriter, is_dir_of, list_dir, normalize_path};
+use ragit_fs::join;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Cursor, Read, Write};
+use std::sync::Mutex;
+
+const BLOCK_SIZE: usize = 512;
+
+// GNU tar writes a name that doesn't fit in the header as a separate entry
+// with this name, right before the entry of the file.
+const LONG_NAME: &str = "././@LongLink";
+
+// The size field has 11 octal digits.
+const MAX_FILE_SIZE: u64 = 0o77777777777;
+
+/// It streams the files into a tar archive (ustar, with GNU long names), in the order
+/// they're written. Nothing is kept in memory, so it cannot read the files back: read
+/// the archive with `MemoryStorage::from_tar`. A file of `Storage::create` is written to
+/// a temporary file first, because its size goes before its contents.
+///
+/// The entries have no owner and no timestamp, so that the same database is always
+/// archived to the same bytes. If it's dumped with multiple `jobs`, the tables are
+/// written in a random order, though.
+pub struct TarStorage<W: Write + Send> {
+    inner: Mutex<TarWriter<W>>,
+}
+
+struct TarWriter<W> {
+    writer: W,
+
+    // normalized paths of the files that are written
+    files: BTreeSet<String>,
+}
+
+impl<W: Write + Send> TarStorage<W> {
+    pub fn new(writer: W) -> Self {
+        TarStorage {
+            inner: Mutex::new(TarWriter {
+                writer,
+                files: BTreeSet::new(),
+            }),
+        }
+    }
+
+    /// It writes the end of the archive, and returns the writer.
+    pub fn finish(self) -> Result<W, Error> {
+        let mut inner = self.inner.into_inner().unwrap();
+        inner.writer.write_all(&[0; BLOCK_SIZE * 2]).map_err(tar_error)?;
+        inner.writer.flush().map_err(tar_error)?;
+        Ok(inner.writer)
+    }
+
+    fn write_from(&self, path: &str, contents: &mut dyn Read, size: u64) -> Result<(), Error> {
+        let path = normalize_path(path);
+        let mut inner = self.inner.lock().unwrap();
+
+        if size > MAX_FILE_SIZE {
+            return Err(Error::StorageError(format!("`{path}` is too large for a tar archive")));
+        }
+
+        if path.len() > 100 {
+            inner.writer.write_all(&header(LONG_NAME, path.len() as u64 + 1, b'L')).map_err(tar_error)?;
+            write_padded(&mut inner.writer, format!("{path}\0").as_bytes())?;
+        }
+
+        inner.writer.write_all(&header(&path, size, b'0')).map_err(tar_error)?;
+
+        if std::io::copy(&mut contents.take(size), &mut inner.writer).map_err(tar_error)? != size {
+            return Err(Error::StorageError(format!("`{path}` is shorter than its size")));
+        }
+
+        inner.writer.write_all(&vec![0; (BLOCK_SIZE - size as usize % BLOCK_SIZE) % BLOCK_SIZE]).map_err(tar_error)?;
+        inner.files.insert(path);
+        Ok(())
+    }
+}
+
+impl MemoryStorage {
+    /// It reads a tar archive (e.g. of `TarStorage`) into memory. Directories and other kinds
+    /// of entries are ignored. If a file appears multiple times, the last one wins.
+    pub fn from_tar<R: Read>(mut reader: R) -> Result<MemoryStorage, Error> {
+        let mut files = BTreeMap::new();
+        let mut long_name = None;
+
+        loop {
+            let mut header = [0; BLOCK_SIZE];
+            reader.read_exact(&mut header).map_err(tar_error)?;
+
+            // The archive ends with 2 empty blocks, but some writers only write 1.
+            if header.iter().all(|b| *b == 0) {
+                break;
+            }
+
+            if checksum(&header) != parse_octal(&header[148..156])? {
+                return Err(Error::StorageError(String::from("the checksum of a tar header doesn't match")));
+            }
+
+            let size = parse_octal(&header[124..136])?;
+            let mut contents = vec![];
+            (&mut reader).take(size).read_to_end(&mut contents).map_err(tar_error)?;
+
+            if contents.len() as u64 != size {
+                return Err(Error::StorageError(String::from("unexpected end of a tar archive")));
+            }
+
+            let padding = (BLOCK_SIZE - size as usize % BLOCK_SIZE) % BLOCK_SIZE;
+            reader.read_exact(&mut vec![0; padding]).map_err(tar_error)?;
+
+            match header[156] {
+                b'L' => {
+                    long_name = Some(parse_string(&contents));
+                },
+                // pax headers: "{length} {key}={value}\n"
+                b'x' => {
+                    for record in parse_string(&contents).lines() {
+                        if let Some((_, path)) = record.split_once(" path=") {
+                            long_name = Some(path.to_string());
+                        }
+                    }
+                },
+                b'0' | 0 => {
+                    let name = match long_name.take() {
+                        Some(name) => name,
+                        None => {
+                            let prefix = parse_string(&header[345..500]);
+                            let name = parse_string(&header[0..100]);
+
+                            if prefix.is_empty() { name } else { format!("{prefix}/{name}") }
+                        },
+                    };
+
+                    files.insert(normalize_path(&name), contents);
+                },
+                _ => {
+                    long_name = None;
+                },
+            }
+        }
+
+        Ok(MemoryStorage::from(files))
+    }
+}
+
+impl<W: Write + Send> Storage for TarStorage<W> {
+    fn read(&self, path: &str) -> Result<Vec<u8>, Error> {
+        Err(Error::StorageError(format!("cannot read `{path}`: `TarStorage` is write-only")))
+    }
+
+    fn write(&self, path: &str, contents: &[u8]) -> Result<(), Error> {
+        self.write_from(path, &mut Cursor::new(contents), contents.len() as u64)
+    }
+
+    fn create(&self, path: &str) -> Result<Box<dyn StorageWriter + '_>, Error> {
+        let path = path.to_string();
+        Ok(Box::new(SpoolWriter::new(move |contents, size| self.write_from(&path, contents, size))?))
+    }
+
+    fn read_dir(&self, dir: &str) -> Result<Vec<String>, Error> {
+        let inner = self.inner.lock().unwrap();
+        let mut result = vec![];
+
+        for name in list_dir(inner.files.iter(), &normalize_path(dir)).iter() {
+            result.push(join(dir, name)?);
+        }
+
+        Ok(result)
+    }
+
+    fn is_dir(&self, path: &str) -> bool {
+        is_dir_of(self.inner.lock().unwrap().files.iter(), &normalize_path(path))
+    }
+
+    fn is_file(&self, path: &str) -> bool {
+        self.inner.lock().unwrap().files.contains(&normalize_path(path))
+    }
+}
+
+// If `path` is longer than 100 bytes, it's truncated. The long name entry has the full path.
+fn header(path: &str, size: u64, kind: u8) -> [u8; BLOCK_SIZE] {
+    let mut header = [0; BLOCK_SIZE];
+    let path = &path.as_bytes()[..path.len().min(100)];
+
+    header[0..path.len()].copy_from_slice(path);
+    header[100..108].copy_from_slice(b"0000644\0");  // mode
+    header[108..116].copy_from_slice(b"0000000\0");  // uid
+    header[116..124].copy_from_slice(b"0000000\0");  // gid
+    header[124..136].copy_from_slice(format!("{size:011o}\0").as_bytes());
+    header[136..148].copy_from_slice(b"00000000000\0");  // mtime
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    let checksum = checksum(&header);
+    header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+    header
+}
+
+// The sum of the bytes of the header, where the checksum field is filled with spaces.
+fn checksum(header: &[u8; BLOCK_SIZE]) -> u64 {
+    header.iter().enumerate().map(
+        |(i, b)| if (148..156).contains(&i) { b' ' as u64 } else { *b as u64 }
+    ).sum()
+}
+
+fn write_padded<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<(), Error> {
+    writer.write_all(bytes).map_err(tar_error)?;
+    writer.write_all(&vec![0; (BLOCK_SIZE - bytes.len() % BLOCK_SIZE) % BLOCK_SIZE]).map_err(tar_error)?;
+    Ok(())
+}
+
+// A numeric field is octal digits, terminated by a NUL or a space.
+fn parse_octal(field: &[u8]) -> Result<u64, Error> {
+    let digits = String::from_utf8_lossy(field);
+    let digits = digits.trim_matches(|c| c == '\0' || c == ' ');
+
+    if digits.is_empty() {
+        return Ok(0);
+    }
+
+    u64::from_str_radix(digits, 8).map_err(
+        |_| Error::StorageError(format!("invalid number in a tar header: {digits:?}"))
+    )
+}
+
+fn parse_string(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).to_string()
+}
+
+fn tar_error(e: std::io::Error) -> Error {
+    Error::StorageError(format!("{e}"))
+}
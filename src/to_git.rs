@@ -1,59 +1,367 @@
 use crate::db::DB;
 use crate::error::Error;
-use crate::record::{
-    Record,
-    RecordId,
-    read_records,
-    write_records,
-};
-use crate::table::{Table, escape_path};
-use crate::value::Value;
+use crate::hash::HASH_ALGORITHM_FILE;
+use crate::incremental::IncrementalStorage;
+use crate::layout::BucketLayout;
+use crate::manifest::{ChecksumWriter, MANIFEST_FILE, Manifest, ManifestTable, TableChecksum, check_output_dir, read_manifest};
+use crate::options::{ProgressCallback, TableFilter};
+use crate::record::{Record, RecordWriter};
+use crate::report::{Report, TableReport};
+use crate::snapshot::SharedSnapshot;
+use crate::staging::Staging;
+use crate::storage::{FsStorage, Storage, StorageWriter};
+use crate::sort::{DEFAULT_SPILL_THRESHOLD, ExternalSorter, SortedRecords};
+use crate::table::{Table, escape_path, escape_table_name};
+use crate::value::{Value, string_literal, value_to_string};
 use crate::view::View;
+use crate::vtable::VirtualTable;
 use ragit_fs::{
-    WriteMode,
-    create_dir_all,
+    basename,
     exists,
+    is_dir,
     join,
-    remove_dir_all,
-    write_string,
+    read_dir,
 };
-use rusqlite::{Connection, OpenFlags};
+use rusqlite::{Connection, OpenFlags, Row};
 use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry;
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Clone, Debug, Default)]
+pub struct ToGitOptions {
+    /// By default, stfg replaces the output directory with a new one. If it's set, stfg
+    /// reuses the existing output directory and only rewrites the files whose contents
+    /// have changed. Each file is compared with the existing one while it's dumped, and
+    /// only the changed files are written (to a temporary directory). Buckets and tables
+    /// that no longer exist are removed. Either way, the existing output is untouched
+    /// if the dump fails.
+    ///
+    /// Unlike a full dump, which replaces the output directory with a single rename,
+    /// the changed files are then moved into the existing directory one by one, the old
+    /// files are removed, and the manifests are moved last. So it's not atomic: if the
+    /// process dies in the middle of it, the output is a mix of the old and the new
+    /// dump, and its checksums don't match until you run it again.
+    pub incremental: bool,
+
+    /// If it's set, the rowid of each record is written to the data files, and `from_git`
+    /// restores the exact rowids. It only affects rowid tables without an `INTEGER PRIMARY KEY`
+    /// column, because the rowid of the other tables is already stored in the column.
+    pub preserve_rowid: bool,
+
+    /// How the records of each table are split into files, by the name of the table.
+    /// It overrides `default_bucket_layout`.
+    pub bucket_layouts: HashMap<String, BucketLayout>,
+
+    /// If it's not set, a table keeps the layout of the existing output (in incremental
+    /// mode), or uses `BucketLayout::default()`.
+    pub default_bucket_layout: Option<BucketLayout>,
+
+    /// How many records of a table are kept in memory while sorting them. If a table has
+    /// more records, they're sorted in chunks, spilled to temporary files and merged.
+    /// The output is the same regardless of this value. It's `DEFAULT_SPILL_THRESHOLD` if not set.
+    pub spill_threshold: Option<usize>,
+
+    /// How many threads read and write the tables. Each thread has its own read-only
+    /// connection, and they all read the same snapshot of the database, so the output
+    /// is the same as a single-threaded run. If the databases cannot be opened by
+    /// another connection (e.g. in-memory databases), it runs on a single thread.
+    /// It's 1 if not set.
+    pub jobs: Option<usize>,
+
+    /// If `output_path` exists, stfg makes sure that it's an output of stfg before it replaces
+    /// (or updates) it, so that a typo like `-o ~` doesn't wipe your home directory. If it's
+    /// set, stfg skips the check and overwrites `output_path` anyway.
+    pub force: bool,
+
+    /// Which tables to dump. The tables that are filtered out are not in the output
+    /// (in incremental mode, they're removed from the output).
+    pub tables: TableFilter,
+
+    /// It's called every time a table is dumped.
+    pub on_progress: Option<ProgressCallback>,
+}
+
+impl ToGitOptions {
+    pub fn new() -> Self {
+        ToGitOptions::default()
+    }
+
+    pub fn incremental(mut self, incremental: bool) -> Self {
+        self.incremental = incremental;
+        self
+    }
+
+    pub fn preserve_rowid(mut self, preserve_rowid: bool) -> Self {
+        self.preserve_rowid = preserve_rowid;
+        self
+    }
+
+    pub fn bucket_layout(mut self, table: &str, layout: BucketLayout) -> Self {
+        self.bucket_layouts.insert(table.to_string(), layout);
+        self
+    }
+
+    pub fn default_bucket_layout(mut self, layout: BucketLayout) -> Self {
+        self.default_bucket_layout = Some(layout);
+        self
+    }
+
+    pub fn spill_threshold(mut self, spill_threshold: usize) -> Self {
+        self.spill_threshold = Some(spill_threshold);
+        self
+    }
+
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = Some(jobs);
+        self
+    }
+
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    pub fn tables(mut self, tables: TableFilter) -> Self {
+        self.tables = tables;
+        self
+    }
+
+    pub fn on_progress<F: Fn(&TableReport) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.on_progress = Some(ProgressCallback::new(f));
+        self
+    }
+
+    fn layout_of(&self, table: &Table, old_manifest: Option<&Manifest>) -> BucketLayout {
+        if let Some(layout) = self.bucket_layouts.get(&table.name) {
+            return *layout;
+        }
+
+        if let Some(layout) = self.default_bucket_layout {
+            return layout;
+        }
+
+        old_manifest.and_then(
+            |manifest| manifest.tables.iter().find(|t| t.dir == table.escaped_name)
+        ).map(
+            |t| t.layout
+        ).unwrap_or_default()
+    }
+}
 
 pub fn to_git(
     db_path: &str,
     output_path: &str,
-) -> Result<(), Error> {
-    let db_schema = get_db_schema(db_path)?;
-    dump_db(db_path, &db_schema, output_path)?;
-    Ok(())
+) -> Result<Report, Error> {
+    to_git_with_options(db_path, output_path, &ToGitOptions::default())
 }
 
-pub(crate) fn get_db_schema(db_path: &str) -> Result<DB, Error> {
+pub fn to_git_with_options(
+    db_path: &str,
+    output_path: &str,
+    options: &ToGitOptions,
+) -> Result<Report, Error> {
     let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
-    get_db_schema_worker(conn)
+    to_git_from_connection(&conn, output_path, options)
+}
+
+/// It dumps a database that is serialized by sqlite (`sqlite3_serialize`, or the bytes of
+/// a database file). The bytes are copied to an in-memory database.
+#[cfg(feature = "serialize")]
+pub fn to_git_from_bytes(
+    bytes: &[u8],
+    output_path: &str,
+    options: &ToGitOptions,
+) -> Result<Report, Error> {
+    let mut conn = Connection::open_in_memory()?;
+    conn.deserialize_read_exact("main", bytes, bytes.len(), true)?;
+    to_git_from_connection(&conn, output_path, options)
+}
+
+/// It dumps every database of `conn`: `main` and the attached databases (`temp` is not dumped).
+/// `main` is dumped to `output_path`, and an attached database is dumped to `output_path/@{schema}`
+/// (see `schema_path`). Attach the databases before calling this function.
+pub fn to_git_from_connection(
+    conn: &Connection,
+    output_path: &str,
+    options: &ToGitOptions,
+) -> Result<Report, Error> {
+    let started_at = Instant::now();
+
+    if !options.force {
+        check_overwrite(output_path, options.incremental)?;
+    }
+
+    let incremental = options.incremental && exists(output_path);
+
+    // The existing buckets would be rewritten anyway, but we don't want
+    // the user to accidentally reshuffle the entire output.
+    if incremental {
+        check_output_dir(output_path)?;
+    }
+
+    // It's dumped to a temporary directory first, so that `output_path` is
+    // untouched if anything goes wrong. In incremental mode, only the files
+    // that have changed are written there.
+    let output = if incremental {
+        Output::Incremental(IncrementalStorage::new(output_path)?)
+    } else {
+        Output::Full(Staging::new_dir(output_path)?)
+    };
+    let mut snapshot = SharedSnapshot::begin(conn, options.jobs.unwrap_or(1))?;
+    let mut tables = vec![];
+
+    for schema in get_schema_names(conn)?.iter() {
+        let db_schema = get_db_schema_worker(conn, schema)?;
+
+        // A table keeps the layout of the existing output.
+        let old_manifest = if incremental {
+            let old_path = schema_path(output_path, schema)?;
+
+            if exists(&old_path) { read_manifest(&FsStorage::default(), &old_path)? } else { None }
+        } else {
+            None
+        };
+
+        let (storage, data_dir): (&dyn Storage, String) = match &output {
+            Output::Full(staging) => (&FsStorage::default(), schema_path(&staging.path, schema)?),
+            Output::Incremental(storage) => (storage, schema_path("", schema)?),
+        };
+
+        tables.extend(dump_db(
+            conn,
+            &mut snapshot.workers,
+            &db_schema,
+            storage,
+            &data_dir,
+            old_manifest.as_ref(),
+            options,
+        )?);
+    }
+
+    drop(snapshot);
+
+    match output {
+        Output::Full(staging) => staging.commit()?,
+        Output::Incremental(storage) => storage.commit()?,
+    }
+
+    Ok(Report {
+        tables,
+        elapsed: started_at.elapsed(),
+    })
+}
+
+enum Output {
+    // It replaces `output_path`.
+    Full(Staging),
+
+    // It updates `output_path` (see `ToGitOptions::incremental`).
+    Incremental(IncrementalStorage),
+}
+
+/// Like `to_git_from_connection`, but the output is written to `storage` (e.g. a `MemoryStorage`
+/// or a `TarStorage`) instead of a directory. `storage` should be empty, and nothing is removed
+/// from it. `incremental` and `force` only make sense for a directory, so it refuses them.
+pub fn to_git_into_storage(
+    conn: &Connection,
+    storage: &dyn Storage,
+    options: &ToGitOptions,
+) -> Result<Report, Error> {
+    if options.incremental || options.force {
+        return Err(Error::EdgeCase(String::from("`incremental` and `force` cannot be used with `to_git_into_storage`")));
+    }
+
+    let started_at = Instant::now();
+    let mut snapshot = SharedSnapshot::begin(conn, options.jobs.unwrap_or(1))?;
+    let mut tables = vec![];
+
+    for schema in get_schema_names(conn)?.iter() {
+        let db_schema = get_db_schema_worker(conn, schema)?;
+
+        tables.extend(dump_db(
+            conn,
+            &mut snapshot.workers,
+            &db_schema,
+            storage,
+            &schema_path("", schema)?,
+            None,
+            options,
+        )?);
+    }
+
+    drop(snapshot);
+
+    Ok(Report {
+        tables,
+        elapsed: started_at.elapsed(),
+    })
+}
+
+/// Attached databases are dumped to `@{schema}` directories. `@` is always escaped
+/// in the names of tables (see `escape_path`), so they never collide with tables.
+pub(crate) const ATTACHED_SCHEMA_PREFIX: &str = "@";
+
+/// Where the schema is dumped to. `main` is dumped to the root of `output_path`.
+pub(crate) fn schema_path(output_path: &str, schema: &str) -> Result<String, Error> {
+    if schema == "main" {
+        Ok(output_path.to_string())
+    }
+
+    else {
+        Ok(join(output_path, &format!("{ATTACHED_SCHEMA_PREFIX}{}", escape_path(schema)))?)
+    }
+}
+
+// `main` comes first, and the attached databases follow in the order they're attached.
+fn get_schema_names(conn: &Connection) -> Result<Vec<String>, Error> {
+    let mut stmt = conn.prepare("SELECT name FROM pragma_database_list ORDER BY seq;")?;
+    let mut rows = stmt.query([])?;
+    let mut result = vec![];
+
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(0)?;
+
+        // The temp schema is gone when the connection is closed.
+        if name != "temp" {
+            result.push(name);
+        }
+    }
+
+    Ok(result)
 }
 
 pub(crate) fn get_db_schema_from_raw_sql(sql: &str) -> Result<DB, Error> {
     let conn = Connection::open_in_memory()?;
-    conn.execute(sql, [])?;
-    get_db_schema_worker(conn)
+    conn.execute_batch(sql)?;
+    get_db_schema_worker(&conn, "main")
 }
 
-fn get_db_schema_worker(conn: Connection) -> Result<DB, Error> {
-    let mut tables_names: Vec<String> = vec![];
+fn get_db_schema_worker(conn: &Connection, schema: &str) -> Result<DB, Error> {
+    // (name, is_virtual, without_rowid)
+    let mut tables_names: Vec<(String, bool, bool)> = vec![];
     let mut tables_by_name = HashMap::new();
     let mut shadow_tables: HashSet<String> = HashSet::new();
     let mut views = vec![];
 
-    let mut tables_stmt = conn.prepare("SELECT * FROM pragma_table_list;")?;
-    let mut table_stmt = conn.prepare("SELECT * FROM pragma_table_info(?1);")?;
+    let mut tables_stmt = conn.prepare("SELECT * FROM pragma_table_list WHERE schema = ?1;")?;
+    // `pragma_table_info` doesn't show generated columns and hidden columns.
+    let mut table_stmt = conn.prepare("SELECT * FROM pragma_table_xinfo(?1, ?2);")?;
+    let mut pk_index_stmt = conn.prepare("SELECT COUNT(*) FROM pragma_index_list(?1, ?2) WHERE origin = 'pk';")?;
 
-    // TODO: if the name `sqlite_schema` is already used, I have to use `sqlite_master`.
-    let mut sqls_stmt = conn.prepare("SELECT * FROM sqlite_schema;")?;
+    // `sqlite_master` is the legacy name of `sqlite_schema`. Unlike `sqlite_schema`,
+    // it works with any version of sqlite. Also, a schema-qualified name always refers
+    // to the schema table of the database, even if a temp table has the same name.
+    let mut sqls_stmt = conn.prepare(&format!(
+        "SELECT * FROM \"{}\".sqlite_master;",
+        schema.replace("\"", "\"\""),
+    ))?;
 
-    let mut tables_q = tables_stmt.query([])?;
+    let mut tables_q = tables_stmt.query([schema])?;
 
     while let Some(table_q) = tables_q.next()? {
         let table_name = table_q.get("name")?;
@@ -70,32 +378,75 @@ fn get_db_schema_worker(conn: Connection) -> Result<DB, Error> {
                 shadow_tables.insert(table_name);
                 continue;
             },
+
+            // views are handled later, with `sqlite_schema`
+            "view" => {
+                continue;
+            },
             ty => {
                 return Err(Error::EdgeCase(format!("A type of table is `{ty}`.")));
             },
         }
 
-        tables_names.push(table_name);
+        let without_rowid = table_q.get::<_, i64>("wr")? != 0;
+        tables_names.push((table_name, table_type == "virtual", without_rowid));
     }
 
-    for table_name in tables_names.iter() {
+    for (table_name, is_virtual, without_rowid) in tables_names.iter() {
         let mut column_names: Vec<String> = vec![];
-        let mut primary_key: Option<String> = None;
-        let mut columns_q = table_stmt.query([table_name])?;
+        let mut column_types: Vec<String> = vec![];
+        let mut generated_columns: Vec<String> = vec![];
+
+        // (ordinal in the primary key, column name)
+        let mut primary_key: Vec<(usize, String)> = vec![];
+        let mut columns_q = table_stmt.query([table_name.as_str(), schema])?;
 
         while let Some(column_q) = columns_q.next()? {
             let column_name: String = column_q.get("name")?;
-            let is_primary_key = column_q.get::<_, usize>("pk")? != 0;
 
-            if is_primary_key {
-                primary_key = Some(column_name.clone());
+            match column_q.get::<_, i64>("hidden")? {
+                0 => {},
+
+                // A hidden column of a virtual table (e.g. `rank` of fts5).
+                // It's managed by the module.
+                1 => {
+                    continue;
+                },
+
+                // A generated column (2: virtual, 3: stored). Its value is computed
+                // from the other columns, so we don't have to store it.
+                _ => {
+                    generated_columns.push(column_name);
+                    continue;
+                },
+            }
+
+            // It's 0 if the column is not a part of the primary key.
+            // Otherwise, it's the 1-based index of the column in the primary key.
+            let pk_ordinal = column_q.get::<_, usize>("pk")?;
+
+            if pk_ordinal != 0 {
+                primary_key.push((pk_ordinal, column_name.clone()));
             }
 
             column_names.push(column_name);
+            column_types.push(column_q.get("type")?);
         }
 
+        // `PRIMARY KEY (b, a)` has to be hashed in the order of `(b, a)`, not in the order of columns.
+        primary_key.sort_by_key(|(ordinal, _)| *ordinal);
+        let primary_key = primary_key.into_iter().map(|(_, column)| column).collect::<Vec<_>>();
+
+        // If a rowid table has a single `INTEGER PRIMARY KEY` column, the column is an alias of the rowid.
+        // Except `INTEGER PRIMARY KEY DESC`, which is an ordinary column with a unique index (a quirk of
+        // sqlite). An alias doesn't need an index, so it's an alias if the primary key doesn't have one.
+        let has_rowid_alias = !without_rowid && primary_key.len() == 1 && column_names.iter().zip(column_types.iter()).any(
+            |(name, ty)| name == &primary_key[0] && ty.eq_ignore_ascii_case("INTEGER")
+        ) && pk_index_stmt.query_row([table_name.as_str(), schema], |row| row.get::<_, i64>(0))? == 0;
+
         let table = Table {
-            escaped_name: escape_path(table_name),
+            escaped_name: escape_table_name(table_name),
+            schema: schema.to_string(),
             name: table_name.to_string(),
 
             // will be filled later
@@ -104,7 +455,15 @@ fn get_db_schema_worker(conn: Connection) -> Result<DB, Error> {
             create_trigger_sql: String::new(),
 
             columns: column_names,
+            generated_columns,
             primary_key,
+            is_virtual: *is_virtual,
+
+            // will be filled later
+            virtual_table: None,
+
+            without_rowid: *without_rowid,
+            has_rowid_alias,
         };
 
         match tables_by_name.entry(table_name.to_string()) {
@@ -147,6 +506,9 @@ fn get_db_schema_worker(conn: Connection) -> Result<DB, Error> {
                     name: object_name,
                     // It seems like sqlite's dump doesn't end with ';' :(
                     create_view_sql: format!("{sql};"),
+
+                    // will be filled later
+                    create_trigger_sql: String::new(),
                 });
                 continue;
             },
@@ -186,6 +548,11 @@ fn get_db_schema_worker(conn: Connection) -> Result<DB, Error> {
                 }
 
                 table.create_table_sql = create_table_sqls[0].2.to_string();
+
+                if table.is_virtual {
+                    table.virtual_table = VirtualTable::from_sql(&table.create_table_sql);
+                }
+
                 table.create_index_sql = create_index_sqls.iter().map(
                     // It seems like sqlite's dump doesn't end with ';' :(
                     |(_, _, sql)| format!("{sql};")
@@ -195,182 +562,634 @@ fn get_db_schema_worker(conn: Connection) -> Result<DB, Error> {
                     |(_, _, sql)| format!("{sql};")
                 ).collect::<Vec<_>>().join("\n\n");
             },
-            None => {
-                return Err(Error::EdgeCase(format!("There's a schema for table {table_name}, but there's no such table.")));
+            // `INSTEAD OF` triggers belong to a view.
+            None => match views.iter_mut().find(|view| view.name == table_name) {
+                Some(view) if sqls.iter().all(|(t, _, _)| t == "trigger") => {
+                    sqls.sort_by_key(|(_, name, _)| name.to_string());
+                    view.create_trigger_sql = sqls.iter().map(
+                        // It seems like sqlite's dump doesn't end with ';' :(
+                        |(_, _, sql)| format!("{sql};")
+                    ).collect::<Vec<_>>().join("\n\n");
+                },
+                _ => {
+                    return Err(Error::EdgeCase(format!("There's a schema for table {table_name}, but there's no such table.")));
+                },
             },
         }
     }
 
     let mut tables = tables_by_name.into_values().collect::<Vec<_>>();
     tables.sort_by_key(|t| t.name.to_string());
-    tables = tables.into_iter().filter(
+    // sqlite_sequence is also an auto-generated table, but it has a create-table-sql, so I have to
+    // filter it out with this heuristic.
+    let is_sqlite_sequence = |t: &Table| t.name == "sqlite_sequence" && t.columns.len() == 2 && t.columns[0] == "name" && t.columns[1] == "seq";
+    let has_sqlite_sequence = tables.iter().any(is_sqlite_sequence);
+
+    tables.retain(
         |t| {
             // AFAIK, auto-generated tables (sqlite_schema, sqlite_temp_schema) don't have create-table-sqls.
-            !t.create_table_sql.is_empty() &&
+            let is_auto_generated = t.create_table_sql.is_empty();
 
-            // sqlite_sequence is also an auto-generated table, but it has a create-table-sql, so I have to
-            // filter it out with this heuristic.
-            !(t.name == "sqlite_sequence" && t.columns.len() == 2 && t.columns[0] == "name" && t.columns[1] == "seq")
+            !is_auto_generated && !is_sqlite_sequence(t)
         }
-    ).collect();
+    );
 
     views.sort_by_key(|v| v.name.to_string());
 
     Ok(DB {
+        schema: schema.to_string(),
         tables,
         views,
+        has_sqlite_sequence,
     })
 }
 
-// TODO: make it configurable
-const FLUSH_THRES: usize = 1024;
-
 fn dump_db(
-    db_path: &str,
+    conn: &Connection,
+    workers: &mut [Connection],
     db_schema: &DB,
+    storage: &dyn Storage,
     output_path: &str,
-) -> Result<(), Error> {
-    if exists(&output_path) {
-        remove_dir_all(&output_path)?;
+    old_manifest: Option<&Manifest>,
+    options: &ToGitOptions,
+) -> Result<Vec<TableReport>, Error> {
+    let mut dumps = Vec::with_capacity(db_schema.tables.len());
+
+    for table in db_schema.tables.iter().filter(|table| options.tables.matches(&table.name)) {
+        let rowid = if options.preserve_rowid { table.hidden_rowid() } else { None };
+        let layout = options.layout_of(table, old_manifest);
+        let data_dir = join(output_path, &table.escaped_name)?;
+        dumps.push(TableDump { table, rowid, layout, data_dir });
     }
 
-    create_dir_all(&output_path)?;
+    let results = dump_tables(conn, workers, storage, &dumps, options)?;
+    let mut manifest_tables = Vec::with_capacity(dumps.len());
+    let mut reports = Vec::with_capacity(dumps.len());
 
-    let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    for (TableDump { table, layout, data_dir, .. }, (report, mut checksum)) in dumps.iter().zip(results) {
+        let fts_config = match &table.virtual_table {
+            Some(virtual_table) if virtual_table.has_config() => Some(dump_fts_config(conn, table)?),
+            _ => None,
+        };
+        let fts_shadow = match &table.virtual_table {
+            Some(virtual_table) if !virtual_table.shadow_tables().is_empty() => Some(dump_fts_shadow(conn, table, virtual_table)?),
+            _ => None,
+        };
 
-    for table in db_schema.tables.iter() {
-        let mut record_stmt = conn.prepare(&table.record_stmt())?;
-        let mut records_q = record_stmt.query([])?;
-        let mut records_by_id_prefix = HashMap::new();
+        // in the order of `TABLE_FILES`
+        for (name, contents) in [
+            ("table.sql", Some(&table.create_table_sql)),
+            ("index.sql", Some(&table.create_index_sql)),
+            ("trigger.sql", Some(&table.create_trigger_sql)),
+            (FTS_CONFIG_FILE, fts_config.as_ref()),
+            (FTS_SHADOW_FILE, fts_shadow.as_ref()),
+        ] {
+            if let Some(contents) = contents {
+                storage.write(&join(data_dir, name)?, contents.as_bytes())?;
+                checksum.add_file(name, contents.as_bytes());
+            }
+        }
 
-        while let Some(record) = records_q.next()? {
-            let mut fields = Vec::with_capacity(table.columns.len());
+        manifest_tables.push(ManifestTable {
+            name: table.name.to_string(),
+            dir: table.escaped_name.to_string(),
+            rows: report.rows as i64,
+            layout: *layout,
+            checksum: checksum.finish(),
+        });
+        reports.push(report);
+    }
 
-            // It's used to create the id of this record.
-            let mut hash_data = Vec::with_capacity(
-                if table.primary_key.is_some() {
-                    1
-                } else {
-                    table.columns.len()
-                }
-            );
+    storage.write(
+        &join(
+            output_path,
+            "view.sql",
+        )?,
+        db_schema.views.iter().map(
+            |view| view.create_view_sql.to_string()
+        ).collect::<Vec<_>>().join("\n\n").as_bytes(),
+    )?;
 
-            for column_name in table.columns.iter() {
-                let value: Value = record.get(column_name.as_str())?;
-
-                match &table.primary_key {
-                    Some(pk) if pk == column_name => {
-                        hash_data.push(value.clone());
-                    },
-                    Some(_) => {},
-                    None => {
-                        hash_data.push(value.clone());
-                    },
-                }
+    // triggers of views
+    storage.write(
+        &join(
+            output_path,
+            "trigger.sql",
+        )?,
+        db_schema.views.iter().filter(
+            |view| !view.create_trigger_sql.is_empty()
+        ).map(
+            |view| view.create_trigger_sql.to_string()
+        ).collect::<Vec<_>>().join("\n\n").as_bytes(),
+    )?;
 
-                fields.push((column_name.to_string(), value));
-            }
+    storage.write(
+        &join(
+            output_path,
+            SQLITE_SEQUENCE_FILE,
+        )?,
+        dump_sqlite_sequence(conn, db_schema, &options.tables)?.as_bytes(),
+    )?;
 
-            let id = RecordId::hash(&hash_data);
+    storage.write(
+        &join(
+            output_path,
+            PRAGMA_FILE,
+        )?,
+        dump_pragmas(conn, &db_schema.schema)?.as_bytes(),
+    )?;
 
-            match records_by_id_prefix.entry(id.prefix()) {
-                Entry::Occupied(mut e) => {
-                    let v: &mut Vec<Record> = e.get_mut();
-                    v.push(Record {
-                        id,
-                        fields,
-                    });
+    storage.write(
+        &join(
+            output_path,
+            MANIFEST_FILE,
+        )?,
+        Manifest::new(manifest_tables).to_toml().as_bytes(),
+    )?;
 
-                    // TODO: make this number configurable
-                    if v.len() >= FLUSH_THRES {
-                        flush(output_path, &table.escaped_name, id.prefix(), &v)?;
-                        v.clear();
-                    }
-                },
-                Entry::Vacant(e) => {
-                    let mut v = Vec::with_capacity(FLUSH_THRES);
-                    v.push(Record {
-                        id,
-                        fields,
-                    });
-                    e.insert(v);
-                },
+    Ok(reports)
+}
+
+/// Counters of AUTOINCREMENT columns are stored in `sqlite_sequence`. If we don't dump
+/// them, the counters restart at max(rowid) and the ids of deleted rows will be reused.
+///
+/// Each line of the file looks like `"table_name"=seq`, sorted by the table name.
+pub(crate) const SQLITE_SEQUENCE_FILE: &str = "sqlite_sequence";
+
+/// Database-level settings that are not part of any `CREATE` statement.
+/// Each line of the file looks like `"user_version"=3`.
+pub(crate) const PRAGMA_FILE: &str = "pragma";
+
+/// Files that stfg creates next to the directories of the tables (`HASH_ALGORITHM_FILE` is
+/// created by older versions). A table with the same name is stored in a different directory
+/// (see `escape_table_name`).
+pub(crate) const RESERVED_FILES: [&str; 6] = [
+    "view.sql",
+    "trigger.sql",
+    SQLITE_SEQUENCE_FILE,
+    PRAGMA_FILE,
+    MANIFEST_FILE,
+    HASH_ALGORITHM_FILE,
+];
+
+/// `from_git` applies the pragmas in this order. `encoding`, `page_size` and `auto_vacuum`
+/// have to be set before any table is created, and `journal_mode` is set at the very end
+/// because `page_size` cannot be changed in WAL mode.
+pub(crate) const PRAGMAS: [&str; 6] = [
+    "encoding",
+    "page_size",
+    "auto_vacuum",
+    "application_id",
+    "user_version",
+    "journal_mode",
+];
+
+fn dump_pragmas(conn: &Connection, schema: &str) -> Result<String, Error> {
+    let mut lines = vec![];
+
+    for pragma in PRAGMAS.iter() {
+        let value: Value = conn.pragma_query_value(Some(schema), pragma, |row| row.get(0))?;
+        lines.push(format!("{}={}\n", string_literal(pragma), value_to_string(&value)));
+    }
+
+    Ok(lines.concat())
+}
+
+/// Configurations of an fts5 table (e.g. `rank`, `automerge`), which are stored in a shadow table.
+/// Each line of the file looks like `"rank"="bm25(10.0, 5.0)"`, sorted by the key.
+pub(crate) const FTS_CONFIG_FILE: &str = "config";
+
+fn dump_fts_config(conn: &Connection, table: &Table) -> Result<String, Error> {
+    // `version` is managed by fts5 itself.
+    let mut stmt = conn.prepare(&format!(
+        "SELECT k, v FROM \"{}\".\"{}_config\" WHERE k != 'version' ORDER BY k;",
+        table.schema.replace("\"", "\"\""),
+        table.name.replace("\"", "\"\""),
+    ))?;
+    let mut rows = stmt.query([])?;
+    let mut lines = vec![];
+
+    while let Some(row) = rows.next()? {
+        let key: String = row.get(0)?;
+        let value: Value = row.get(1)?;
+        lines.push(format!("{}={}\n", string_literal(&key), value_to_string(&value)));
+    }
+
+    Ok(lines.concat())
+}
+
+/// Shadow tables of a contentless fts table (see `VirtualTable::shadow_tables`). A row is
+/// written like a record (a line per column, and rows are separated by an empty line), and
+/// its first line is the suffix of the shadow table, like `"table"="data"`.
+pub(crate) const FTS_SHADOW_FILE: &str = "shadow";
+
+fn dump_fts_shadow(conn: &Connection, table: &Table, virtual_table: &VirtualTable) -> Result<String, Error> {
+    let schema = table.schema.replace("\"", "\"\"");
+    let mut rows_s = vec![];
+
+    for suffix in virtual_table.shadow_tables().iter() {
+        let shadow_name = format!("{}_{suffix}", table.name);
+        let exists = conn.query_row(
+            &format!("SELECT COUNT(*) FROM \"{schema}\".sqlite_schema WHERE type = 'table' AND name = ?1;"),
+            [&shadow_name],
+            |row| row.get::<_, i64>(0),
+        )? != 0;
+
+        if !exists {
+            continue;
+        }
+
+        let shadow_name = format!("\"{schema}\".\"{}\"", shadow_name.replace("\"", "\"\""));
+        let columns = conn.prepare(&format!("SELECT * FROM {shadow_name};"))?.column_names().iter().map(
+            |column| column.to_string()
+        ).collect::<Vec<_>>();
+
+        // Every column, so that the order doesn't depend on how sqlite scans the table.
+        let order_by = (1..=columns.len()).map(|i| i.to_string()).collect::<Vec<_>>().join(", ");
+        let mut stmt = conn.prepare(&format!("SELECT * FROM {shadow_name} ORDER BY {order_by};"))?;
+        let mut rows = stmt.query([])?;
+
+        while let Some(row) = rows.next()? {
+            let mut lines = vec![format!("\"table\"={}\n", string_literal(suffix))];
+
+            for (i, column) in columns.iter().enumerate() {
+                let value: Value = row.get(i)?;
+                lines.push(format!("{}={}\n", string_literal(column), value_to_string(&value)));
             }
+
+            rows_s.push(lines.concat());
         }
+    }
+
+    Ok(rows_s.join("\n"))
+}
+
+fn dump_sqlite_sequence(conn: &Connection, db_schema: &DB, tables: &TableFilter) -> Result<String, Error> {
+    if !db_schema.has_sqlite_sequence {
+        return Ok(String::new());
+    }
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT name, seq FROM \"{}\".sqlite_sequence ORDER BY name;",
+        db_schema.schema.replace("\"", "\"\""),
+    ))?;
+    let mut rows = stmt.query([])?;
+    let mut lines = vec![];
 
-        for (prefix, records) in records_by_id_prefix.into_iter() {
-            flush(output_path, &table.escaped_name, prefix, &records)?;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(0)?;
+        let seq: Value = row.get(1)?;
+
+        // Otherwise, `from_git` would complain that there's no AUTOINCREMENT table.
+        if !tables.matches(&name) {
+            continue;
         }
 
-        // TODO: dump table.create_table_sql and table.create_index_sql.
-        let data_dir = join(output_path, &table.escaped_name)?;
+        // `name` can have an arbitrary character.
+        lines.push(format!("{}={}\n", string_literal(&name), value_to_string(&seq)));
+    }
+
+    Ok(lines.concat())
+}
+
+// What `dump_tables` needs to dump a table.
+struct TableDump<'a> {
+    table: &'a Table,
+    rowid: Option<&'static str>,
+    layout: BucketLayout,
+    data_dir: String,
+}
+
+// (name of the rowid, start, end), both inclusive
+type RowidRange = (&'static str, i64, i64);
+
+// A table is split into rowid ranges only if each range has at least this many rows.
+// Otherwise, merging the ranges costs more than reading them in parallel.
+const MIN_ROWS_PER_RANGE: i64 = 16384;
 
-        if !exists(&data_dir) {
-            create_dir_all(&data_dir)?;
+// It returns (report, checksum) of each table, and reports the progress. If there are `workers` (see `SharedSnapshot`),
+// the tables are dumped in parallel. A large table is split into rowid ranges, which are
+// read and sorted by different workers, then merged. The output is the same either way.
+fn dump_tables(
+    conn: &Connection,
+    workers: &mut [Connection],
+    storage: &dyn Storage,
+    dumps: &[TableDump],
+    options: &ToGitOptions,
+) -> Result<Vec<(TableReport, TableChecksum)>, Error> {
+    if workers.is_empty() {
+        let mut result = Vec::with_capacity(dumps.len());
+
+        for dump in dumps.iter() {
+            let started_at = Instant::now();
+            let (rows, records) = sort_records(conn, dump, None, options)?;
+            let checksum = write_buckets(records, storage, dump)?;
+            result.push((report_table(dump, rows, started_at, options), checksum));
         }
 
-        write_string(
-            &join(
-                &data_dir,
-                "table.sql",
-            )?,
-            &table.create_table_sql,
-            WriteMode::AlwaysCreate,
-        )?;
-        write_string(
-            &join(
-                &data_dir,
-                "index.sql",
-            )?,
-            &table.create_index_sql,
-            WriteMode::AlwaysCreate,
-        )?;
-        write_string(
-            &join(
-                &data_dir,
-                "trigger.sql",
-            )?,
-            &table.create_trigger_sql,
-            WriteMode::AlwaysCreate,
-        )?;
-    }
-
-    write_string(
-        &join(
-            &output_path,
-            "view.sql",
-        )?,
-        &db_schema.views.iter().map(
-            |view| view.create_view_sql.to_string()
-        ).collect::<Vec<_>>().join("\n\n"),
-        WriteMode::AlwaysCreate,
+        return Ok(result);
+    }
+
+    // (index of the table, index of the range, range)
+    let mut ranges = vec![];
+
+    // sorted records of each range, and how many ranges are not sorted yet
+    let mut sorted_ranges = Vec::with_capacity(dumps.len());
+    let mut remaining_ranges = Vec::with_capacity(dumps.len());
+
+    // when the first range of each table is read
+    let started_at = (0..dumps.len()).map(|_| OnceLock::new()).collect::<Vec<_>>();
+
+    for (table_index, dump) in dumps.iter().enumerate() {
+        let table_ranges = split_rowids(conn, dump.table, workers.len())?;
+        sorted_ranges.push(Mutex::new((0..table_ranges.len()).map(|_| None).collect::<Vec<_>>()));
+        remaining_ranges.push(AtomicUsize::new(table_ranges.len()));
+
+        for (range_index, range) in table_ranges.into_iter().enumerate() {
+            ranges.push((table_index, range_index, range));
+        }
+    }
+
+    let next_range = AtomicUsize::new(0);
+    let results = Mutex::new(vec![None; dumps.len()]);
+    let error = Mutex::new(None);
+
+    std::thread::scope(|s| {
+        for worker in workers.iter_mut() {
+            let (ranges, sorted_ranges, remaining_ranges, started_at, next_range, results, error) = (
+                &ranges,
+                &sorted_ranges,
+                &remaining_ranges,
+                &started_at,
+                &next_range,
+                &results,
+                &error,
+            );
+
+            s.spawn(move || {
+                while error.lock().unwrap().is_none() {
+                    let Some((table_index, range_index, range)) = ranges.get(next_range.fetch_add(1, Ordering::Relaxed)) else { break; };
+                    let dump = &dumps[*table_index];
+                    let table_started_at = *started_at[*table_index].get_or_init(Instant::now);
+
+                    let result = sort_records(worker, dump, *range, options).and_then(
+                        |sorted| {
+                            sorted_ranges[*table_index].lock().unwrap()[*range_index] = Some(sorted);
+
+                            // The worker that sorts the last range of the table writes the buckets.
+                            if remaining_ranges[*table_index].fetch_sub(1, Ordering::AcqRel) != 1 {
+                                return Ok(None);
+                            }
+
+                            let sorted = std::mem::take(&mut *sorted_ranges[*table_index].lock().unwrap());
+                            let rows = sorted.iter().map(|s| s.as_ref().unwrap().0).sum::<usize>();
+                            let records = SortedRecords::merge(sorted.into_iter().map(|s| s.unwrap().1).collect())?;
+                            let checksum = write_buckets(records, storage, dump)?;
+                            Ok(Some((report_table(dump, rows, table_started_at, options), checksum)))
+                        }
+                    );
+
+                    match result {
+                        Ok(Some(result)) => {
+                            results.lock().unwrap()[*table_index] = Some(result);
+                        },
+                        Ok(None) => {},
+                        Err(e) => {
+                            error.lock().unwrap().get_or_insert(e);
+                        },
+                    }
+                }
+            });
+        }
+    });
+
+    if let Some(e) = error.into_inner().unwrap() {
+        return Err(e);
+    }
+
+    Ok(results.into_inner().unwrap().into_iter().map(|result| result.unwrap()).collect())
+}
+
+fn report_table(dump: &TableDump, rows: usize, started_at: Instant, options: &ToGitOptions) -> TableReport {
+    let report = TableReport {
+        schema: dump.table.schema.to_string(),
+        name: dump.table.name.to_string(),
+        rows,
+        elapsed: started_at.elapsed(),
+    };
+
+    if let Some(on_progress) = &options.on_progress {
+        on_progress.call(&report);
+    }
+
+    report
+}
+
+// It splits the rowids of a table into at most `n` ranges. If the table is
+// small or doesn't have a rowid, there's only 1 range: `None` (the entire table).
+fn split_rowids(conn: &Connection, table: &Table, n: usize) -> Result<Vec<Option<RowidRange>>, Error> {
+    let rowid_name = match table.rowid_name() {
+        Some(rowid_name) if !table.is_virtual && table.dumps_rows() => rowid_name,
+        _ => {
+            return Ok(vec![None]);
+        },
+    };
+    let (min, max, count): (Option<i64>, Option<i64>, i64) = conn.query_row(
+        &format!(
+            "SELECT MIN({rowid_name}), MAX({rowid_name}), COUNT(*) FROM \"{}\".\"{}\";",
+            table.schema.replace("\"", "\"\""),
+            table.name.replace("\"", "\"\""),
+        ),
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
     )?;
+    let ranges = (count / MIN_ROWS_PER_RANGE).min(n as i64);
+
+    let (Some(min), Some(max)) = (min, max) else { return Ok(vec![None]); };
+
+    if ranges <= 1 {
+        return Ok(vec![None]);
+    }
+
+    // i128, because `max - min` may overflow
+    let width = (max as i128 - min as i128 + ranges as i128) / ranges as i128;
+    let mut result = vec![];
+
+    for i in 0..ranges as i128 {
+        let start = min as i128 + width * i;
+
+        if start > max as i128 {
+            break;
+        }
+
+        let end = (start + width - 1).min(max as i128);
+        result.push(Some((rowid_name, start as i64, end as i64)));
+    }
+
+    Ok(result)
+}
+
+// It reads the records of the table (or a range of it) and sorts them.
+fn sort_records(
+    conn: &Connection,
+    dump: &TableDump,
+    range: Option<RowidRange>,
+    options: &ToGitOptions,
+) -> Result<(usize, SortedRecords), Error> {
+    let TableDump { table, rowid, .. } = dump;
+    let mut sorter = ExternalSorter::new(options.spill_threshold.unwrap_or(DEFAULT_SPILL_THRESHOLD));
+    let mut rows = 0;
+
+    if table.dumps_rows() {
+        let mut record_stmt;
+        let mut records_q = match range {
+            Some((rowid_name, start, end)) => {
+                record_stmt = conn.prepare(&table.record_stmt_in_range(*rowid, rowid_name))?;
+                record_stmt.query([start, end])?
+            },
+            None => {
+                record_stmt = conn.prepare(&table.record_stmt(*rowid))?;
+                record_stmt.query([])?
+            },
+        };
+
+        while let Some(row) = records_q.next()? {
+            rows += 1;
+            sorter.push(row_to_record(row, table, rowid.is_some())?)?;
+        }
+    }
 
+    Ok((rows, sorter.finish()?))
+}
+
+// The records are sorted by id, and so are the buckets. So it writes the buckets
+// one by one, and never has to read a bucket back. A bucket is streamed into the
+// storage a record at a time, and it's hashed on the way. It returns the checksum of
+// the buckets (see `table_checksum`).
+fn write_buckets(mut records: SortedRecords, storage: &dyn Storage, dump: &TableDump) -> Result<TableChecksum, Error> {
+    let mut checksum = TableChecksum::new(dump.layout);
+    let mut curr_bucket: Option<(u64, BucketWriter)> = None;
+
+    while let Some(record) = records.next_record()? {
+        let bucket = dump.layout.bucket_of(record.id);
+
+        match &mut curr_bucket {
+            Some((curr, writer)) if *curr == bucket => {
+                writer.write(&record)?;
+            },
+            _ => {
+                if let Some((bucket, writer)) = curr_bucket.take() {
+                    finish_bucket(&mut checksum, bucket, writer)?;
+                }
+
+                let path = join(&dump.data_dir, &dump.layout.bucket_path(bucket))?;
+                let mut writer = RecordWriter::new(&path, ChecksumWriter::new(storage.create(&path)?));
+                writer.write(&record)?;
+                curr_bucket = Some((bucket, writer));
+            },
+        }
+    }
+
+    if let Some((bucket, writer)) = curr_bucket {
+        finish_bucket(&mut checksum, bucket, writer)?;
+    }
+
+    Ok(checksum)
+}
+
+type BucketWriter<'a> = RecordWriter<ChecksumWriter<Box<dyn StorageWriter + 'a>>>;
+
+fn finish_bucket(checksum: &mut TableChecksum, bucket: u64, writer: BucketWriter) -> Result<(), Error> {
+    let (file, file_checksum) = writer.finish()?.finish();
+    file.finish()?;
+    checksum.add_bucket_checksum(bucket, file_checksum);
     Ok(())
 }
 
-fn flush(
-    output_path: &str,
-    table_name: &str,
-    id_prefix: u64,
-    records: &[Record],
-) -> Result<(), Error> {
-    let id_prefix_s = format!("{id_prefix:03o}");
-    let data_dir = join(output_path, table_name)?;
+// An output of stfg has `MANIFEST_FILE` (or `HASH_ALGORITHM_FILE` if it's created by an older
+// version, or neither if it's even older, see `is_legacy_output`). If `output_path` is not an output of stfg, everything in it would be removed (or mixed
+// with the output). If it is, and it's not `incremental`, the files that the user has put there
+// would be removed.
+fn check_overwrite(output_path: &str, incremental: bool) -> Result<(), Error> {
+    if !exists(output_path) {
+        return Ok(());
+    }
 
-    if !exists(&data_dir) {
-        create_dir_all(&data_dir)?;
+    if !is_dir(output_path) {
+        return Err(Error::RefusedToOverwrite {
+            path: output_path.to_string(),
+            files: vec![output_path.to_string()],
+        });
     }
 
-    let data_path = join(&data_dir, &id_prefix_s)?;
-    let mut data = if exists(&data_path) {
-        read_records(&data_path)?
+    let is_output = exists(&join(output_path, MANIFEST_FILE)?)
+        || exists(&join(output_path, HASH_ALGORITHM_FILE)?)
+        || is_legacy_output(output_path)?;
+    let mut files = vec![];
+
+    for path in read_dir(output_path, true)?.iter() {
+        if !is_output || (!incremental && !is_stfg_output(path)?) {
+            files.push(if is_dir(path) { format!("{path}/") } else { path.to_string() });
+        }
+    }
+
+    if !files.is_empty() {
+        return Err(Error::RefusedToOverwrite {
+            path: output_path.to_string(),
+            files,
+        });
+    }
+
+    Ok(())
+}
+
+// The first versions of stfg only create `view.sql` and a directory (with `table.sql`) per table.
+fn is_legacy_output(output_path: &str) -> Result<bool, Error> {
+    if !exists(&join(output_path, "view.sql")?) {
+        return Ok(false);
+    }
+
+    for path in read_dir(output_path, false)?.iter() {
+        if is_dir(path) && exists(&join(path, "table.sql")?) {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+// Files and directories that stfg creates in the root of an output directory.
+pub(crate) fn is_stfg_output(path: &str) -> Result<bool, Error> {
+    let name = basename(path)?;
+
+    if is_dir(path) {
+        // a table, or an attached database
+        Ok(exists(&join(path, "table.sql")?) || (name.starts_with(ATTACHED_SCHEMA_PREFIX) && exists(&join(path, PRAGMA_FILE)?)))
+    }
+
+    else {
+        Ok(RESERVED_FILES.contains(&name.as_str()))
+    }
+}
+
+// If `has_rowid` is set, the last column of `row` is the rowid (see `Table::record_stmt`).
+fn row_to_record(row: &Row, table: &Table, has_rowid: bool) -> Result<Record, Error> {
+    let mut fields = Vec::with_capacity(table.columns.len());
+
+    for column_name in table.columns.iter() {
+        let value: Value = row.get(column_name.as_str())?;
+        fields.push((column_name.to_string(), value));
+    }
+
+    let rowid = if has_rowid {
+        Some(row.get::<_, i64>(table.columns.len())?)
     } else {
-        vec![]
+        None
     };
 
-    data.append(&mut records.to_vec());
-    data.sort_by_key(|r| r.id);
-    write_records(&data_path, &data)?;
-    Ok(())
+    Ok(Record {
+        id: table.record_id(&fields)?,
+        fields,
+        rowid,
+    })
 }
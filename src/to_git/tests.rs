@@ -0,0 +1,478 @@
+use super::{ToGitOptions, get_db_schema_from_raw_sql, to_git_from_connection, to_git_into_storage};
+use crate::error::Error;
+use crate::from_git::{FromGitOptions, from_git_into_connection, from_git_schema, from_git_with_options, from_storage_into_connection};
+use crate::fsck::fsck;
+use crate::layout::BucketLayout;
+use crate::options::TableFilter;
+use crate::storage::{FsStorage, MemoryStorage};
+use crate::util::TempDir;
+use rusqlite::Connection;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+#[test]
+fn composite_primary_key() {
+    let db = get_db_schema_from_raw_sql("CREATE TABLE t (a TEXT, b INTEGER, c TEXT, PRIMARY KEY (c, a));").unwrap();
+    assert_eq!(db.tables[0].primary_key, vec![String::from("c"), String::from("a")]);
+
+    let db = get_db_schema_from_raw_sql("CREATE TABLE t (a TEXT PRIMARY KEY, b INTEGER);").unwrap();
+    assert_eq!(db.tables[0].primary_key, vec![String::from("a")]);
+
+    let db = get_db_schema_from_raw_sql("CREATE TABLE t (a TEXT, b INTEGER);").unwrap();
+    assert!(db.tables[0].primary_key.is_empty());
+}
+
+#[test]
+fn attached_schema() {
+    let tmp = TempDir::new("attached-schema");
+    let output = tmp.join("output").to_str().unwrap().to_string();
+    let aux_path = tmp.join("aux.db").to_str().unwrap().to_string();
+
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch("
+        ATTACH DATABASE ':memory:' AS aux;
+        CREATE TABLE t (a INTEGER PRIMARY KEY, b TEXT);
+        INSERT INTO t VALUES (1, 'main');
+        CREATE TABLE aux.t (a INTEGER PRIMARY KEY, b TEXT);
+        INSERT INTO aux.t VALUES (1, 'aux'), (2, 'aux');
+        CREATE VIEW aux.v AS SELECT b FROM t;
+    ").unwrap();
+    to_git_from_connection(&conn, &output, &ToGitOptions::default()).unwrap();
+
+    assert!(tmp.join("output/t/table.sql").exists());
+    assert!(tmp.join("output/@aux/t/table.sql").exists());
+
+    from_git_schema(&output, &aux_path, "aux", &FromGitOptions::default()).unwrap();
+    let restored = Connection::open(&aux_path).unwrap();
+    let count: i64 = restored.query_row("SELECT COUNT(*) FROM v WHERE b = 'aux';", [], |row| row.get(0)).unwrap();
+    assert_eq!(count, 2);
+
+    assert!(matches!(from_git_schema(&output, &aux_path, "logs", &FromGitOptions::default()), Err(Error::NoSuchSchema(_))));
+}
+
+#[test]
+fn change_bucket_layout() {
+    let tmp = TempDir::new("bucket-layout");
+    let output = tmp.join("output").to_str().unwrap().to_string();
+    let db_path = tmp.join("restored.db").to_str().unwrap().to_string();
+
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch("
+        CREATE TABLE t (a INTEGER PRIMARY KEY, b TEXT);
+        WITH RECURSIVE n(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n WHERE x < 100)
+        INSERT INTO t SELECT x, 'row ' || x FROM n;
+    ").unwrap();
+    let mut options = ToGitOptions { incremental: true, ..ToGitOptions::default() };
+    to_git_from_connection(&conn, &output, &options).unwrap();
+
+    for layout in [BucketLayout::Nested(4), BucketLayout::Single, BucketLayout::Flat(1)] {
+        options.bucket_layouts.insert(String::from("t"), layout);
+        to_git_from_connection(&conn, &output, &options).unwrap();
+
+        // The buckets of the previous layout are gone.
+        let data_dir = tmp.join("output/t").to_str().unwrap().to_string();
+        let files = std::fs::read_dir(&data_dir).unwrap().map(
+            |entry| entry.unwrap().file_name().into_string().unwrap()
+        ).filter(
+            |name| !name.ends_with(".sql")
+        ).collect::<HashSet<_>>();
+        let buckets = layout.list_buckets(&FsStorage::default(), &data_dir).unwrap().iter().map(
+            |(bucket, _)| layout.bucket_path(*bucket).split('/').next().unwrap().to_string()
+        ).collect::<HashSet<_>>();
+        assert_eq!(files, buckets);
+        assert!(fsck(&output, false).unwrap().is_empty());
+
+        from_git_with_options(&output, &db_path, &FromGitOptions::default()).unwrap();
+        let restored = Connection::open(&db_path).unwrap();
+        let count: i64 = restored.query_row("SELECT COUNT(*) FROM t;", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 100);
+    }
+
+    // Without an explicit layout, it keeps the layout of the existing output.
+    // Files that stfg didn't create are kept.
+    std::fs::write(tmp.join("output/notes.txt"), "hello").unwrap();
+    options.bucket_layouts.clear();
+    to_git_from_connection(&conn, &output, &options).unwrap();
+    assert!(tmp.join("output/t/0").exists());
+    assert!(tmp.join("output/notes.txt").exists());
+}
+
+#[test]
+fn incremental_update() {
+    let tmp = TempDir::new("incremental-update");
+    let output = tmp.join("output").to_str().unwrap().to_string();
+    let fresh = tmp.join("fresh").to_str().unwrap().to_string();
+
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch("
+        CREATE TABLE t (a INTEGER PRIMARY KEY, b TEXT);
+        WITH RECURSIVE n(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n WHERE x < 100)
+        INSERT INTO t SELECT x, 'row ' || x FROM n;
+        CREATE TABLE u (a TEXT);
+        INSERT INTO u VALUES ('x');
+    ").unwrap();
+    let options = ToGitOptions::new().incremental(true).bucket_layout("t", BucketLayout::Flat(1));
+    to_git_from_connection(&conn, &output, &options).unwrap();
+
+    // The mtime of a file that's rewritten is always newer than this.
+    let old_mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+    let before = list_files(&tmp.join("output"));
+
+    for path in before.iter() {
+        std::fs::File::options().write(true).open(path).unwrap().set_modified(old_mtime).unwrap();
+    }
+
+    // It empties a bucket, updates a row of another bucket and drops `u`.
+    let mut buckets = before.iter().filter(
+        |path| path.parent().unwrap().ends_with("t") && path.extension().is_none()
+    );
+    let (emptied, updated) = (buckets.next().unwrap().clone(), buckets.next().unwrap().clone());
+
+    for (bucket, sql) in [(&emptied, "DELETE FROM t WHERE a = ?1;"), (&updated, "UPDATE t SET b = 'updated' WHERE a = ?1;")] {
+        for line in std::fs::read_to_string(bucket).unwrap().lines() {
+            if let Some(a) = line.strip_prefix("\"a\"=") {
+                conn.execute(sql, [a.parse::<i64>().unwrap()]).unwrap();
+            }
+        }
+    }
+
+    conn.execute("DROP TABLE u;", []).unwrap();
+    to_git_from_connection(&conn, &output, &options).unwrap();
+
+    let after = list_files(&tmp.join("output"));
+    assert!(!after.contains(&emptied));
+    assert!(!tmp.join("output/u").exists());
+    assert_eq!(after.len(), before.len() - 1 - before.iter().filter(|path| path.starts_with(tmp.join("output/u"))).count());
+
+    // Only the manifest and the updated bucket are rewritten.
+    let rewritten = after.iter().filter(
+        |path| std::fs::metadata(path).unwrap().modified().unwrap() != old_mtime
+    ).cloned().collect::<HashSet<_>>();
+    assert_eq!(rewritten, HashSet::from([tmp.join("output/manifest.toml"), updated]));
+
+    // The result is the same as a fresh dump.
+    to_git_from_connection(&conn, &fresh, &options.clone().incremental(false)).unwrap();
+    let fresh_files = list_files(&tmp.join("fresh"));
+    assert_eq!(fresh_files.len(), after.len());
+
+    for path in fresh_files.iter() {
+        let relative = path.strip_prefix(tmp.join("fresh")).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), std::fs::read(tmp.join("output").join(relative)).unwrap());
+    }
+}
+
+// every file under `dir`, recursively
+fn list_files(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut result = vec![];
+
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+
+        if path.is_dir() {
+            result.extend(list_files(&path));
+        }
+
+        else {
+            result.push(path);
+        }
+    }
+
+    result.sort();
+    result
+}
+
+#[test]
+fn reserved_names() {
+    let tmp = TempDir::new("reserved-names");
+    let output = tmp.join("output").to_str().unwrap().to_string();
+
+    // Tables that have the same name as the files in the root of the output.
+    let names = ["pragma", "PRAGMA_", "manifest.toml", "Trigger.sql", "view.sql", "hash-algorithm"];
+    let conn = Connection::open_in_memory().unwrap();
+
+    for name in names.iter() {
+        conn.execute_batch(&format!("CREATE TABLE \"{name}\" (a TEXT); INSERT INTO \"{name}\" VALUES ('{name}');")).unwrap();
+    }
+
+    to_git_from_connection(&conn, &output, &ToGitOptions::new()).unwrap();
+    assert!(tmp.join("output/pragma").is_file());
+    assert!(tmp.join("output/$70$ragma/table.sql").exists());
+    assert!(tmp.join("output/manifest.toml").is_file());
+    assert!(tmp.join("output/$6d$anifest.toml/table.sql").exists());
+    assert!(tmp.join("output/$54$rigger.sql/table.sql").exists());
+    assert!(fsck(&output, false).unwrap().is_empty());
+
+    let mut restored = Connection::open_in_memory().unwrap();
+    from_git_into_connection(&output, &mut restored, "main", &FromGitOptions::new()).unwrap();
+
+    for name in names.iter() {
+        let value: String = restored.query_row(&format!("SELECT a FROM \"{name}\";"), [], |row| row.get(0)).unwrap();
+        assert_eq!(&value, name);
+    }
+}
+
+#[test]
+fn preserve_rowid() {
+    let tmp = TempDir::new("preserve-rowid");
+    let output = tmp.join("output").to_str().unwrap().to_string();
+
+    // `INTEGER PRIMARY KEY DESC` is not an alias of the rowid.
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch("
+        CREATE TABLE plain (a TEXT);
+        INSERT INTO plain (rowid, a) VALUES (10, 'x'), (3, 'y'), (7, 'x');
+        CREATE TABLE descending (a INTEGER PRIMARY KEY DESC, b TEXT);
+        INSERT INTO descending (rowid, a, b) VALUES (100, 1, 'one'), (5, 2, 'two');
+        CREATE TABLE alias (a INTEGER PRIMARY KEY, b TEXT);
+        INSERT INTO alias VALUES (42, 'x'), (8, 'y');
+    ").unwrap();
+    to_git_from_connection(&conn, &output, &ToGitOptions::new().preserve_rowid(true)).unwrap();
+
+    let mut restored = Connection::open_in_memory().unwrap();
+    from_git_into_connection(&output, &mut restored, "main", &FromGitOptions::new()).unwrap();
+
+    for (table, columns) in [("plain", "a"), ("descending", "a, b"), ("alias", "a, b")] {
+        let sql = format!("SELECT group_concat(rowid || ':' || concat_ws(',', {columns}), ' ') FROM (SELECT rowid, * FROM {table} ORDER BY rowid);");
+        let rows = |conn: &Connection| conn.query_row(&sql, [], |row| row.get::<_, String>(0)).unwrap();
+        assert_eq!(rows(&restored), rows(&conn), "{table}");
+    }
+}
+
+#[test]
+fn spill_to_temp_files() {
+    let tmp = TempDir::new("spill");
+
+    // It has duplicate rows, which have the same id.
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch("
+        CREATE TABLE t (a INTEGER, b TEXT);
+        WITH RECURSIVE n(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n WHERE x < 3000)
+        INSERT INTO t SELECT x % 700, 'row ' || (x % 500) FROM n;
+    ").unwrap();
+
+    let mut outputs = vec![];
+
+    // 1 spills every record, and 20 needs multiple merge passes.
+    for spill_threshold in [None, Some(1), Some(20)] {
+        let output = tmp.join(format!("output-{spill_threshold:?}")).to_str().unwrap().to_string();
+        let options = ToGitOptions {
+            preserve_rowid: true,
+            default_bucket_layout: Some(BucketLayout::Flat(1)),
+            spill_threshold,
+            ..ToGitOptions::default()
+        };
+        to_git_from_connection(&conn, &output, &options).unwrap();
+
+        let mut files = vec![];
+
+        for bucket in std::fs::read_dir(tmp.join(&output).join("t")).unwrap() {
+            let bucket = bucket.unwrap();
+            files.push((bucket.file_name(), std::fs::read(bucket.path()).unwrap()));
+        }
+
+        files.sort();
+        outputs.push(files);
+    }
+
+    assert_eq!(outputs[0], outputs[1]);
+    assert_eq!(outputs[0], outputs[2]);
+}
+
+#[test]
+fn into_storage_refuses_directory_options() {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch("CREATE TABLE t (a);").unwrap();
+
+    for options in [ToGitOptions::new().incremental(true), ToGitOptions::new().force(true)] {
+        let storage = MemoryStorage::new();
+        assert!(matches!(to_git_into_storage(&conn, &storage, &options), Err(Error::EdgeCase(_))));
+        assert!(storage.files().is_empty());
+    }
+}
+
+#[test]
+fn sqlite_sequence() {
+    // `format!("{:?}")` would write the name as `"odd\u{1b}name\u{301}"`.
+    let odd_name = "odd\x1bname\u{301}";
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch(&format!("
+        CREATE TABLE counter (id INTEGER PRIMARY KEY AUTOINCREMENT, a TEXT);
+        INSERT INTO counter (a) VALUES ('a'), ('b'), ('c');
+        DELETE FROM counter WHERE id > 1;
+        CREATE TABLE \"{odd_name}\" (id INTEGER PRIMARY KEY AUTOINCREMENT, \"{odd_name}\" TEXT);
+        INSERT INTO \"{odd_name}\" (\"{odd_name}\") VALUES ('a'), ('b');
+        DELETE FROM \"{odd_name}\";
+    ")).unwrap();
+    let storage = MemoryStorage::new();
+    to_git_into_storage(&conn, &storage, &ToGitOptions::new()).unwrap();
+
+    let mut restored = Connection::open_in_memory().unwrap();
+    from_storage_into_connection(&storage, &mut restored, "main", &FromGitOptions::new()).unwrap();
+
+    let sql = "SELECT group_concat(name || '=' || seq, ',') FROM (SELECT * FROM sqlite_sequence ORDER BY name);";
+    let sequence: String = restored.query_row(sql, [], |row| row.get(0)).unwrap();
+    assert_eq!(sequence, format!("counter=3,{odd_name}=2"));
+
+    // The ids of the deleted rows are not reused.
+    restored.execute("INSERT INTO counter (a) VALUES ('d');", []).unwrap();
+    assert_eq!(restored.last_insert_rowid(), 4);
+    restored.execute(&format!("INSERT INTO \"{odd_name}\" (\"{odd_name}\") VALUES ('c');"), []).unwrap();
+    assert_eq!(restored.last_insert_rowid(), 3);
+    let value: String = restored.query_row(&format!("SELECT \"{odd_name}\" FROM \"{odd_name}\";"), [], |row| row.get(0)).unwrap();
+    assert_eq!(value, "c");
+}
+
+#[test]
+fn parallel_dump() {
+    let tmp = TempDir::new("parallel");
+    let db_path = tmp.join("input.db").to_str().unwrap().to_string();
+
+    // `big` is large enough to be split into rowid ranges, and has duplicate rows.
+    let conn = Connection::open(&db_path).unwrap();
+    conn.execute_batch("
+        CREATE TABLE big (a INTEGER, b TEXT);
+        WITH RECURSIVE n(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n WHERE x < 50000)
+        INSERT INTO big SELECT x % 3000, 'row ' || (x % 2000) FROM n;
+        CREATE TABLE small (a INTEGER PRIMARY KEY, b TEXT);
+        INSERT INTO small VALUES (1, 'a'), (2, 'b');
+        CREATE TABLE empty (a TEXT);
+    ").unwrap();
+
+    let mut outputs = vec![];
+
+    for jobs in [None, Some(4)] {
+        let output = tmp.join(format!("output-{jobs:?}")).to_str().unwrap().to_string();
+        let options = ToGitOptions {
+            preserve_rowid: true,
+            spill_threshold: Some(1000),
+            jobs,
+            ..ToGitOptions::default()
+        };
+        to_git_from_connection(&conn, &output, &options).unwrap();
+
+        let mut files = vec![];
+
+        for table in ["big", "small", "empty"] {
+            for bucket in std::fs::read_dir(tmp.join(&output).join(table)).unwrap() {
+                let bucket = bucket.unwrap();
+                files.push((bucket.path().strip_prefix(&output).unwrap().to_path_buf(), std::fs::read(bucket.path()).unwrap()));
+            }
+        }
+
+        files.push((std::path::PathBuf::from("manifest.toml"), std::fs::read(tmp.join(&output).join("manifest.toml")).unwrap()));
+        files.sort();
+        outputs.push(files);
+    }
+
+    assert_eq!(outputs[0], outputs[1]);
+}
+
+#[test]
+fn refuse_to_overwrite() {
+    let tmp = TempDir::new("refuse-to-overwrite");
+    let output = tmp.join("output").to_str().unwrap().to_string();
+    std::fs::create_dir_all(&output).unwrap();
+    std::fs::write(tmp.join("output/notes.txt"), "hello").unwrap();
+
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch("CREATE TABLE t (a INTEGER PRIMARY KEY, b TEXT);").unwrap();
+
+    for incremental in [false, true] {
+        let options = ToGitOptions { incremental, ..ToGitOptions::default() };
+
+        match to_git_from_connection(&conn, &output, &options) {
+            Err(Error::RefusedToOverwrite { files, .. }) => {
+                assert_eq!(files, vec![tmp.join("output/notes.txt").to_str().unwrap().to_string()]);
+            },
+            r => panic!("{r:?}"),
+        }
+
+        assert!(tmp.join("output/notes.txt").exists());
+    }
+
+    to_git_from_connection(&conn, &output, &ToGitOptions { force: true, ..ToGitOptions::default() }).unwrap();
+    assert!(!tmp.join("output/notes.txt").exists());
+    assert!(tmp.join("output/t/table.sql").exists());
+
+    // An output of the first versions of stfg doesn't have a manifest.
+    let legacy = tmp.join("legacy").to_str().unwrap().to_string();
+    std::fs::create_dir_all(tmp.join("legacy/t")).unwrap();
+    std::fs::write(tmp.join("legacy/view.sql"), "").unwrap();
+    std::fs::write(tmp.join("legacy/t/table.sql"), "CREATE TABLE t (a INTEGER PRIMARY KEY, b TEXT);").unwrap();
+    to_git_from_connection(&conn, &legacy, &ToGitOptions::default()).unwrap();
+    assert!(tmp.join("legacy/manifest.toml").exists());
+    std::fs::remove_dir_all(&legacy).unwrap();
+
+    // A file is replaced too, and nothing is left behind.
+    let file = tmp.join("file.txt").to_str().unwrap().to_string();
+    std::fs::write(&file, "hello").unwrap();
+    to_git_from_connection(&conn, &file, &ToGitOptions { force: true, ..ToGitOptions::default() }).unwrap();
+    assert!(tmp.join("file.txt/t/table.sql").exists());
+    assert_eq!(std::fs::read_dir(&tmp).unwrap().count(), 2);
+}
+
+#[test]
+fn filter_and_report() {
+    let tmp = TempDir::new("filter-and-report");
+    let output = tmp.join("output").to_str().unwrap().to_string();
+    let db_path = tmp.join("restored.db").to_str().unwrap().to_string();
+
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch("
+        CREATE TABLE a (x INTEGER PRIMARY KEY AUTOINCREMENT, y TEXT);
+        CREATE TABLE b (x INTEGER PRIMARY KEY AUTOINCREMENT, y TEXT);
+        CREATE TABLE c (x INTEGER PRIMARY KEY, y TEXT);
+        INSERT INTO a (y) VALUES ('a'), ('a');
+        INSERT INTO b (y) VALUES ('b');
+        INSERT INTO c VALUES (1, 'c'), (2, 'c'), (3, 'c');
+        CREATE VIEW v AS SELECT y FROM b;
+    ").unwrap();
+
+    let progress = Arc::new(Mutex::new(vec![]));
+    let progress_ = progress.clone();
+    let options = ToGitOptions::new()
+        .tables(TableFilter::default().exclude("b"))
+        .on_progress(move |report| progress_.lock().unwrap().push(report.name.to_string()));
+    let report = to_git_from_connection(&conn, &output, &options).unwrap();
+
+    assert_eq!(report.tables.iter().map(|t| (t.name.as_str(), t.rows)).collect::<Vec<_>>(), vec![("a", 2), ("c", 3)]);
+    assert_eq!(*progress.lock().unwrap(), vec![String::from("a"), String::from("c")]);
+    assert!(!tmp.join("output/b").exists());
+
+    let report = from_git_with_options(
+        &output,
+        &db_path,
+        &FromGitOptions::new().tables(TableFilter::default().include("a").include("b")),
+    ).unwrap();
+    assert_eq!(report.tables.iter().map(|t| (t.name.as_str(), t.rows)).collect::<Vec<_>>(), vec![("a", 2)]);
+
+    let restored = Connection::open(&db_path).unwrap();
+    let tables: i64 = restored.query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name IN ('a', 'b', 'c');", [], |row| row.get(0)).unwrap();
+    assert_eq!(tables, 1);
+
+    let seq: i64 = restored.query_row("SELECT seq FROM sqlite_sequence WHERE name = 'a';", [], |row| row.get(0)).unwrap();
+    assert_eq!(seq, 2);
+}
+
+#[cfg(feature = "serialize")]
+#[test]
+fn dump_bytes() {
+    let tmp = TempDir::new("dump-bytes");
+    let output = tmp.join("output").to_str().unwrap().to_string();
+    let db_path = tmp.join("restored.db").to_str().unwrap().to_string();
+
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch("
+        CREATE TABLE t (a INTEGER PRIMARY KEY, b TEXT);
+        INSERT INTO t VALUES (1, 'one'), (2, 'two');
+    ").unwrap();
+    let bytes = conn.serialize("main").unwrap().to_vec();
+
+    let report = super::to_git_from_bytes(&bytes, &output, &ToGitOptions::default()).unwrap();
+    assert_eq!(report.rows(), 2);
+
+    from_git_with_options(&output, &db_path, &FromGitOptions::default()).unwrap();
+    let restored = Connection::open(&db_path).unwrap();
+    let b: String = restored.query_row("SELECT b FROM t WHERE a = 2;", [], |row| row.get(0)).unwrap();
+    assert_eq!(b, "two");
+}
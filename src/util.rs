@@ -1,5 +1,6 @@
 use base64::Engine;
 use crate::error::Error;
+use std::collections::HashSet;
 
 pub(crate) fn encode_base64(bytes: &[u8]) -> String {
     base64::prelude::BASE64_STANDARD.encode(bytes)
@@ -13,7 +14,7 @@ pub(crate) fn decode_base64(s: &str) -> Result<Vec<u8>, Error> {
 // end earlier than `bytes`. It returns the index of '"' that
 // finishes the string literal.
 pub(crate) fn parse_string_literal(bytes: &[u8]) -> Option<(String, usize)> {
-    match bytes.get(0) {
+    match bytes.first() {
         Some(b'"') => {},
         _ => {
             return None;
@@ -68,3 +69,149 @@ pub(crate) fn parse_string_literal(bytes: &[u8]) -> Option<(String, usize)> {
         Err(_) => None,
     }
 }
+
+// It returns all the identifiers in `sql`, lowercased (sqlite's identifiers are case-insensitive).
+// It doesn't care about keywords, so the result contains `select`, `from` and so on.
+// String literals and comments are skipped.
+pub(crate) fn sql_identifiers(sql: &str) -> HashSet<String> {
+    let chars = sql.chars().collect::<Vec<_>>();
+    let mut result = HashSet::new();
+    let mut cursor = 0;
+
+    while let Some(ch) = chars.get(cursor) {
+        match ch {
+            '-' if chars.get(cursor + 1) == Some(&'-') => {
+                while let Some(ch) = chars.get(cursor) {
+                    if *ch == '\n' {
+                        break;
+                    }
+
+                    cursor += 1;
+                }
+            },
+            '/' if chars.get(cursor + 1) == Some(&'*') => {
+                cursor += 2;
+
+                while cursor < chars.len() && !(chars[cursor] == '*' && chars.get(cursor + 1) == Some(&'/')) {
+                    cursor += 1;
+                }
+
+                cursor += 2;
+            },
+            '\'' | '"' | '`' | '[' => {
+                let end = if *ch == '[' { ']' } else { *ch };
+                let mut buffer = vec![];
+                cursor += 1;
+
+                while let Some(ch) = chars.get(cursor) {
+                    if *ch == end {
+                        // `''` and `""` are escaped quotes
+                        if end != ']' && chars.get(cursor + 1) == Some(&end) {
+                            buffer.push(end);
+                            cursor += 2;
+                            continue;
+                        }
+
+                        break;
+                    }
+
+                    buffer.push(*ch);
+                    cursor += 1;
+                }
+
+                // `'...'` is a string literal, not an identifier.
+                if end != '\'' {
+                    result.insert(buffer.into_iter().collect::<String>().to_lowercase());
+                }
+
+                cursor += 1;
+            },
+            ch if ch.is_alphanumeric() || *ch == '_' || *ch == '$' => {
+                let mut buffer = vec![];
+
+                while let Some(ch) = chars.get(cursor) {
+                    if ch.is_alphanumeric() || *ch == '_' || *ch == '$' {
+                        buffer.push(*ch);
+                        cursor += 1;
+                    }
+
+                    else {
+                        break;
+                    }
+                }
+
+                result.insert(buffer.into_iter().collect::<String>().to_lowercase());
+            },
+            _ => {
+                cursor += 1;
+            },
+        }
+    }
+
+    result
+}
+
+/// The current time in UTC, like `20250131-235959`. It's used in file names.
+pub(crate) fn utc_timestamp() -> String {
+    let seconds = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) as i64;
+    let (days, seconds) = (seconds.div_euclid(86400), seconds.rem_euclid(86400));
+
+    // days since 1970-01-01 -> (year, month, day), from Howard Hinnant's `civil_from_days`
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{year:04}{month:02}{day:02}-{:02}{:02}{:02}",
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+    )
+}
+
+/// A directory in the system's temp dir for a test. It's removed when it's dropped, so
+/// a failing test doesn't leave it behind.
+#[cfg(test)]
+pub(crate) struct TempDir {
+    path: std::path::PathBuf,
+}
+
+#[cfg(test)]
+impl TempDir {
+    /// It creates `stfg-{name}-{pid}`, removing what a previous run has left there.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("stfg-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &std::path::Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+impl AsRef<std::path::Path> for TempDir {
+    fn as_ref(&self) -> &std::path::Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
@@ -1,5 +1,5 @@
 use crate::util::{decode_base64, encode_base64, parse_string_literal};
-pub(crate) use rusqlite::types::Value;
+pub use rusqlite::types::Value;
 
 #[cfg(test)]
 mod tests;
@@ -17,65 +17,70 @@ pub(crate) fn value_to_string(v: &Value) -> String {
 
             s
         },
-        // I don't use `format!("{s:?}")` because...
-        // 1. There are so many edge cases with string escapes and
-        //    `parse_string_literal` cannot handle them properly.
-        //    I hope there's `eval(s)` in rust, but there isn't.
-        // 2. We don't have to handle all the edge cases. All we
-        //    need is a format that `parse_string_literal` can parse.
-        // 3. If we ignore the edge cases, this implementation is faster
-        //    than `format!("{s:?}")`.
-        Value::Text(s) => {
-            let mut chars = Vec::with_capacity(s.len() + 2);
-            chars.push('"');
+        Value::Text(s) => string_literal(s),
+        Value::Blob(v) => {
+            // 1. Blob is not readable anyway. We don't have to try to make it readable.
+            // 2. Some 3rd party git tools require a file to be valid utf-8. So I'm using base64.
+            // 3. "null" is also a valid base64 output. In order to avoid that, I add a prefix to the output.
+            format!("b{}", encode_base64(v))
+        },
+    }
+}
 
-            for ch in s.chars() {
-                match ch {
-                    '\n' | '\r' | '\t' | '\0' => {
-                        chars.push('\\');
+// I don't use `format!("{s:?}")` because...
+// 1. There are so many edge cases with string escapes and
+//    `parse_string_literal` cannot handle them properly.
+//    I hope there's `eval(s)` in rust, but there isn't.
+// 2. We don't have to handle all the edge cases. All we
+//    need is a format that `parse_string_literal` can parse.
+// 3. If we ignore the edge cases, this implementation is faster
+//    than `format!("{s:?}")`.
+//
+// It's also used for the names (e.g. of a column or a table) in the data files, which
+// `parse_line` reads back.
+pub(crate) fn string_literal(s: &str) -> String {
+    let mut chars = Vec::with_capacity(s.len() + 2);
+    chars.push('"');
+
+    for ch in s.chars() {
+        match ch {
+            '\n' | '\r' | '\t' | '\0' => {
+                chars.push('\\');
 
-                        match ch {
-                            '\n' => {
-                                chars.push('n');
-                            },
-                            '\r' => {
-                                chars.push('r');
-                            },
-                            '\t' => {
-                                chars.push('t');
-                            },
-                            '\0' => {
-                                chars.push('0');
-                            },
-                            _ => unreachable!(),
-                        }
+                match ch {
+                    '\n' => {
+                        chars.push('n');
+                    },
+                    '\r' => {
+                        chars.push('r');
                     },
-                    '"' | '\\' => {
-                        chars.push('\\');
-                        chars.push(ch);
+                    '\t' => {
+                        chars.push('t');
                     },
-                    _ => {
-                        chars.push(ch);
+                    '\0' => {
+                        chars.push('0');
                     },
+                    _ => unreachable!(),
                 }
-            }
-
-            chars.push('"');
-            chars.into_iter().collect()
-        },
-        Value::Blob(v) => {
-            // 1. Blob is not readable anyway. We don't have to try to make it readable.
-            // 2. Some 3rd party git tools require a file to be valid utf-8. So I'm using base64.
-            // 3. "null" is also a valid base64 output. In order to avoid that, I add a prefix to the output.
-            format!("b{}", encode_base64(&v))
-        },
+            },
+            '"' | '\\' => {
+                chars.push('\\');
+                chars.push(ch);
+            },
+            _ => {
+                chars.push(ch);
+            },
+        }
     }
+
+    chars.push('"');
+    chars.into_iter().collect()
 }
 
 pub(crate) fn value_from_string(s: &str) -> Option<Value> {
     let b = s.as_bytes();
 
-    match b.get(0) {
+    match b.first() {
         Some(b'n') => {
             if b == b"null" {
                 Some(Value::Null)
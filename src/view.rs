@@ -1,4 +1,59 @@
+use crate::util::sql_identifiers;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+#[cfg(test)]
+mod tests;
+
 pub struct View {
     pub name: String,
     pub create_view_sql: String,
+
+    // `INSTEAD OF` triggers on this view
+    pub create_trigger_sql: String,
+}
+
+/// A view may select from another view, so the other view has to be created first.
+///
+/// sqlite doesn't tell us the dependencies, so it guesses them from the identifiers in
+/// `create_view_sql`. A false positive (e.g. a column with the same name as a view) only
+/// changes the order. If there's a cycle, the remaining views are sorted by name.
+/// Views without dependencies are sorted by name, so that the result is deterministic.
+pub(crate) fn sort_by_dependency(views: Vec<View>) -> Vec<View> {
+    let names = views.iter().map(
+        |view| view.name.to_lowercase()
+    ).collect::<HashSet<_>>();
+    let mut dependencies: HashMap<String, HashSet<String>> = HashMap::with_capacity(views.len());
+
+    for view in views.iter() {
+        let name = view.name.to_lowercase();
+        let mut identifiers = sql_identifiers(&view.create_view_sql);
+        identifiers.retain(|identifier| identifier != &name && names.contains(identifier));
+        dependencies.insert(name, identifiers);
+    }
+
+    let mut views_by_name = views.into_iter().map(
+        |view| (view.name.to_lowercase(), view)
+    ).collect::<HashMap<_, _>>();
+    let mut result = Vec::with_capacity(views_by_name.len());
+
+    while !views_by_name.is_empty() {
+        let ready = views_by_name.keys().filter(
+            |name| dependencies[*name].iter().all(|dependency| !views_by_name.contains_key(dependency))
+        ).map(
+            |name| name.to_string()
+        ).collect::<BTreeSet<_>>();
+
+        // There's a cycle. Nothing we can do.
+        let ready = if ready.is_empty() {
+            views_by_name.keys().map(|name| name.to_string()).collect::<BTreeSet<_>>()
+        } else {
+            ready
+        };
+
+        for name in ready.iter() {
+            result.push(views_by_name.remove(name).unwrap());
+        }
+    }
+
+    result
 }
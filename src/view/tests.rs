@@ -0,0 +1,24 @@
+use super::{View, sort_by_dependency};
+
+fn view(name: &str, create_view_sql: &str) -> View {
+    View {
+        name: name.to_string(),
+        create_view_sql: create_view_sql.to_string(),
+        create_trigger_sql: String::new(),
+    }
+}
+
+#[test]
+fn view_dependency() {
+    let views = vec![
+        view("a", "CREATE VIEW a AS SELECT * FROM \"c d\" JOIN b;"),
+        view("b", "CREATE VIEW b AS SELECT 'a' FROM t;"),
+        view("c d", "CREATE VIEW \"c d\" AS SELECT * FROM [E] -- a\n;"),
+        view("e", "CREATE VIEW e AS SELECT 1 /* b */;"),
+    ];
+    let sorted = sort_by_dependency(views).iter().map(
+        |view| view.name.to_string()
+    ).collect::<Vec<_>>();
+
+    assert_eq!(sorted, vec!["b", "e", "c d", "a"]);
+}
@@ -0,0 +1,221 @@
+#[cfg(test)]
+mod tests;
+
+/// stfg dumps the rows of a virtual table with `SELECT`, like a normal table. It works
+/// for most modules, but some modules need special care.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VirtualTable {
+    /// `fts3`, `fts4` and `fts5`
+    Fts {
+        module: String,
+        content: FtsContent,
+    },
+
+    /// `rtree` and `rtree_i32`. Its rows can be dumped and inserted like a normal table.
+    Rtree,
+
+    /// Unknown modules are treated like normal tables.
+    Other(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FtsContent {
+    /// The default. The fts table stores its own contents, so the rows are dumped like a normal table.
+    Internal,
+
+    /// `content=table_name`. The contents are in another table, so dumping the rows would duplicate
+    /// the data. The rows are not dumped, and the index is rebuilt with the `'rebuild'` command after
+    /// the content table is restored.
+    External(String),
+
+    /// `content=''`. The contents are not stored anywhere, so the rows cannot be dumped. Its
+    /// index (the shadow tables) is dumped as it is, instead (see `VirtualTable::shadow_tables`).
+    Contentless,
+}
+
+impl VirtualTable {
+    /// It returns `None` if `create_table_sql` doesn't have `USING module`.
+    pub fn from_sql(create_table_sql: &str) -> Option<VirtualTable> {
+        let (module, args) = parse_module_args(create_table_sql)?;
+
+        match module.as_str() {
+            "fts3" | "fts4" | "fts5" => {
+                let mut content = FtsContent::Internal;
+
+                for arg in args.iter() {
+                    if let Some((key, value)) = arg.split_once('=') && key.trim().eq_ignore_ascii_case("content") {
+                        let value = unquote(value.trim());
+
+                        content = if value.is_empty() {
+                            FtsContent::Contentless
+                        } else {
+                            FtsContent::External(value)
+                        };
+                    }
+                }
+
+                Some(VirtualTable::Fts { module, content })
+            },
+            "rtree" | "rtree_i32" => Some(VirtualTable::Rtree),
+            _ => Some(VirtualTable::Other(module)),
+        }
+    }
+
+    /// Whether the rows of the table are dumped to the data files.
+    pub fn dumps_rows(&self) -> bool {
+        !matches!(
+            self,
+            VirtualTable::Fts { content: FtsContent::External(_) | FtsContent::Contentless, .. },
+        )
+    }
+
+    /// Whether the index has to be rebuilt with the `'rebuild'` command after the data is restored.
+    pub fn needs_rebuild(&self) -> bool {
+        matches!(
+            self,
+            VirtualTable::Fts { content: FtsContent::External(_), .. },
+        )
+    }
+
+    /// Suffixes of the shadow tables that are dumped as they are (see `FTS_SHADOW_FILE`). Some of
+    /// them may not exist (e.g. `docsize` with `columnsize=0`). fts5's `config` is dumped separately.
+    pub fn shadow_tables(&self) -> &'static [&'static str] {
+        match self {
+            VirtualTable::Fts { module, content: FtsContent::Contentless } if module == "fts5" => &["data", "idx", "docsize"],
+            VirtualTable::Fts { content: FtsContent::Contentless, .. } => &["segments", "segdir", "docsize", "stat"],
+            _ => &[],
+        }
+    }
+
+    /// fts5 stores its configurations (e.g. `rank`, `automerge`) in `{name}_config`.
+    pub fn has_config(&self) -> bool {
+        matches!(
+            self,
+            VirtualTable::Fts { module, .. } if module == "fts5",
+        )
+    }
+}
+
+// `CREATE VIRTUAL TABLE t USING fts5(a, b, content='c')` -> `Some(("fts5", ["a", "b", "content='c'"]))`
+fn parse_module_args(sql: &str) -> Option<(String, Vec<String>)> {
+    let chars = sql.chars().collect::<Vec<_>>();
+    let mut cursor = 0;
+
+    // finds keyword `USING`, which is not quoted
+    loop {
+        match chars.get(cursor)? {
+            '\'' | '"' | '`' | '[' => {
+                cursor = skip_quoted(&chars, cursor);
+            },
+            ch if is_identifier_char(*ch) => {
+                let start = cursor;
+
+                while chars.get(cursor).map(|ch| is_identifier_char(*ch)).unwrap_or(false) {
+                    cursor += 1;
+                }
+
+                if chars[start..cursor].iter().collect::<String>().eq_ignore_ascii_case("using") {
+                    break;
+                }
+            },
+            _ => {
+                cursor += 1;
+            },
+        }
+    }
+
+    while chars.get(cursor)?.is_whitespace() {
+        cursor += 1;
+    }
+
+    let start = cursor;
+
+    while chars.get(cursor).map(|ch| is_identifier_char(*ch)).unwrap_or(false) {
+        cursor += 1;
+    }
+
+    let module = chars[start..cursor].iter().collect::<String>().to_lowercase();
+
+    while chars.get(cursor).map(|ch| ch.is_whitespace()).unwrap_or(false) {
+        cursor += 1;
+    }
+
+    // `CREATE VIRTUAL TABLE t USING module;` is valid
+    if chars.get(cursor) != Some(&'(') {
+        return Some((module, vec![]));
+    }
+
+    cursor += 1;
+    let mut args = vec![];
+    let mut arg_start = cursor;
+    let mut depth = 0;
+
+    loop {
+        match chars.get(cursor)? {
+            '\'' | '"' | '`' | '[' => {
+                cursor = skip_quoted(&chars, cursor);
+                continue;
+            },
+            '(' => {
+                depth += 1;
+            },
+            ')' if depth > 0 => {
+                depth -= 1;
+            },
+            ch @ (')' | ',') => {
+                args.push(chars[arg_start..cursor].iter().collect::<String>().trim().to_string());
+                arg_start = cursor + 1;
+
+                if *ch == ')' {
+                    break;
+                }
+            },
+            _ => {},
+        }
+
+        cursor += 1;
+    }
+
+    args.retain(|arg| !arg.is_empty());
+    Some((module, args))
+}
+
+// `chars[cursor]` is an opening quote. It returns the index after the closing quote.
+fn skip_quoted(chars: &[char], mut cursor: usize) -> usize {
+    let end = if chars[cursor] == '[' { ']' } else { chars[cursor] };
+    cursor += 1;
+
+    while let Some(ch) = chars.get(cursor) {
+        cursor += 1;
+
+        if *ch == end {
+            // `''` and `""` are escaped quotes
+            if end != ']' && chars.get(cursor) == Some(&end) {
+                cursor += 1;
+                continue;
+            }
+
+            break;
+        }
+    }
+
+    cursor
+}
+
+// `'abc'` -> `abc`, `"a""b"` -> `a"b`, `abc` -> `abc`
+fn unquote(s: &str) -> String {
+    let chars = s.chars().collect::<Vec<_>>();
+
+    match (chars.first(), chars.last()) {
+        (Some(start @ ('\'' | '"' | '`')), Some(end)) if chars.len() > 1 && start == end => {
+            let quote = start.to_string();
+            chars[1..(chars.len() - 1)].iter().collect::<String>().replace(&quote.repeat(2), &quote)
+        },
+        (Some('['), Some(']')) => chars[1..(chars.len() - 1)].iter().collect(),
+        _ => s.to_string(),
+    }
+}
+
+fn is_identifier_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_' || ch == '$'
+}
@@ -0,0 +1,98 @@
+use super::{FtsContent, VirtualTable};
+use crate::from_git::{FromGitOptions, from_storage_into_connection};
+use crate::storage::MemoryStorage;
+use crate::to_git::{ToGitOptions, to_git_into_storage};
+use rusqlite::Connection;
+
+#[test]
+fn virtual_table_from_sql() {
+    for (sql, virtual_table) in [
+        (
+            "CREATE VIRTUAL TABLE email USING fts5(sender, title, body)",
+            Some(VirtualTable::Fts { module: String::from("fts5"), content: FtsContent::Internal }),
+        ),
+        (
+            "CREATE VIRTUAL TABLE \"using\" USING FTS5(a, content='docs', content_rowid='id')",
+            Some(VirtualTable::Fts { module: String::from("fts5"), content: FtsContent::External(String::from("docs")) }),
+        ),
+        (
+            "CREATE VIRTUAL TABLE t USING fts4(a, content=\"my \"\"docs\"\"\")",
+            Some(VirtualTable::Fts { module: String::from("fts4"), content: FtsContent::External(String::from("my \"docs\"")) }),
+        ),
+        (
+            "CREATE VIRTUAL TABLE t USING fts5(a, content='', tokenize = 'porter unicode61')",
+            Some(VirtualTable::Fts { module: String::from("fts5"), content: FtsContent::Contentless }),
+        ),
+        (
+            "CREATE VIRTUAL TABLE t USING rtree(id, min_x, max_x, +label)",
+            Some(VirtualTable::Rtree),
+        ),
+        (
+            "CREATE VIRTUAL TABLE t USING my_module",
+            Some(VirtualTable::Other(String::from("my_module"))),
+        ),
+        (
+            "CREATE TABLE t (a, b)",
+            None,
+        ),
+    ] {
+        assert_eq!(VirtualTable::from_sql(sql), virtual_table);
+    }
+}
+
+#[test]
+fn roundtrip() {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch("
+        CREATE TABLE document (id INTEGER PRIMARY KEY, body TEXT);
+        INSERT INTO document VALUES (1, 'hello world'), (2, 'full text search');
+
+        CREATE VIRTUAL TABLE document_fts USING fts5(body, content='document', content_rowid='id');
+        INSERT INTO document_fts (document_fts) VALUES ('rebuild');
+        INSERT INTO document_fts (document_fts, rank) VALUES ('rank', 'bm25(10.0)');
+
+        CREATE VIRTUAL TABLE note USING fts4(body);
+        INSERT INTO note VALUES ('a note'), ('another note');
+
+        CREATE VIRTUAL TABLE contentless USING fts5(body, content='');
+        INSERT INTO contentless (rowid, body) VALUES (1, 'hello'), (2, 'hello again');
+
+        CREATE VIRTUAL TABLE contentless4 USING fts4(body, content='');
+        INSERT INTO contentless4 (docid, body) VALUES (1, 'hello'), (2, 'hello again');
+
+        CREATE VIRTUAL TABLE place USING rtree(id, min_x, max_x, +name);
+        INSERT INTO place VALUES (1, 0.5, 1.5, 'Seoul'), (2, -1.0, 1.0, 'Null Island');
+    ").unwrap();
+    let storage = MemoryStorage::new();
+    to_git_into_storage(&conn, &storage, &ToGitOptions::new()).unwrap();
+
+    let mut restored = Connection::open_in_memory().unwrap();
+    from_storage_into_connection(&storage, &mut restored, "main", &FromGitOptions::new()).unwrap();
+
+    for sql in [
+        "SELECT group_concat(rowid) FROM document_fts WHERE document_fts MATCH 'text';",
+        "SELECT v FROM document_fts_config WHERE k = 'rank';",
+        "SELECT group_concat(body, ',') FROM note WHERE note MATCH 'note';",
+        "SELECT group_concat(id || name, ',') FROM place WHERE max_x > 1.2;",
+    ] {
+        assert_eq!(
+            restored.query_row(sql, [], |row| row.get::<_, String>(0)).unwrap(),
+            conn.query_row(sql, [], |row| row.get::<_, String>(0)).unwrap(),
+        );
+    }
+
+    // A contentless table has no rows to dump, but its index is restored.
+    for sql in [
+        "SELECT group_concat(rowid) FROM contentless WHERE contentless MATCH 'hello';",
+        "SELECT group_concat(rowid) FROM contentless4 WHERE contentless4 MATCH 'hello';",
+    ] {
+        assert_eq!(restored.query_row(sql, [], |row| row.get::<_, String>(0)).unwrap(), "1,2");
+    }
+
+    restored.execute("INSERT INTO contentless (contentless) VALUES ('integrity-check');", []).unwrap();
+
+    // It can still be updated.
+    restored.execute("INSERT INTO contentless (rowid, body) VALUES (3, 'hello');", []).unwrap();
+    let sql = "SELECT COUNT(*) FROM contentless WHERE contentless MATCH 'hello';";
+    assert_eq!(restored.query_row(sql, [], |row| row.get::<_, i64>(0)).unwrap(), 3);
+}
@@ -0,0 +1,9 @@
+CREATE TABLE enrollment (
+    student TEXT,
+    course TEXT,
+    grade TEXT,
+    PRIMARY KEY (course, student)
+);
+INSERT INTO enrollment (student, course, grade) VALUES ("alice", "math", "A");
+INSERT INTO enrollment (student, course, grade) VALUES ("alice", "physics", "B");
+INSERT INTO enrollment (student, course, grade) VALUES ("bob", "math", "C");
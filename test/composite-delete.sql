@@ -0,0 +1 @@
+DELETE FROM enrollment WHERE student = "alice" AND course = "math";
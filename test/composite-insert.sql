@@ -0,0 +1,2 @@
+INSERT INTO enrollment (student, course, grade) VALUES ("bob", "physics", "A");
+UPDATE enrollment SET grade = "B" WHERE student = "bob" AND course = "math";
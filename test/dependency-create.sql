@@ -0,0 +1,28 @@
+CREATE TABLE author (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL
+);
+
+CREATE TABLE book (
+    id INTEGER PRIMARY KEY,
+    author_id INTEGER REFERENCES author(id),
+    title TEXT NOT NULL
+);
+
+-- `a_` comes before `book_` and `z_`, so creating the views in the order of their names doesn't work.
+CREATE VIEW z_book_with_author AS SELECT book.title, author.name FROM book JOIN author ON book.author_id = author.id;
+CREATE VIEW a_book_count AS SELECT name, COUNT(*) AS count FROM z_book_with_author GROUP BY name;
+
+CREATE TRIGGER insert_book_with_author INSTEAD OF INSERT ON z_book_with_author
+BEGIN
+    INSERT INTO author (name) VALUES (NEW.name);
+    INSERT INTO book (author_id, title) VALUES (last_insert_rowid(), NEW.title);
+END;
+
+CREATE TRIGGER author_name AFTER INSERT ON book
+BEGIN
+    UPDATE author SET name = upper(name) WHERE id = NEW.author_id;
+END;
+
+INSERT INTO author (id, name) VALUES (1, "Kim");
+INSERT INTO book (author_id, title) VALUES (1, "Rust");
@@ -0,0 +1 @@
+DELETE FROM book WHERE title = "Rust";
@@ -0,0 +1 @@
+INSERT INTO z_book_with_author (title, name) VALUES ("SQLite", "Lee");
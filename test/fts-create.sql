@@ -0,0 +1,19 @@
+CREATE TABLE document (
+    id INTEGER PRIMARY KEY,
+    title TEXT,
+    body TEXT
+);
+
+-- The contents are in `document`, so the rows of `document_fts` must not be dumped.
+CREATE VIRTUAL TABLE document_fts USING fts5(title, body, content='document', content_rowid='id');
+INSERT INTO document_fts (document_fts, rank) VALUES ('rank', 'bm25(10.0, 1.0)');
+INSERT INTO document_fts (document_fts, rank) VALUES ('automerge', 8);
+
+CREATE VIRTUAL TABLE note_fts USING fts4(content);
+
+CREATE VIRTUAL TABLE place USING rtree(id, min_x, max_x, min_y, max_y, +name);
+
+INSERT INTO document (id, title, body) VALUES (1, 'Hello', 'Hello, world!');
+INSERT INTO document_fts (rowid, title, body) VALUES (1, 'Hello', 'Hello, world!');
+INSERT INTO note_fts (content) VALUES ('a note');
+INSERT INTO place (id, min_x, max_x, min_y, max_y, name) VALUES (1, 0.1, 0.2, 10.5, 11.5, 'Seoul');
@@ -0,0 +1,2 @@
+INSERT INTO document_fts (document_fts, rowid, title, body) VALUES ('delete', 1, 'Hello', 'Hello, world!');
+DELETE FROM document WHERE id = 1;
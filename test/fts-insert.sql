@@ -0,0 +1,3 @@
+INSERT INTO document (id, title, body) VALUES (2, 'SQLite', 'full text search');
+INSERT INTO document_fts (rowid, title, body) VALUES (2, 'SQLite', 'full text search');
+INSERT INTO place (id, min_x, max_x, min_y, max_y, name) VALUES (2, -1.0, 1.0, -1.0, 1.0, 'Null Island');
@@ -0,0 +1,12 @@
+CREATE TABLE person (
+    id INTEGER PRIMARY KEY,
+    first_name TEXT,
+    last_name TEXT,
+    full_name TEXT GENERATED ALWAYS AS (first_name || ' ' || last_name) STORED,
+    name_length INTEGER GENERATED ALWAYS AS (length(full_name)) VIRTUAL
+);
+
+CREATE INDEX person_full_name ON person (full_name);
+
+INSERT INTO person (first_name, last_name) VALUES ('Hyunsol', 'Bae');
+INSERT INTO person (first_name, last_name) VALUES ('Ada', 'Lovelace');
@@ -0,0 +1,2 @@
+INSERT INTO person (first_name, last_name) VALUES ('Alan', 'Turing');
+UPDATE person SET last_name = 'King' WHERE first_name = 'Ada';
@@ -0,0 +1,10 @@
+CREATE TABLE account (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    balance REAL NOT NULL,
+    avatar BLOB,
+    extra ANY
+) STRICT;
+
+INSERT INTO account (id, name, balance, avatar, extra) VALUES (1, 'alice', 10.5, x'0102', 3);
+INSERT INTO account (id, name, balance, avatar, extra) VALUES (2, 'bob', 0.0, NULL, 'text');
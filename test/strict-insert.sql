@@ -0,0 +1,2 @@
+INSERT INTO account (id, name, balance, avatar, extra) VALUES (3, 'charlie', -1.25, x'', 1.5);
+UPDATE account SET balance = 20.0 WHERE id = 1;
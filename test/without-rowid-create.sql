@@ -0,0 +1,10 @@
+CREATE TABLE config (
+    section TEXT,
+    key TEXT,
+    value TEXT,
+    PRIMARY KEY (section, key)
+) WITHOUT ROWID;
+
+INSERT INTO config (section, key, value) VALUES ('ui', 'theme', 'dark');
+INSERT INTO config (section, key, value) VALUES ('ui', 'font', 'mono');
+INSERT INTO config (section, key, value) VALUES ('net', 'timeout', '30');
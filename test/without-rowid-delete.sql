@@ -0,0 +1 @@
+DELETE FROM config WHERE section = 'ui' AND key = 'font';
@@ -0,0 +1,2 @@
+INSERT INTO config (section, key, value) VALUES ('net', 'retry', '3');
+UPDATE config SET value = 'light' WHERE section = 'ui' AND key = 'theme';